@@ -9,11 +9,16 @@
 //! new memory settings.
 //!
 //! The build script also sets the linker flags to tell it which link script to use.
+//!
+//! Finally, it resolves the corruption target: `CORRUPT_TARGET_ADDR` can give its absolute address
+//! (decimal or `0x` hex), or `CORRUPT_TARGET_SYMBOL` names a symbol in the ELF file at `CORRUPT_TARGET_ELF`,
+//! e.g. a `#[no_mangle]` static of the firmware that should be tested. The address is passed on to the crate
+//! in `CORRUPT_TARGET_ADDR` as a decimal number, which is empty if neither is set.
 
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -31,7 +36,22 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
-    // Specify linker arguments.
+    // The `kani` cfg is set by the Kani model checker when running proofs
+    println!("cargo:rustc-check-cfg=cfg(kani)");
+
+    let target = resolve_target();
+    println!(
+        "cargo:rustc-env=CORRUPT_TARGET_ADDR={}",
+        target
+            .map(|address| address.to_string())
+            .unwrap_or_default()
+    );
+
+    // Specify linker arguments. They are only for the firmware, the host tests of the library
+    // (see `make test`) are linked like any other host program.
+    if !env::var("TARGET").unwrap().starts_with("thumb") {
+        return;
+    }
 
     // `--nmagic` is required if memory section addresses are not aligned to 0x10000,
     // for example the FLASH and RAM sections in your `memory.x`.
@@ -41,3 +61,81 @@ fn main() {
     // Set the linker script to the one provided by cortex-m-rt.
     println!("cargo:rustc-link-arg=-Tlink.x");
 }
+
+/// The absolute target address from the environment, see the comment at the top
+fn resolve_target() -> Option<u32> {
+    println!("cargo:rerun-if-env-changed=CORRUPT_TARGET_ADDR");
+    println!("cargo:rerun-if-env-changed=CORRUPT_TARGET_SYMBOL");
+    println!("cargo:rerun-if-env-changed=CORRUPT_TARGET_ELF");
+
+    if let Some(symbol) = env::var("CORRUPT_TARGET_SYMBOL")
+        .ok()
+        .filter(|s| !s.is_empty())
+    {
+        let elf = env::var("CORRUPT_TARGET_ELF").expect(
+            "CORRUPT_TARGET_SYMBOL needs CORRUPT_TARGET_ELF, the file to look the symbol up in",
+        );
+        println!("cargo:rerun-if-changed={}", elf);
+        return Some(
+            find_symbol(Path::new(&elf), &symbol)
+                .unwrap_or_else(|| panic!("symbol {} not found in {}", symbol, elf)),
+        );
+    }
+
+    let address = env::var("CORRUPT_TARGET_ADDR")
+        .ok()
+        .filter(|s| !s.is_empty())?;
+    let parsed = match address.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => address.parse(),
+    };
+    Some(parsed.unwrap_or_else(|_| panic!("CORRUPT_TARGET_ADDR {} is not a number", address)))
+}
+
+/// Looks up the value of `name` in the symbol table of a 32-bit little-endian ELF file (i.e. any Cortex-M binary).
+/// The name must match exactly, so Rust symbols should be `#[no_mangle]`.
+fn find_symbol(path: &Path, name: &str) -> Option<u32> {
+    let elf =
+        std::fs::read(path).unwrap_or_else(|e| panic!("can't read {}: {}", path.display(), e));
+    assert!(
+        elf.starts_with(b"\x7fELF\x01\x01"),
+        "{} is not a 32-bit little-endian ELF file",
+        path.display()
+    );
+
+    let u16_at = |offset: usize| u16::from_le_bytes([elf[offset], elf[offset + 1]]) as usize;
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes([
+            elf[offset],
+            elf[offset + 1],
+            elf[offset + 2],
+            elf[offset + 3],
+        ])
+    };
+
+    // e_shoff, e_shentsize and e_shnum
+    let section_headers = u32_at(0x20) as usize;
+    let header_size = u16_at(0x2E);
+    let section = |index: usize| section_headers + index * header_size;
+
+    for symtab in (0..u16_at(0x30)).map(section) {
+        // SHT_SYMTAB
+        if u32_at(symtab + 0x04) != 2 {
+            continue;
+        }
+        let symbols = u32_at(symtab + 0x10) as usize;
+        let symbols_size = u32_at(symtab + 0x14) as usize;
+        // sh_link is the string table of the names
+        let strings = u32_at(section(u32_at(symtab + 0x18) as usize) + 0x10) as usize;
+
+        // Elf32_Sym is 16 bytes: st_name, st_value, st_size and some smaller fields
+        for symbol in (symbols..symbols + symbols_size).step_by(16) {
+            let start = strings + u32_at(symbol) as usize;
+            let end = start + elf[start..].iter().position(|&b| b == 0)?;
+            if &elf[start..end] == name.as_bytes() {
+                return Some(u32_at(symbol + 4));
+            }
+        }
+    }
+    None
+}
@@ -32,3 +32,8 @@ MEMORY
      } > RAM2
    } INSERT AFTER .bss;
 */
+
+/* The corruption target must not be on a page of the firmware image, see TARGET_PAGE_START in main.rs.
+   Only the offsets into the flash are compared, as this links the firmware at the alias at address 0 */
+ASSERT((__corrupt_target_page & 0xFFFFFF) >= ((LOADADDR(.data) + SIZEOF(.data)) & 0xFFFFFF),
+  "the page of the corruption target holds the firmware, choose a target after it (see README)");
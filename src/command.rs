@@ -0,0 +1,39 @@
+//! Commands a host can leave in backup register 24 before a reset, e.g. with a probe script.
+//! They are executed once, early at boot, so experiments can be orchestrated without rebuilding.
+//!
+//! The upper 8 bits of the register select the command, the lower 24 bits are its argument.
+//! For example, writing `0x0200_BEEF` sets the jitter seed to `0xBEEF` and starts a new search.
+
+/// A command from the host, see [Command::decode] for the encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Start a new search (code 1)
+    Restart,
+    /// Set the jitter seed to the argument and start a new search (code 2)
+    Reseed(u32),
+    /// Set the number of verification reads to the argument (code 3)
+    SetVerificationReads(u32),
+    /// Try the waiting time of the last attempt again, instead of continuing the search (code 4)
+    Replay,
+    /// Send all backup registers over ITM (code 5)
+    DumpState,
+    /// Start the bootloader in system memory instead of the search, e.g. for a recovery over USB DFU (code 6)
+    EnterBootloader,
+}
+
+impl Command {
+    /// Decodes the content of [crate::state::SearchState::command]. Zero means no command, unknown commands are ignored,
+    /// so both return [None].
+    pub fn decode(value: u32) -> Option<Command> {
+        let argument = value & 0x00FF_FFFF;
+        match value >> 24 {
+            1 => Some(Command::Restart),
+            2 => Some(Command::Reseed(argument)),
+            3 => Some(Command::SetVerificationReads(argument)),
+            4 => Some(Command::Replay),
+            5 => Some(Command::DumpState),
+            6 => Some(Command::EnterBootloader),
+            _ => None,
+        }
+    }
+}
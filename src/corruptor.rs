@@ -0,0 +1,67 @@
+//! Ownership of the peripherals the search uses, so they are taken exactly once at the start of main.
+
+use stm32l4::stm32l4r5::{self, DBGMCU, EXTI, IWDG, PWR, RCC, RTC, SYSCFG};
+
+use crate::flash::Flash;
+use crate::hw::{self, ConfiguredLeds, RtcBackupRegs, Watchdog};
+
+/// Owns the device peripherals of the search, taken once with [Corruptor::take].
+///
+/// The fields are public, so disjoint parts can be borrowed at the same time. The core peripherals are not
+/// part of it: the ITM needs a mutable borrow, which would conflict with the [RtcBackupRegs] borrowed from here
+/// for the whole search. They are taken once in main as well.
+///
+/// The panic and fault handlers (and [crate::hw::init_leds] etc., which they use) are the documented exception
+/// and still steal the peripherals: they can't be handed anything, and the search never continues after them.
+pub struct Corruptor {
+    pub flash: Flash,
+    pub rcc: RCC,
+    pub pwr: PWR,
+    pub rtc: RTC,
+    pub iwdg: IWDG,
+    pub exti: EXTI,
+    pub dbgmcu: DBGMCU,
+    /// Only for the button (`button` feature)
+    #[cfg(feature = "button")]
+    pub gpioc: stm32l4r5::GPIOC,
+    pub syscfg: SYSCFG,
+}
+
+impl Corruptor {
+    /// Takes the device peripherals. Panics if they were taken before, or if the flash isn't supported
+    /// (see [Flash::new]).
+    pub fn take() -> Self {
+        let peripherals = stm32l4r5::Peripherals::take().unwrap();
+        Corruptor {
+            flash: Flash::new(peripherals.FLASH).unwrap(),
+            rcc: peripherals.RCC,
+            pwr: peripherals.PWR,
+            rtc: peripherals.RTC,
+            iwdg: peripherals.IWDG,
+            exti: peripherals.EXTI,
+            dbgmcu: peripherals.DBGMCU,
+            #[cfg(feature = "button")]
+            gpioc: peripherals.GPIOC,
+            syscfg: peripherals.SYSCFG,
+        }
+    }
+
+    /// The LEDs, configured as outputs
+    pub fn leds(&self) -> ConfiguredLeds {
+        hw::init_leds()
+    }
+
+    pub fn flash(&self) -> &Flash {
+        &self.flash
+    }
+
+    /// The IWDG that times the corruption, once [hw::activate_watchdog] started it
+    pub fn watchdog(&self) -> Watchdog<'_> {
+        Watchdog::new(&self.iwdg)
+    }
+
+    /// The search state in the backup registers. They must be accessible, see [hw::enable_rtc]
+    pub fn search(&self) -> RtcBackupRegs<'_> {
+        RtcBackupRegs::new(&self.rtc)
+    }
+}
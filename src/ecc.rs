@@ -0,0 +1,272 @@
+//! A software model of the flash ECC of the STM32L4R5, to predict which writes give which ECC errors.
+//!
+//! ST doesn't document the exact code, and FLASH_ECCR reports only the address and the kind of an error, no
+//! syndrome (see `EccError` in the firmware). So the bit layout of [compute_ecc] can't be checked against the
+//! hardware: it is the textbook extended Hamming code, with the properties the reference manual gives for the
+//! real one (an erased doubleword is valid, single-bit errors are corrected and double-bit errors detected).
+//! Predictions that only depend on these properties, like whether a double write breaks the ECC at all, hold
+//! for the hardware too. Which bits end up flipped doesn't.
+
+/// What the ECC reported for one doubleword read, e.g. by `Flash::read_line_checked` in the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EccKind {
+    /// No error was flagged
+    None,
+    /// A single-bit error was corrected, the value is right
+    Corrected,
+    /// A double-bit error that can't be corrected, the value is what the flash returned without correction
+    Uncorrectable,
+}
+
+/// The 8 ECC bits the flash stores for a doubleword: an extended Hamming (72, 64) code, i.e. 7 Hamming parity
+/// bits (bits 0-6) and one overall parity bit (bit 7). Like the hardware, it corrects single-bit and detects
+/// double-bit errors, see [decode_ecc].
+///
+/// The code is computed on inverted bits, so an erased doubleword (all ones) gets an all-ones ECC and is
+/// valid, as it is on the chip.
+pub fn compute_ecc(dword: u64) -> u8 {
+    // Programming turns ones into zeros, so the code works on the zeros
+    let data = !dword;
+    let syndrome = hamming_syndrome(data);
+    let overall = (data.count_ones() + syndrome.count_ones()) & 1;
+
+    !(syndrome | overall << 7) as u8
+}
+
+/// The 7 Hamming parity bits of [compute_ecc] for the inverted data bits
+fn hamming_syndrome(data: u64) -> u32 {
+    // The data bits fill the code positions 1 to 71 that aren't a power of two, those hold the parity bits
+    let mut syndrome = 0u32;
+    let mut bit = 0;
+    for position in 1..72u32 {
+        if position.is_power_of_two() {
+            continue;
+        }
+        if data >> bit & 1 != 0 {
+            syndrome ^= position;
+        }
+        bit += 1;
+    }
+    syndrome
+}
+
+/// Decodes a doubleword and its stored ECC bits (see [compute_ecc]) like a read does: returns the value the read
+/// gives and what the ECC reports. A single flipped bit, in the data or in the ECC, is corrected. Two flipped bits
+/// are detected, the data is then returned as it is.
+pub fn decode_ecc(data: u64, ecc: u8) -> (u64, EccKind) {
+    // Compare the stored parity bits with the ones the data needs, and check the parity over all 72 bits
+    let zeros = !data;
+    let parity = !ecc as u32;
+    let syndrome = hamming_syndrome(zeros) ^ (parity & 0x7F);
+    let odd = (zeros.count_ones() + parity.count_ones()) & 1 != 0;
+    match (syndrome, odd) {
+        (0, false) => (data, EccKind::None),
+        // A single flipped bit at the code position of the syndrome. If that's a power of two (or zero for the
+        // overall parity bit), it's one of the ECC bits and the data is right already
+        (1..=71, true) => match data_bit(syndrome) {
+            Some(bit) => (data ^ 1 << bit, EccKind::Corrected),
+            None => (data, EccKind::Corrected),
+        },
+        (0, true) => (data, EccKind::Corrected),
+        // An even number of flipped bits, or a position no single bit can have
+        _ => (data, EccKind::Uncorrectable),
+    }
+}
+
+/// The data bit at the given code position of [hamming_syndrome], `None` for the positions of the parity bits
+fn data_bit(position: u32) -> Option<u32> {
+    if position.is_power_of_two() {
+        return None;
+    }
+    // Skip the parity bits at positions 1, 2, 4, ... up to the position
+    Some(position - (32 - position.leading_zeros()) - 1)
+}
+
+/// What the flash holds after [simulate_double_write]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DoubleWriteResult {
+    /// The stored data bits. For [EccKind::Corrected], a read returns the corrected value instead
+    pub data: u64,
+    /// The stored ECC bits, in the layout of [compute_ecc]
+    pub ecc: u8,
+    /// What reading the doubleword back would report
+    pub error: EccKind,
+}
+
+/// Models programming `first` into an erased doubleword and then `second` over it, without an erase in between,
+/// using the ECC model of [compute_ecc]. Programming can only turn ones into zeros, in the data and the ECC bits,
+/// so the flash ends up with `first & second` and the AND of both ECCs. These only stay consistent if one of
+/// the writes didn't add any zeros.
+pub fn simulate_double_write(first: u64, second: u64) -> DoubleWriteResult {
+    let data = first & second;
+    let ecc = compute_ecc(first) & compute_ecc(second);
+
+    let (_, error) = decode_ecc(data, ecc);
+
+    DoubleWriteResult { data, ecc, error }
+}
+
+/// True if programming `second` over `first` without an erase in between gives an ECC error at all,
+/// corrected or not (see [simulate_double_write])
+pub fn double_write_breaks_ecc(first: u64, second: u64) -> bool {
+    simulate_double_write(first, second).error != EccKind::None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn erased_doubleword_is_valid() {
+        assert_eq!(compute_ecc(u64::MAX), 0xFF);
+        assert_eq!(decode_ecc(u64::MAX, 0xFF), (u64::MAX, EccKind::None));
+    }
+
+    #[test]
+    fn known_vectors() {
+        // Clearing data bit 0 (code position 3) sets parity bits 0 and 1, and the overall parity
+        assert_eq!(compute_ecc(!1), !0b1000_0011);
+        // Data bit 1 is at position 5 (parity bits 0 and 2)
+        assert_eq!(compute_ecc(!2), !0b1000_0101);
+        // Positions 3 and 5 cancel out in parity bit 0, and the overall parity is even
+        assert_eq!(compute_ecc(!3), !0b0000_0110);
+        // Data bit 63 is at position 71 = 0b100_0111
+        assert_eq!(compute_ecc(!(1 << 63)), !0b1100_0111);
+        // Clearing all 64 data bits sets every parity bit
+        assert_eq!(compute_ecc(0), !0b1111_1111);
+    }
+
+    #[test]
+    fn matching_ecc_decodes_without_error() {
+        for value in [0, 1, 0x5555_5555_5555_5555, 0x0123_4567_89AB_CDEF, u64::MAX] {
+            assert_eq!(
+                decode_ecc(value, compute_ecc(value)),
+                (value, EccKind::None)
+            );
+        }
+    }
+
+    #[test]
+    fn single_bit_errors_are_corrected() {
+        let value = 0x0123_4567_89AB_CDEF;
+        let ecc = compute_ecc(value);
+        for bit in 0..64 {
+            assert_eq!(
+                decode_ecc(value ^ 1 << bit, ecc),
+                (value, EccKind::Corrected),
+                "data bit {}",
+                bit
+            );
+        }
+        for bit in 0..8 {
+            assert_eq!(
+                decode_ecc(value, ecc ^ 1 << bit),
+                (value, EccKind::Corrected),
+                "ECC bit {}",
+                bit
+            );
+        }
+    }
+
+    #[test]
+    fn double_bit_errors_are_detected() {
+        let value = 0x0123_4567_89AB_CDEF;
+        let ecc = compute_ecc(value);
+        for first in 0..72 {
+            for second in first + 1..72 {
+                let flip = |bit: u32| -> (u64, u8) {
+                    if bit < 64 {
+                        (1 << bit, 0)
+                    } else {
+                        (0, 1 << (bit - 64))
+                    }
+                };
+                let (data_a, ecc_a) = flip(first);
+                let (data_b, ecc_b) = flip(second);
+                let corrupted = value ^ data_a ^ data_b;
+                assert_eq!(
+                    decode_ecc(corrupted, ecc ^ ecc_a ^ ecc_b),
+                    (corrupted, EccKind::Uncorrectable),
+                    "bits {} and {}",
+                    first,
+                    second
+                );
+            }
+        }
+    }
+
+    /// `(first, second, error)`: what reading back gives after programming `first` and then `second`
+    const DOUBLE_WRITES: [(u64, u64, EccKind); 13] = [
+        // The same value again doesn't clear anything new
+        (0x0123_4567_89AB_CDEF, 0x0123_4567_89AB_CDEF, EccKind::None),
+        // One of the writes doesn't clear any bits
+        (0x0123_4567_89AB_CDEF, u64::MAX, EccKind::None),
+        (u64::MAX, 0x0123_4567_89AB_CDEF, EccKind::None),
+        // The second clears a superset of the bits of the first
+        (!1, !3, EccKind::Uncorrectable),
+        (!0xF, !0xFF, EccKind::Uncorrectable),
+        // The second clears a subset, so the data stays the same, but its ECC still clears other ECC bits
+        (!3, !1, EccKind::Uncorrectable),
+        (!0xFF, !0xF, EccKind::Uncorrectable),
+        // Disjoint bits
+        (!1, !2, EccKind::Uncorrectable),
+        (!0xF, !0xF0, EccKind::Uncorrectable),
+        // Only one ECC bit too many is cleared, which looks like a single-bit error
+        (!1, !0x101, EccKind::Corrected),
+        // Zero has an all-zeros ECC, so whatever was written before, data and ECC match again
+        (!1, 0, EccKind::None),
+        (0x5555_5555_5555_5555, 0, EccKind::None),
+        // Complementary values end up as zero too
+        (0x5555_5555_5555_5555, 0xAAAA_AAAA_AAAA_AAAA, EccKind::None),
+    ];
+
+    #[test]
+    fn double_write_matrix() {
+        for (first, second, error) in DOUBLE_WRITES {
+            let result = simulate_double_write(first, second);
+            let pair = format!("{:#x} then {:#x}", first, second);
+
+            // Programming only clears bits, in the data and in the ECC
+            assert_eq!(result.data, first & second, "{}", pair);
+            assert_eq!(
+                result.ecc,
+                compute_ecc(first) & compute_ecc(second),
+                "{}",
+                pair
+            );
+            assert_eq!(result.error, error, "{}", pair);
+            // Without an error, the stored ECC is the one of the stored data
+            assert_eq!(
+                error == EccKind::None,
+                result.ecc == compute_ecc(result.data),
+                "{}",
+                pair
+            );
+            assert_eq!(
+                double_write_breaks_ecc(first, second),
+                error != EccKind::None
+            );
+        }
+    }
+
+    #[test]
+    fn split_line_write_keeps_the_ecc() {
+        // Programming each doubleword of a 128-bit line once after the erase, one half after the other, doesn't
+        // corrupt anything: with an ECC per doubleword, neither half can report an error. Only a write or erase
+        // interrupted by a reset can, which is why the experiments all reuse the timed write of the search
+        assert_eq!(simulate_double_write(u64::MAX, 0).error, EccKind::None);
+        assert_eq!(decode_ecc(0, compute_ecc(0)), (0, EccKind::None));
+    }
+
+    #[test]
+    fn double_write_can_be_corrected_to_the_wrong_value() {
+        // Bit 0 added by the second write looks like a single flipped bit, so a read "corrects" it away and
+        // silently returns the first value
+        let result = simulate_double_write(!(1 << 63), !(1 << 63 | 1));
+        assert_eq!(result.error, EccKind::Corrected);
+        assert_eq!(
+            decode_ecc(result.data, result.ecc),
+            (!(1 << 63), EccKind::Corrected)
+        );
+    }
+}
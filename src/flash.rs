@@ -1,29 +1,384 @@
+// This is a general driver for the flash peripheral, so the parts the corruptor itself doesn't use are marked `#[allow(dead_code)]`
+
 use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
-use cortex_m::asm::dmb;
+use cortex_m::asm::{dmb, dsb, isb};
 use stm32l4::stm32l4r5;
 
+use crate::ecc::{compute_ecc, EccKind};
+use crate::mem;
+use stm32_flash_corruptor::geometry::{self, page_count_for};
+
+/// With the `trace` feature, sends the name of a flash register and the value written to it over ITM
+/// (see [trace_register_write]), so the exact register sequence can be compared to the reference manual.
+/// Without the feature, this expands to nothing and the value expression isn't evaluated at all.
+macro_rules! trace_register {
+    ($name:expr, $value:expr) => {
+        #[cfg(feature = "trace")]
+        trace_register_write($name, $value);
+    };
+}
+
+/// Writes the register name (4 ASCII characters, e.g. `b"CR  "`) and the value as two 32-bit words to
+/// [crate::hw::REGISTER_TRACE_PORT]. For registers that can be read back, the value is read after the write,
+/// so it's what the register actually contains.
+#[cfg(feature = "trace")]
+fn trace_register_write(name: &[u8; 4], value: u32) {
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    let itm = &mut core_peripherals.ITM;
+    crate::hw::itm_write_u32(
+        itm,
+        crate::hw::REGISTER_TRACE_PORT,
+        u32::from_le_bytes(*name),
+    );
+    crate::hw::itm_write_u32(itm, crate::hw::REGISTER_TRACE_PORT, value);
+}
+
+pub use stm32_flash_corruptor::registers::{CrState, EccError, Error, ProgrammingFlags};
+use stm32_flash_corruptor::registers::{ECCR_CORRECTION_FLAGS, ECCR_DOUBLE_ERROR_FLAGS};
+use stm32_flash_corruptor::sequence::{self, FlashRegs, Operation, Register, WAIT_POLLS};
+pub use stm32_flash_corruptor::sequence::{Spin, WaitStrategy};
+
+/// Returned by [FlashUnlocked::program_with_verify] when a doubleword couldn't be programmed or verified
 #[derive(Debug, Clone, Copy)]
-pub enum Error {
-    /// Unlocking the flash failed. This should never happen and requires a reset to escape from
-    UnlockFailed = 0b1,
-    /// The flash is currently busy. This should go away after some time or indicates a timeout.
-    Busy = 0b10,
-    /// Flash programming failed due to an software implementation error.
-    Illegal = 0b11,
-    /// The given page number does not exist in the current bank mode.
-    InvalidPage = 0b100,
+pub struct VerifyFailure {
+    /// Index of the doubleword that failed
+    #[allow(dead_code)]
+    pub index: usize,
+    /// What went wrong, e.g. [Error::VerifyMismatch] or [Error::Corrupted]
+    pub error: Error,
+}
+
+/// Set while a checked read is in progress. The NMI handler uses this to decide whether an ECC error
+/// is expected (and can be recovered from) or is the result of a corruption attempt.
+static ECC_FAULT_EXPECTED: AtomicBool = AtomicBool::new(false);
+/// The FLASH_ECCR content captured by the NMI handler during a checked read, zero if no error happened
+static ECC_FAULT_CAPTURED: AtomicU32 = AtomicU32::new(0);
+
+/// All programming error flags that [FlashUnlocked::clear_programming_flags] found still set since boot
+static STALE_PROGRAMMING_FLAGS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns all programming error flags that were still set from an earlier operation when an erase or
+/// write started (see [FlashUnlocked::clear_programming_flags]), since boot
+pub fn stale_programming_flags() -> ProgrammingFlags {
+    ProgrammingFlags(STALE_PROGRAMMING_FLAGS.load(Ordering::SeqCst))
+}
+
+/// Cycles the last page erase took, see [last_operation_cycles]
+static LAST_ERASE_CYCLES: AtomicU32 = AtomicU32::new(0);
+/// Cycles programming the last doubleword took, see [last_operation_cycles]
+static LAST_PROGRAM_CYCLES: AtomicU32 = AtomicU32::new(0);
+
+/// Returns how many cycles the last page erase and the last doubleword programming took, from setting STRT
+/// (or writing the second word) until BSY was cleared. This compares the real durations with the datasheet
+/// times the timeouts are based on, and bounds the timing window of the search.
+///
+/// This is only measured with the `measure-ops` feature, otherwise both are always zero.
+pub fn last_operation_cycles() -> (u32, u32) {
+    (
+        LAST_ERASE_CYCLES.load(Ordering::SeqCst),
+        LAST_PROGRAM_CYCLES.load(Ordering::SeqCst),
+    )
+}
+
+/// The cycle count at the start of an operation for [record_operation], only read with the `measure-ops` feature
+#[inline(always)]
+fn operation_started() -> u32 {
+    if cfg!(feature = "measure-ops") {
+        cortex_m::peripheral::DWT::cycle_count()
+    } else {
+        0
+    }
+}
+
+/// Stores the cycles since `started_at` (from [operation_started]) in `counter`, only with the `measure-ops` feature
+#[inline(always)]
+fn record_operation(counter: &AtomicU32, started_at: u32) {
+    if cfg!(feature = "measure-ops") {
+        let cycles = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(started_at);
+        counter.store(cycles, Ordering::SeqCst);
+    }
+}
+
+/// Returns the FLASH_ECCR content captured during the last checked read that hit an ECC error
+pub fn captured_ecc_error() -> u32 {
+    ECC_FAULT_CAPTURED.load(Ordering::SeqCst)
+}
+
+/// Must be called at the start of the NMI handler. If a checked read (e.g. [Flash::read_dword_checked])
+/// is currently in progress, this records and clears the ECC error and returns true, meaning the handler
+/// should just return. Otherwise, nothing is touched and false is returned.
+pub fn recover_checked_read_fault(flash: &stm32l4r5::FLASH) -> bool {
+    if !ECC_FAULT_EXPECTED.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let eccr = flash.eccr.read().bits();
+    ECC_FAULT_CAPTURED.store(eccr, Ordering::SeqCst);
+
+    // We don't want to clear single-bit (corrected) errors here
+    sequence::clear_ecc_flags(&Registers(flash), eccr & ECCR_DOUBLE_ERROR_FLAGS);
+
+    true
 }
 
 /// Abstracts interaction with the flash hardware
 pub struct Flash {
     flash: stm32l4r5::FLASH,
+    /// Size of the flash memory in KB, as read from the flash size register
+    size_kb: u16,
+    /// The FLASH_OPTR bit that selects dual-bank mode on this device
+    dual_bank_bit: u8,
+}
+
+/// The registers of the flash interface, for the sequences in [sequence]. Writes are traced with the `trace`
+/// feature (see [trace_register]), and the operations are measured with `measure-ops` (see [last_operation_cycles]).
+pub struct Registers<'a>(&'a stm32l4r5::FLASH);
+
+impl<'a> FlashRegs for Registers<'a> {
+    fn read(&self, register: Register) -> u32 {
+        match register {
+            Register::Sr => self.0.sr.read().bits(),
+            Register::Cr => self.0.cr.read().bits(),
+            Register::Eccr => self.0.eccr.read().bits(),
+        }
+    }
+
+    fn write(&self, register: Register, value: u32) {
+        match register {
+            Register::Sr => {
+                self.0.sr.write(|w| unsafe { w.bits(value) });
+                trace_register!(b"SR  ", self.0.sr.read().bits());
+            }
+            Register::Cr => {
+                self.0.cr.write(|w| unsafe { w.bits(value) });
+                trace_register!(b"CR  ", self.0.cr.read().bits());
+            }
+            Register::Eccr => self.0.eccr.write(|w| unsafe { w.bits(value) }),
+        }
+    }
+
+    fn program_dword(&self, address: u32, value: u64) {
+        // From the manual:
+        // > Only double word can be programmed.
+        // > – Write a first word in an address aligned with double word
+        // > – Write the second word
+        // The two words are put together by the flash interface, and programming only starts after the second
+        // one. Volatile writes are never reordered with each other (and the Cortex-M4 issues them in program
+        // order), so no barrier is needed *between* the two halves - the low word always comes first.
+        // After the second word however, we use a DSB to make sure both writes have actually completed before
+        // we start polling BSY, otherwise we might read the status before the programming has even started.
+        let address = address as *mut u32;
+        unsafe {
+            core::ptr::write_volatile(address, value as u32);
+            core::ptr::write_volatile(address.add(1), (value >> 32) as u32);
+        }
+        dsb();
+    }
+
+    fn operation_started(&self) -> u32 {
+        operation_started()
+    }
+
+    fn operation_finished(&self, operation: Operation, started_at: u32) {
+        let counter = match operation {
+            Operation::Erase => &LAST_ERASE_CYCLES,
+            Operation::Program => &LAST_PROGRAM_CYCLES,
+        };
+        record_operation(counter, started_at);
+    }
+}
+
+/// Both halves of a 128-bit flash line, each with the ECC result of its own read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineResult {
+    /// The doubleword at the start of the line
+    pub low: u64,
+    /// The doubleword 8 bytes after the start of the line
+    pub high: u64,
+    pub low_error: EccKind,
+    pub high_error: EccKind,
+}
+
+/// Like [Flash::ecc_status], but for contexts without a [Flash], e.g. a fault handler that stole the peripherals.
+/// Reading FLASH_ECCR has no side effects, so this is safe to call while a [Flash] exists elsewhere.
+///
+/// Without a [Flash], the bank mode isn't known, so pass it in: the one the binary was built for, which
+/// is checked at boot.
+pub fn ecc_status_raw(flash: &stm32l4r5::FLASH, dualbank: bool) -> Option<EccError> {
+    EccError::from_eccr(flash.eccr.read().bits(), dualbank)
+}
+
+/// What [FlashUnlocked::self_test] writes: alternating bits, all bits and a value where every byte differs
+pub const SELF_TEST_PATTERN: [u64; 4] = [
+    0x5555_5555_5555_5555,
+    0xAAAA_AAAA_AAAA_AAAA,
+    0x0000_0000_0000_0000,
+    0x0123_4567_89AB_CDEF,
+];
+
+/// How long a dry run (`dry-run` feature) takes for erasing a page: 22ms, the typical page erase time of the datasheet
+const DRY_RUN_ERASE_CYCLES: u32 = crate::hw::SYSTEM_CLOCK_HZ / 1000 * 22;
+/// How long a dry run takes for programming a doubleword: 82µs, the typical time of the datasheet
+const DRY_RUN_DWORD_CYCLES: u32 = crate::hw::SYSTEM_CLOCK_HZ / 1_000_000 * 82;
+/// How long a dry run takes for programming a row with fast programming: about 2ms according to the datasheet
+const DRY_RUN_ROW_CYCLES: u32 = crate::hw::SYSTEM_CLOCK_HZ / 1000 * 2;
+
+/// Result of [Flash::count_ecc_corrections]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EccCounts {
+    /// Doublewords with a single-bit error, which the ECC corrected
+    pub corrected: u32,
+    /// Doublewords with a double-bit error, which can't be corrected (and would have faulted)
+    pub uncorrectable: u32,
+}
+
+/// Reads a 32-bit word without calling anything, so it can be used by [program_from_ram]
+#[inline(always)]
+unsafe fn ram_read(address: u32) -> u32 {
+    let value;
+    core::arch::asm!("ldr {value}, [{address}]", address = in(reg) address, value = out(reg) value,
+        options(nostack, preserves_flags));
+    value
+}
+
+/// Writes a 32-bit word without calling anything, so it can be used by [program_from_ram]
+#[inline(always)]
+unsafe fn ram_write(address: u32, value: u32) {
+    core::arch::asm!("str {value}, [{address}]", address = in(reg) address, value = in(reg) value,
+        options(nostack, preserves_flags));
+}
+
+/// Standard programming of `dwords` doublewords from `data` to `address`, like [FlashUnlocked::write_dwords]
+/// does it, but running from RAM: it is copied into `.data` at startup.
+///
+/// While the flash programs a bank, fetching code from the same bank stalls the core until the operation is done.
+/// In single-bank mode, that's every fetch. This routine doesn't touch the flash at all, so it keeps running
+/// while a doubleword is programmed. That's why it only uses [ram_read] and [ram_write] and plain integer
+/// arithmetic - anything else might call a function in flash, at least in debug builds.
+/// An interrupt would still stall, as the vector table and handlers are in flash.
+///
+/// `cr` and `sr` are the addresses of FLASH_CR and FLASH_SR. PG is set at the start and cleared at the end.
+/// Returns FLASH_SR after the last doubleword, or after the first one that failed or timed out.
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn program_from_ram(
+    cr: u32,
+    sr: u32,
+    mut address: u32,
+    mut data: u32,
+    dwords: u32,
+    max_polls: u32,
+) -> u32 {
+    /// FLASH_CR bit 0
+    const CR_PG: u32 = 1;
+
+    ram_write(cr, ram_read(cr) | CR_PG);
+
+    let mut status = ram_read(sr);
+    let mut written = 0;
+    while written < dwords {
+        // Read the data first, it might be in the bank we are about to program
+        let low = ram_read(data);
+        let high = ram_read(data.wrapping_add(4));
+        ram_write(address, low);
+        ram_write(address.wrapping_add(4), high);
+        core::arch::asm!("dsb", options(nostack, preserves_flags));
+
+        let mut polls = 0;
+        while polls < max_polls && ram_read(sr) & Error::SR_BSY != 0 {
+            polls = polls.wrapping_add(1);
+        }
+        status = ram_read(sr);
+        if status & (Error::SR_BSY | Error::SR_SIZERR | Error::SR_PROGERR | Error::SR_ILLEGAL_MASK)
+            != 0
+        {
+            break;
+        }
+
+        address = address.wrapping_add(8);
+        data = data.wrapping_add(8);
+        written = written.wrapping_add(1);
+    }
+
+    ram_write(cr, ram_read(cr) & !CR_PG);
+    status
+}
+
+/// Erases `pages` consecutive pages like [FlashUnlocked::erase_page_range] does, but running from RAM, for
+/// the same reason and with the same restrictions as [program_from_ram].
+///
+/// The first page is `page_in_bank` of `bank`, the following ones continue in the next bank after
+/// `pages_per_bank` pages. `cr` and `sr` are the addresses of FLASH_CR and FLASH_SR. PER is set at the start and
+/// cleared at the end. Returns FLASH_SR after the last page, or after the first one that failed or timed out.
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn erase_from_ram(
+    cr: u32,
+    sr: u32,
+    mut bank: u32,
+    mut page_in_bank: u32,
+    pages: u32,
+    pages_per_bank: u32,
+    max_polls: u32,
+) -> u32 {
+    /// FLASH_CR bit 1
+    const CR_PER: u32 = 1 << 1;
+    /// FLASH_CR bits 3-10
+    const CR_PNB_SHIFT: u32 = 3;
+    const CR_PNB_MASK: u32 = 0xFF << CR_PNB_SHIFT;
+    /// FLASH_CR bit 11
+    const CR_BKER: u32 = 1 << 11;
+    /// FLASH_CR bit 16
+    const CR_STRT: u32 = 1 << 16;
+
+    ram_write(cr, ram_read(cr) | CR_PER);
+
+    let mut status = ram_read(sr);
+    let mut erased = 0;
+    while erased < pages {
+        let selected = ram_read(cr) & !(CR_PNB_MASK | CR_BKER)
+            | page_in_bank << CR_PNB_SHIFT
+            | if bank == 1 { CR_BKER } else { 0 };
+        ram_write(cr, selected);
+        ram_write(cr, selected | CR_STRT);
+        core::arch::asm!("dsb", options(nostack, preserves_flags));
+
+        let mut polls = 0;
+        while polls < max_polls && ram_read(sr) & Error::SR_BSY != 0 {
+            polls = polls.wrapping_add(1);
+        }
+        status = ram_read(sr);
+        if status & (Error::SR_BSY | Error::SR_SIZERR | Error::SR_PROGERR | Error::SR_ILLEGAL_MASK)
+            != 0
+        {
+            break;
+        }
+
+        page_in_bank = page_in_bank.wrapping_add(1);
+        if page_in_bank == pages_per_bank {
+            page_in_bank = 0;
+            bank = bank.wrapping_add(1);
+        }
+        erased = erased.wrapping_add(1);
+    }
+
+    ram_write(cr, ram_read(cr) & !CR_PER);
+    status
 }
 
 /// Represents a Flash object that has been unlocked for programming.
 /// See <https://rust-unofficial.github.io/patterns/patterns/behavioural/RAII.html>
+///
+/// This only borrows the [Flash] immutably, so read-only methods (e.g. [Flash::ecc_status])
+/// can still be used on the original object while the flash is unlocked.
+///
+/// It also remembers the pages of the last successful erase, writes are only allowed there. A page erased in an
+/// earlier boot (or with another [FlashUnlocked]) doesn't count, as a reset might have happened in between.
 pub struct FlashUnlocked<'a> {
-    flash: &'a mut Flash,
+    flash: &'a Flash,
+    erased_pages: core::ops::Range<u32>,
 }
 
 #[cfg(not(kani))]
@@ -38,7 +393,8 @@ impl<'a> Drop for FlashUnlocked<'a> {
         // > (FLASH_SR) is set. Any attempt to write to it with the BSY bit set will cause the AHB bus to
         // > stall until the BSY bit is cleared
         // This is fine for us, since we would want to wait for the flash to finish anyway.
-        self.flash.flash.cr.modify(|_, w| w.lock().clear_bit());
+        self.flash.flash.cr.modify(|_, w| w.lock().set_bit());
+        trace_register!(b"CR  ", self.flash.flash.cr.read().bits());
     }
 }
 
@@ -52,65 +408,158 @@ impl<'a> Deref for FlashUnlocked<'a> {
 }
 
 impl Flash {
+    /// Bit of DBANK in FLASH_OPTR, see [Flash::dbank]
+    const DBANK_BIT: u8 = 22;
+    /// Bit of DB1M in FLASH_OPTR, see [Flash::db1m]
+    const DB1M_BIT: u8 = 21;
     /// Constant value from STM Documentation
     const FLASH_KEY1: u32 = 0x4567_0123;
     /// Constant value from STM Documentation
     const FLASH_KEY2: u32 = 0xCDEF_89AB;
+    /// Constant value from STM Documentation, for unlocking the option bytes
+    #[cfg(feature = "destructive")]
+    const OPT_KEY1: u32 = 0x0819_2A3B;
+    /// Constant value from STM Documentation, for unlocking the option bytes
+    #[cfg(feature = "destructive")]
+    const OPT_KEY2: u32 = 0x4C5D_6E7F;
+
+    /// Create flash interaction abstraction from HAL object.
+    /// This reads the flash size register to find out how dual-bank mode is configured on this device,
+    /// and returns [Error::UnsupportedDevice] for sizes other than 1MB and 2MB.
+    pub fn new(flash: stm32l4r5::FLASH) -> Result<Self, Error> {
+        let size_kb = crate::hw::flash_size_kb();
+
+        // 2MB devices need to care about the DBANK bit (Bit 22),
+        // while 1MB devices have to check DB1M (Bit 21)
+        let dual_bank_bit = match size_kb {
+            2048 => Self::DBANK_BIT,
+            1024 => Self::DB1M_BIT,
+            _ => return Err(Error::UnsupportedDevice),
+        };
+
+        Ok(Flash {
+            flash,
+            size_kb,
+            dual_bank_bit,
+        })
+    }
+
+    /// The smallest unit the flash can be programmed in, in bytes: a doubleword on the STM32L4, as every doubleword
+    /// has its own ECC. Writes must be aligned to it and a multiple of it. This is a `const fn`, so it can be used
+    /// for array sizes and other constants.
+    pub const fn write_granularity() -> usize {
+        core::mem::size_of::<u64>()
+    }
 
-    /// Create flash interaction abstraction from HAL object
-    pub fn new(flash: stm32l4r5::FLASH) -> Self {
-        Flash { flash }
+    /// Size of the flash memory in KB, as reported by the flash size register
+    pub fn size_kb(&self) -> u16 {
+        self.size_kb
+    }
+
+    /// The registers of the flash interface, for the sequences in [sequence]
+    pub fn regs(&self) -> Registers<'_> {
+        Registers(&self.flash)
+    }
+
+    /// Number of pages over all banks, from the flash size register and the current bank mode. This is what
+    /// page numbers are checked against, so it's right on the 1MB variants as well.
+    pub fn page_count(&self) -> u32 {
+        page_count_for(self.size_kb, self.is_dualbank())
+    }
+
+    /// The FLASH_OPTR bit [Flash::is_dualbank] looks at: 22 (DBANK) on 2MB devices, 21 (DB1M) on 1MB devices
+    #[allow(dead_code)]
+    pub fn dual_bank_bit(&self) -> u8 {
+        self.dual_bank_bit
     }
 
     /// True if the chip is in dual bank mode. If false, the chip is in single bank mode.
-    /// This decides whether the flash page size is [DUAL_BANK_PAGE_SIZE] or [SINGLE_BANK_PAGE_SIZE]
+    /// This decides the flash page size, see [mem::page_size]
     pub fn is_dualbank(&self) -> bool {
-        // Since we are on an 2MB device, we need to care about the DBANK bit (Bit 22),
-        // while <= 1MB devices would have to check DB1M (Bit 21)
+        if self.dual_bank_bit == Self::DBANK_BIT {
+            self.dbank()
+        } else {
+            self.db1m()
+        }
+    }
+
+    /// Reads and decodes the control register (FLASH_CR). Reading it has no side effects,
+    /// so this can be called at any time, e.g. on an error path.
+    #[allow(dead_code)]
+    pub fn cr_snapshot(&self) -> CrState {
+        CrState::from_bits(self.flash.cr.read().bits())
+    }
+
+    /// The option register (FLASH_OPTR), as loaded from the option bytes at reset
+    #[allow(dead_code)]
+    pub fn optr(&self) -> &stm32l4r5::flash::OPTR {
+        &self.flash.optr
+    }
+
+    /// The DBANK option bit (FLASH_OPTR bit 22), which selects dual-bank mode on **2MB** devices.
+    /// On 1MB devices, this bit has no effect, use [Flash::db1m] there. [Flash::is_dualbank] picks the right one.
+    pub fn dbank(&self) -> bool {
         // stm32l4 crate doesn't have a function for DBANK, so do it manually
-        // Note that it does have one for DB1M named "dualbank", which is the wrong one
-        // to check on a 2MB device.
-        const BIT_22_BITMASK: u32 = 1 << 22;
-        let dual_bank_bit = self.flash.optr.read().bits() & BIT_22_BITMASK;
+        self.flash.optr.read().bits() & (1 << Self::DBANK_BIT) != 0
+    }
 
-        return dual_bank_bit != 0;
+    /// The DB1M option bit (FLASH_OPTR bit 21), which selects dual-bank mode on **1MB** devices.
+    /// On 2MB devices, this bit has no effect, use [Flash::dbank] there. [Flash::is_dualbank] picks the right one.
+    ///
+    /// The stm32l4 crate reads this bit with `optr.read().dualbank()`, which is the wrong one to check
+    /// on a 2MB device despite its name.
+    pub fn db1m(&self) -> bool {
+        self.flash.optr.read().bits() & (1 << Self::DB1M_BIT) != 0
     }
 
     /// Page size in the current mode (depending on [Flash::is_dualbank])
     pub fn page_size(&self) -> u32 {
-        if self.is_dualbank() {
-            0x1000
-        } else {
-            0x2000
-        }
+        mem::page_size(self)
     }
 
     /// Reads the current flash status:
-    /// Errors are: the flash is busy or got an illegal programming sequence.
+    /// Errors are: the flash is busy, got a write with the wrong size or an illegal programming sequence.
     /// Otherwise, the Flash is ready to be written to.
     fn status(&self) -> Result<(), Error> {
-        let sr = self.flash.sr.read();
+        Error::from_status_bits(self.flash.sr.read().bits())
+    }
 
-        if sr.bsy().bit_is_set() {
-            Err(Error::Busy)
-        } else if sr.pgaerr().bit_is_set() || sr.progerr().bit_is_set() || sr.wrperr().bit_is_set()
-        {
-            Err(Error::Illegal)
-        } else {
-            Ok(())
+    /// True if a flash operation is currently ongoing (BSY bit in FLASH_SR).
+    /// Unlike [FlashUnlocked::wait], this doesn't block and doesn't check or clear any error flags.
+    pub fn is_busy(&self) -> bool {
+        self.flash.sr.read().bsy().bit_is_set()
+    }
+
+    /// Checks the precondition of every erase and write: the flash isn't busy and no programming error is pending.
+    /// Waits for an ongoing operation like [FlashUnlocked::wait] does, so this may block for up to its timeout,
+    /// and then returns [Error::Busy]. This is meant to validate the state once, before a sequence of operations.
+    ///
+    /// Pending error flags are only reported, not cleared (see [FlashUnlocked::clear_programming_flags]).
+    #[allow(dead_code)]
+    pub fn assert_ready(&self) -> Result<(), Error> {
+        for _ in 0..WAIT_POLLS {
+            if !self.is_busy() {
+                break;
+            }
         }
+
+        self.status()
     }
 
     /// Unlock the flash according to the unlock sequence (see 3.3.5 Flash program and erase operations).
     /// The returned object, if [Ok], will automatically relock the flash once it gets dropped (RAII).
-    pub fn unlock(&mut self) -> Result<FlashUnlocked, Error> {
+    ///
+    /// Note that only one of these objects should exist at a time, as dropping one relocks the flash for all.
+    pub fn unlock(&self) -> Result<FlashUnlocked<'_>, Error> {
         self.flash
             .keyr
             .write(|w| unsafe { w.keyr().bits(Flash::FLASH_KEY1) });
+        trace_register!(b"KEYR", Flash::FLASH_KEY1);
         dmb();
         self.flash
             .keyr
             .write(|w| unsafe { w.keyr().bits(Flash::FLASH_KEY2) });
+        trace_register!(b"KEYR", Flash::FLASH_KEY2);
         dmb();
 
         // Lock bit:
@@ -123,163 +572,900 @@ impl Flash {
             return Err(Error::UnlockFailed);
         }
 
-        Ok(FlashUnlocked { flash: self })
+        Ok(FlashUnlocked {
+            flash: self,
+            erased_pages: 0..0,
+        })
+    }
+
+    /// Reads every doubleword touching `[start, start + len)` with [Flash::read_dword_checked].
+    /// Returns true if any of them has a double-bit ECC error, the FLASH_ECCR content of the last one
+    /// is then available from [captured_ecc_error].
+    pub fn read_range_faults(&self, start: u32, len: u32) -> bool {
+        const DWORD_SIZE: u32 = core::mem::size_of::<u64>() as u32;
+
+        // Stale cache lines would hide the error, the reads must reach the flash memory
+        self.flush_data_cache();
+
+        // ECC works on doublewords, so reading each doubleword touching the range is enough
+        let first_dword = start & !(DWORD_SIZE - 1);
+        let mut faulted = false;
+        for addr in (first_dword..start + len).step_by(DWORD_SIZE as usize) {
+            faulted |= self.read_dword_checked(addr as *const u64).is_err();
+        }
+
+        faulted
+    }
+
+    /// Checks that every doubleword touching `[start, start + len)` is erased (all ones), and returns the
+    /// address of the first one that isn't. A doubleword with a double-bit ECC error counts as not erased,
+    /// as an incompletely erased doubleword often has one - it is read with [Flash::read_dword_checked].
+    ///
+    /// The STM32L4R5 has no hardware blank check (PEMPTY in FLASH_SR only tells whether the start of the bank is
+    /// programmed, for booting), so this reads the range, but stops at the first doubleword that isn't erased.
+    /// With `bypass_cache`, the data cache is disabled while reading, so every read really reaches the flash
+    /// and reports its ECC status, instead of only flushing it once at the start.
+    pub fn verify_erased(&self, start: u32, len: u32, bypass_cache: bool) -> Option<u32> {
+        const DWORD_SIZE: u32 = core::mem::size_of::<u64>() as u32;
+
+        let acr = &self.flash.acr;
+        let dcache_enabled = acr.read().dcen().bit_is_set();
+        self.flush_data_cache();
+        if bypass_cache {
+            acr.modify(|_, w| w.dcen().clear_bit());
+        }
+
+        let first_dword = start & !(DWORD_SIZE - 1);
+        let not_erased = (first_dword..start + len)
+            .step_by(DWORD_SIZE as usize)
+            .find(|&addr| self.read_dword_checked(addr as *const u64) != Ok(u64::MAX));
+
+        acr.modify(|_, w| w.dcen().bit(dcache_enabled));
+
+        not_erased
+    }
+
+    /// Reads every doubleword touching `[start, start + len)` and counts how many of them had a single-bit ECC error
+    /// that was corrected (ECCC or ECCC2 in FLASH_ECCR), to tell how severe a partial corruption is.
+    ///
+    /// Doublewords with a double-bit error would fault, so they are read with [Flash::read_dword_checked] and
+    /// counted separately in [EccCounts::uncorrectable]. The data cache is disabled during the scan, as a
+    /// doubleword coming from the cache doesn't go through the ECC check again.
+    #[allow(dead_code)]
+    pub fn count_ecc_corrections(&self, start: u32, len: u32) -> EccCounts {
+        const DWORD_SIZE: u32 = core::mem::size_of::<u64>() as u32;
+
+        let acr = &self.flash.acr;
+        let dcache_enabled = acr.read().dcen().bit_is_set();
+        self.flush_data_cache();
+        acr.modify(|_, w| w.dcen().clear_bit());
+
+        let mut counts = EccCounts::default();
+        let first_dword = start & !(DWORD_SIZE - 1);
+        for addr in (first_dword..start + len).step_by(DWORD_SIZE as usize) {
+            // Each checked read clears the flags of the one before, so every correction is only counted once
+            if self.read_dword_checked(addr as *const u64).is_err() {
+                counts.uncorrectable += 1;
+            } else if self.flash.eccr.read().bits() & ECCR_CORRECTION_FLAGS != 0 {
+                counts.corrected += 1;
+            }
+        }
+        self.clear_ecc_error();
+
+        acr.modify(|_, w| w.dcen().bit(dcache_enabled));
+
+        counts
+    }
+
+    /// The bank that is mapped to the boot address (and address 0), according to the BFB2 option bit.
+    /// With BFB2 set, the chip boots from bank 1 (the manual's Bank 2) and the banks are swapped.
+    /// This only has an effect in dual-bank mode, so it's always bank 0 in single-bank mode.
+    #[allow(dead_code)]
+    pub fn boot_bank(&self) -> u8 {
+        if self.is_dualbank() && self.flash.optr.read().bfb2().bit_is_set() {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Configures the flash accelerator in FLASH_ACR: the instruction cache (ICEN, bit 9), the data cache
+    /// (DCEN, bit 10) and the prefetch buffer (PRFTEN, bit 8). After reset, both caches are on and prefetch is off.
+    ///
+    /// Disabling a cache also resets it, as a cache can only be reset (ICRST/DCRST) while it is disabled.
+    #[allow(dead_code)]
+    pub fn set_caches(&mut self, icache: bool, dcache: bool, prefetch: bool) {
+        let acr = &self.flash.acr;
+        acr.modify(|_, w| {
+            w.icen()
+                .bit(icache)
+                .dcen()
+                .bit(dcache)
+                .prften()
+                .bit(prefetch)
+        });
+        if !icache {
+            acr.modify(|_, w| w.icrst().set_bit());
+            acr.modify(|_, w| w.icrst().clear_bit());
+        }
+        if !dcache {
+            acr.modify(|_, w| w.dcrst().set_bit());
+            acr.modify(|_, w| w.dcrst().clear_bit());
+        }
+    }
+
+    /// Flushes the data cache, so the next data reads actually come from flash instead of the cache.
+    /// A cached doubleword is never re-read from the flash memory, so it can't report its ECC error.
+    ///
+    /// The cache can only be reset while it is disabled, so this disables it, resets it and then
+    /// enables it again (if it was enabled before).
+    pub fn flush_data_cache(&self) {
+        let acr = &self.flash.acr;
+        let enabled = acr.read().dcen().bit_is_set();
+        acr.modify(|_, w| w.dcen().clear_bit());
+        acr.modify(|_, w| w.dcrst().set_bit());
+        acr.modify(|_, w| w.dcrst().clear_bit());
+        acr.modify(|_, w| w.dcen().bit(enabled));
     }
 
-    /// Returns the page number for a given address, depending on the [Flash::page_size]
+    /// Returns the first and the last page (inclusive, see [Flash::address_to_page_number]) touched by
+    /// `[start, start + len)`. If they differ, the range crosses a page boundary and all pages in between
+    /// have to be erased before writing it, e.g. with [FlashUnlocked::erase_page_range].
+    /// An empty range is treated like a single byte at `start`.
+    pub fn pages_spanned(&self, start: u32, len: u32) -> (u32, u32) {
+        geometry::pages_spanned_for(start, len, self.page_size())
+    }
+
+    /// The pages holding this firmware, starting with the vector table on page 0. Erasing them would brick
+    /// the running tool, so [FlashUnlocked::erase_page] and [FlashUnlocked::erase_page_range] refuse to.
+    fn firmware_pages(&self) -> core::ops::Range<u32> {
+        // Proofs don't have the symbols of the linker script, the vector table is on the first page in any case
+        #[cfg(kani)]
+        let firmware_end = mem::FLASH_BASE + 1;
+        #[cfg(not(kani))]
+        let firmware_end = mem::firmware_end();
+
+        geometry::firmware_pages_for(firmware_end, self.page_size())
+    }
+
+    /// True if erasing or programming `page_number` stalls the running code, because it's in the same bank as
+    /// [Flash::firmware_pages]. In single-bank mode, this is true for every page, as there is no read-while-write.
+    /// See [FlashUnlocked::write_dwords_from_ram] for a write that isn't affected.
+    pub fn shares_bank_with_code(&self, page_number: u32) -> bool {
+        !self.is_dualbank()
+            || self.page_location(page_number).0
+                == self.page_location(self.firmware_pages().start).0
+    }
+
+    /// Returns the bank and the page number inside of that bank for a page number below [mem::page_count]
+    fn page_location(&self, page_number: u32) -> (u32, u32) {
+        let (bank, page_in_bank) =
+            geometry::page_location_for(page_number, self.size_kb, self.is_dualbank());
+
+        // The PNB field is only 8 bits wide, and there are only two banks
+        debug_assert!(page_in_bank < 256);
+        debug_assert!(bank <= 1);
+
+        (bank, page_in_bank)
+    }
+
+    /// True if `page_number` is inside of one of the two write protection areas (WRPxA and WRPxB option bytes)
+    /// of its bank, so erasing or writing it fails (WRPERR, reported as [Error::Illegal]).
+    /// An area is disabled when its start page is above its end page.
+    pub fn is_write_protected(&self, page_number: u32) -> bool {
+        let (bank, page_in_bank) = self.page_location(page_number);
+        let flash = &self.flash;
+        let areas = if bank == 0 {
+            [
+                (
+                    flash.wrp1ar.read().wrp1a_strt().bits(),
+                    flash.wrp1ar.read().wrp1a_end().bits(),
+                ),
+                (
+                    flash.wrp1br.read().wrp1b_strt().bits(),
+                    flash.wrp1br.read().wrp1b_end().bits(),
+                ),
+            ]
+        } else {
+            [
+                (
+                    flash.wrp2ar.read().wrp2a_strt().bits(),
+                    flash.wrp2ar.read().wrp2a_end().bits(),
+                ),
+                (
+                    flash.wrp2br.read().wrp2b_strt().bits(),
+                    flash.wrp2br.read().wrp2b_end().bits(),
+                ),
+            ]
+        };
+
+        areas
+            .iter()
+            .any(|&(start, end)| (start as u32..=end as u32).contains(&page_in_bank))
+    }
+
+    /// Checks OPTVERR in FLASH_SR, which is set when the option bytes loaded at reset (or with OBL_LAUNCH)
+    /// don't match their complement, i.e. when the load failed. The option bytes are then not in a
+    /// consistent state, so this should be checked before relying on them, e.g. at boot.
+    pub fn option_bytes_status(&self) -> Result<(), Error> {
+        if self.flash.sr.read().optverr().bit_is_set() {
+            Err(Error::OptionBytesInvalid)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether ECC is enabled for the main memory. On the STM32L4R5 it always is: there is no option bit or
+    /// register to turn it off, so this is constant. It exists so that a search that never sees an ECC error
+    /// can rule out disabled ECC as the reason.
+    #[allow(dead_code)]
+    pub fn ecc_enabled(&self) -> bool {
+        // There is no bit to turn it off: ECCIE (FLASH_ECCR bit 24) only enables the interrupt for corrected
+        // single-bit errors, a double-bit error always raises the NMI
+        true
+    }
+
+    /// Reads the ECC error currently latched in FLASH_ECCR, if any. This is the form for normal code, which has
+    /// a [Flash]; the fault handlers, which only have stolen peripherals, use [ecc_status_raw].
+    ///
+    /// The address is latched with the *first* error and not updated until the flags are cleared,
+    /// so without [Flash::clear_ecc_error] in between, this can describe an older error than the latest one.
+    pub fn ecc_status(&self) -> Option<EccError> {
+        ecc_status_raw(&self.flash, self.is_dualbank())
+    }
+
+    /// Clears all latched ECC errors (single- and double-bit, in both banks), so that the next error
+    /// latches its own address again.
+    ///
+    /// This only touches FLASH_ECCR, so it takes `&self` and can be used between reads while the flash is unlocked.
+    pub fn clear_ecc_error(&self) {
+        sequence::clear_ecc_error(&self.regs());
+    }
+
+    /// Returns the page number for a given (absolute) flash address, depending on the [Flash::page_size].
+    /// Addresses below [mem::FLASH_BASE] (e.g. the alias at address 0) give a page number that doesn't exist,
+    /// so erasing it fails with [Error::InvalidPage] instead of erasing a wrong page.
     pub fn address_to_page_number(&self, address: u32) -> u32 {
-        address / self.page_size()
+        geometry::address_to_page_for(address, self.page_size())
+    }
+
+    /// Reads a word from flash.
+    ///
+    /// Note that flash is protected by ECC on a doubleword basis: if the doubleword containing this
+    /// address has a double-bit ECC error, this read will trigger a non-maskable interrupt.
+    /// Use [Flash::read_dword_checked] if the location might be corrupted and you want to recover.
+    #[allow(dead_code)]
+    pub fn read_word(&self, addr: *const u32) -> u32 {
+        unsafe { core::ptr::read_volatile(addr) }
+    }
+
+    /// Reads a doubleword from flash. `addr` must be 8-byte aligned.
+    ///
+    /// The same ECC caveat as for [Flash::read_word] applies: a double-bit ECC error will fault.
+    pub fn read_dword(&self, addr: *const u64) -> u64 {
+        unsafe { core::ptr::read_volatile(addr) }
+    }
+
+    /// Reads a doubleword from flash, but returns [Error::Corrupted] instead of faulting if it is corrupted.
+    /// This relies on the NMI handler calling [recover_checked_read_fault].
+    pub fn read_dword_checked(&self, addr: *const u64) -> Result<u64, Error> {
+        match self.read_dword_classified(addr) {
+            (_, EccKind::Uncorrectable) => Err(Error::Corrupted),
+            (value, _) => Ok(value),
+        }
+    }
+
+    /// Reads the 128-bit line starting at `addr` (16-byte aligned) as two checked doublewords, and reports the
+    /// ECC result of each half separately. This relies on the NMI handler calling [recover_checked_read_fault].
+    ///
+    /// The low half is read first, then the high half. FLASH_ECCR is cleared before each read, so a flag can
+    /// only come from the read it is attributed to. Afterwards, only a corrected error of the high half is
+    /// still latched. In dual-bank mode, the halves are simply two neighboring doublewords with their own ECC.
+    #[allow(dead_code)]
+    pub fn read_line_checked(&self, addr: *const u64) -> LineResult {
+        let (low, low_error) = self.read_dword_classified(addr);
+        let (high, high_error) = self.read_dword_classified(addr.wrapping_add(1));
+        LineResult {
+            low,
+            high,
+            low_error,
+            high_error,
+        }
+    }
+
+    /// Reads a doubleword with the protocol of [Flash::read_dword_checked], and tells which kind of error
+    /// the read flagged. The value is returned even for an uncorrectable error.
+    fn read_dword_classified(&self, addr: *const u64) -> (u64, EccKind) {
+        // A stale error would keep its address latched, so the captured ECCR wouldn't describe this read
+        self.clear_ecc_error();
+
+        ECC_FAULT_CAPTURED.store(0, Ordering::SeqCst);
+        ECC_FAULT_EXPECTED.store(true, Ordering::SeqCst);
+
+        let value = self.read_dword(addr);
+
+        // Make sure that the NMI of a faulting read has been taken before we look at the result
+        dsb();
+        isb();
+
+        ECC_FAULT_EXPECTED.store(false, Ordering::SeqCst);
+
+        // The NMI handler only clears the double-bit flags, a corrected error is still latched
+        let kind = if ECC_FAULT_CAPTURED.load(Ordering::SeqCst) != 0 {
+            EccKind::Uncorrectable
+        } else if self.ecc_status().is_some() {
+            EccKind::Corrected
+        } else {
+            EccKind::None
+        };
+        (value, kind)
+    }
+}
+
+/// The option bytes after [FlashUnlocked::unlock_option_bytes]. Changes to the option registers only take
+/// effect after [OptionBytesUnlocked::program_and_load].
+#[cfg(feature = "destructive")]
+pub struct OptionBytesUnlocked<'a, 'b> {
+    flash: &'a mut FlashUnlocked<'b>,
+}
+
+#[cfg(feature = "destructive")]
+impl<'a, 'b> OptionBytesUnlocked<'a, 'b> {
+    /// The option register (FLASH_OPTR), e.g. for the bank configuration
+    pub fn optr(&self) -> &stm32l4r5::flash::OPTR {
+        &self.flash.flash.flash.optr
+    }
+
+    /// Writes the changed option registers to flash (OPTSTRT) and loads them (OBL_LAUNCH).
+    /// Loading the option bytes resets the chip, so this only returns if something went wrong:
+    /// [Error::OptionBytesInvalid] if loading them failed, or the error of the programming.
+    pub fn program_and_load(&mut self) -> Result<(), Error> {
+        self.flash.wait()?;
+
+        // OPTVERR stays set from an earlier failed load, clear it (by writing 1) so we only see our own
+        self.flash.flash.flash.sr.write(|w| w.optverr().set_bit());
+        trace_register!(b"SR  ", self.flash.flash.flash.sr.read().bits());
+
+        // Write the changed option bytes to flash
+        self.flash
+            .flash
+            .flash
+            .cr
+            .modify(|_, w| w.optstrt().set_bit());
+        trace_register!(b"CR  ", self.flash.flash.flash.cr.read().bits());
+        self.flash.wait()?;
+
+        // Load the option bytes, which resets the chip
+        self.flash
+            .flash
+            .flash
+            .cr
+            .modify(|_, w| w.obl_launch().set_bit());
+        trace_register!(b"CR  ", self.flash.flash.flash.cr.read().bits());
+
+        // Give the reset some time to happen, we should never get past this
+        for _ in 0..1000 {
+            cortex_m::asm::nop();
+        }
+
+        self.flash.option_bytes_status()?;
+        // Neither a reset nor an error, the load didn't happen in time
+        Err(Error::Busy)
+    }
+}
+
+#[cfg(feature = "destructive")]
+impl<'a, 'b> Drop for OptionBytesUnlocked<'a, 'b> {
+    fn drop(&mut self) {
+        self.flash
+            .flash
+            .flash
+            .cr
+            .modify(|_, w| w.optlock().set_bit());
+        trace_register!(b"CR  ", self.flash.flash.flash.cr.read().bits());
     }
 }
 
 impl<'a> FlashUnlocked<'a> {
-    /// Clears flash programming flags as described in the manual on Page 131, "Programming errors"
-    pub fn clear_programming_flags(&mut self) {
-        self.flash.flash.sr.modify(|_, w| {
-            w.progerr()
-                .clear_bit()
-                .sizerr()
-                .clear_bit()
-                .pgaerr()
-                .clear_bit()
-                .pgserr()
-                .clear_bit()
-                .wrperr()
-                .clear_bit()
-                .miserr()
-                .clear_bit()
-                .fasterr()
-                .clear_bit()
-        });
+    /// Clears flash programming flags as described in the manual on Page 131, "Programming errors".
+    /// Returns which of them were set before, e.g. because an earlier operation was interrupted.
+    /// A non-empty result is also remembered for [stale_programming_flags].
+    pub fn clear_programming_flags(&mut self) -> ProgrammingFlags {
+        let flags = sequence::clear_programming_flags(&self.regs());
+        if !flags.is_empty() {
+            STALE_PROGRAMMING_FLAGS.fetch_or(flags.0, Ordering::SeqCst);
+        }
+
+        flags
     }
 
     /// Erases the flash page with the given number. It is recommended to calculate the flash page
     /// using [Flash::address_to_page_number], as this value depends on the flash mode. This means, that for
     /// the same address, the page number might be different depending on the flash mode.
+    ///
+    /// Returns [Error::InvalidPage] for pages that don't exist and for the pages of this firmware.
     pub fn erase_page(&mut self, page_number: u32) -> Result<(), Error> {
+        self.erase_page_with(page_number, || {})
+    }
+
+    /// Like [FlashUnlocked::erase_page], but calls `idle` while waiting for the flash (see [FlashUnlocked::wait_with]).
+    /// An erase can take up to 25ms, so this allows e.g. feeding a running watchdog in the meantime.
+    pub fn erase_page_with(
+        &mut self,
+        page_number: u32,
+        mut idle: impl FnMut(),
+    ) -> Result<(), Error> {
         // According to "3.3.6 Flash main memory erase sequences"
 
         // 1. Check that no Flash memory operation is ongoing by checking the BSY bit in FLASH_SR
-        self.wait()?;
+        // Error flags of an earlier operation are only looked at in the next step
+        self.wait_for_idle(&mut idle)?;
 
         // 2. Check and clear all error programming flags due to a previous programming. If not, PGSERR is set
         self.clear_programming_flags();
 
-        // Step Nr. 3 differentiates between dual- and single-bank mode
-        if self.is_dualbank() {
-            if page_number >= 512 {
-                return Err(Error::InvalidPage);
-            }
+        // Step Nr. 3 differentiates between dual- and single-bank mode: the page is selected by its bank
+        // and the page number inside of that (see Flash::page_location)
+        self.check_erase_range(&(page_number..page_number + 1))?;
+
+        self.erased_pages = page_number..page_number + 1;
+
+        // During proofs, we want to skip hardware interaction, but still check the math above
+        #[cfg(kani)]
+        return Ok(());
 
-            // During proofs, we want to skip hardware interaction
-            #[cfg(kani)]
+        if cfg!(feature = "dry-run") {
+            self.simulate_operation(DRY_RUN_ERASE_CYCLES, &mut idle);
             return Ok(());
+        }
 
-            // Select either bank 0 or 1, and inside of that, the page number
-            // Note that the manual calls them Bank 1 and Bank 2, but we call them 0 and 1
-            let bank = page_number / 256;
-            let page_number = page_number % 256;
-
-            // We are in Dual-Bank mode, pages are 0x1000 bytes long
-            self.flash.flash.cr.modify(|_, w| unsafe {
-                // set the PER bit
-                w.per()
-                    .set_bit()
-                    // Select the bank (false => Bank 1, true => Bank 2)
-                    .bker()
-                    .bit(bank == 1)
-                    // and select the page to erase (PNB)
-                    .pnb()
-                    .bits(page_number as u8)
-            });
-        } else {
-            // Single-Bank mode, we have 256 pages with size 0x2000 bytes
-            if page_number >= 256 {
-                return Err(Error::InvalidPage);
+        // 3.-5. Set PER and select the page, set STRT, wait for BSY to be cleared
+        let location = self.page_location(page_number);
+        let result = sequence::erase_pages(&self.regs(), core::iter::once(location), &mut idle);
+
+        // Even a failed erase might have changed the page
+        self.invalidate_data_cache();
+
+        if result.is_err() {
+            self.erased_pages = 0..0;
+        }
+        result
+    }
+
+    /// Like [FlashUnlocked::erase_page], but checks afterwards that the whole page reads as erased
+    /// (see [Flash::verify_erased]) and erases it again if it doesn't, up to `max_retries` times.
+    /// A partially erased page would silently undermine an experiment, so this is for erases that aren't timed.
+    ///
+    /// Returns [Error::Illegal] if the page still isn't erased after the last retry.
+    #[allow(dead_code)]
+    pub fn erase_page_verified(&mut self, page_number: u32, max_retries: u32) -> Result<(), Error> {
+        let page_size = self.page_size();
+        let page_start = mem::FLASH_BASE + page_number * page_size;
+        let result = sequence::erase_until_blank(max_retries, || {
+            self.erase_page(page_number)?;
+            // The dcache was already invalidated by the erase, but bypass it anyway, so every read reports its ECC status.
+            // A dry run doesn't erase anything, so there is nothing to check
+            if cfg!(feature = "dry-run") {
+                return Ok(None);
             }
+            Ok(self.verify_erased(page_start, page_size, true))
+        });
+
+        if result.is_err() {
+            self.erased_pages = 0..0;
+        }
+        result
+    }
 
-            // During proofs, we want to skip hardware interaction
-            #[cfg(kani)]
+    /// Erases all pages in `pages`, e.g. the pages returned from [Flash::address_to_page_number] for the start
+    /// and end of a region. Unlike calling [FlashUnlocked::erase_page] for every page, PER is only set once
+    /// and stays set between the pages, only the page selection (BKER/PNB) and STRT are written for each one.
+    /// The erase sequence of the manual only requires PER to be set when STRT is written.
+    ///
+    /// If an erase fails, the remaining pages are not erased. PER is cleared in any case.
+    pub fn erase_page_range(&mut self, pages: core::ops::Range<u32>) -> Result<(), Error> {
+        // Same sequence as in erase_page, "3.3.6 Flash main memory erase sequences"
+        self.wait_for_idle(&mut Spin)?;
+        self.clear_programming_flags();
+
+        // Check the whole range first, we don't want to stop in the middle of it
+        self.check_erase_range(&pages)?;
+        self.erased_pages = pages.clone();
+
+        #[cfg(kani)]
+        return Ok(());
+
+        if cfg!(feature = "dry-run") {
+            self.simulate_operation(pages.len() as u32 * DRY_RUN_ERASE_CYCLES, &mut Spin);
             return Ok(());
+        }
 
-            self.flash.flash.cr.modify(|_, w| unsafe {
-                w
-                    // Set the PER bit
-                    .per()
-                    .set_bit()
-                    // Select the page to erase
-                    .pnb()
-                    .bits(page_number as u8)
-                    // The BKER bit [...] must be kept cleared
-                    .bker()
-                    .clear_bit()
-            });
-        }
-
-        // 4. Set the STRT bit in the FLASH_CR register
-        self.flash.flash.cr.modify(|_, w| w.start().set_bit());
-
-        // 5. Wait for the BSY bit to be cleared in the FLASH_SR register.
-        // If a programming error happened, wait will return an error
-        let result = self.wait();
+        let locations = pages.map(|page_number| self.page_location(page_number));
+        let result = sequence::erase_pages(&self.regs(), locations, &mut Spin);
 
-        // Disable page erase again - this shouldn't be strictly necessary
-        self.flash.flash.cr.modify(|_, w| w.per().clear_bit());
+        self.invalidate_data_cache();
 
+        if result.is_err() {
+            self.erased_pages = 0..0;
+        }
         result
     }
 
+    /// Like [FlashUnlocked::erase_page_range], but the erase runs from RAM (see [erase_from_ram]), so the core
+    /// isn't stalled while a page in the bank the code runs from is erased (see [Flash::shares_bank_with_code]).
+    /// Together with [FlashUnlocked::write_dwords_from_ram], the whole corrupting operation runs from RAM.
+    ///
+    /// The pages of the firmware itself are still refused: the reset that interrupts the erase boots from them.
+    pub fn erase_page_range_from_ram(&mut self, pages: core::ops::Range<u32>) -> Result<(), Error> {
+        // Same checks and preparation as in erase_page_range
+        self.wait_for_idle(&mut Spin)?;
+        self.clear_programming_flags();
+        self.check_erase_range(&pages)?;
+        self.erased_pages = pages.clone();
+
+        if cfg!(feature = "dry-run") {
+            self.simulate_operation(pages.len() as u32 * DRY_RUN_ERASE_CYCLES, &mut Spin);
+            return Ok(());
+        }
+        if pages.is_empty() {
+            return Ok(());
+        }
+
+        let (bank, page_in_bank) = self.page_location(pages.start);
+        let status = unsafe {
+            erase_from_ram(
+                &self.flash.flash.cr as *const _ as u32,
+                &self.flash.flash.sr as *const _ as u32,
+                bank,
+                page_in_bank,
+                pages.len() as u32,
+                mem::pages_per_bank(self),
+                Spin.max_polls(),
+            )
+        };
+        trace_register!(b"CR  ", self.flash.flash.cr.read().bits());
+
+        self.invalidate_data_cache();
+
+        let result = Error::from_status_bits(status);
+        if result.is_err() {
+            self.erased_pages = 0..0;
+        }
+        result
+    }
+
+    /// Checks that all of `pages` exist and none of them holds the firmware (see [Flash::firmware_pages])
+    fn check_erase_range(&self, pages: &core::ops::Range<u32>) -> Result<(), Error> {
+        geometry::check_erase_range_for(pages, self.page_count(), &self.firmware_pages())
+    }
+
+    /// Flushes the data cache (see [Flash::flush_data_cache]) after an erase or write changed the flash.
+    ///
+    /// The cache doesn't notice that the flash content changed below it, so without this, a read right after
+    /// an erase or write could still return the old data from the cache. This was the cause of corruptions
+    /// that were "not visible on read": the cached, still intact doubleword was returned and no ECC check happened.
+    /// Proofs don't model the cache, so it's skipped there.
+    fn invalidate_data_cache(&self) {
+        #[cfg(not(kani))]
+        self.flush_data_cache();
+    }
+
+    /// Programs `value` to the erased doubleword at `address` and verifies it (see
+    /// [FlashUnlocked::program_with_verify]), so its data and ECC are consistent. Returns the ECC the stored
+    /// doubleword has according to [compute_ecc], as a baseline for a following controlled corruption.
+    #[allow(dead_code)]
+    pub fn program_ecc_baseline(&mut self, address: *mut usize, value: u64) -> Result<u8, Error> {
+        self.program_with_verify(address, &[value])
+            .map_err(|failure| failure.error)?;
+        Ok(compute_ecc(value))
+    }
+
+    /// Like [FlashUnlocked::write_dwords], but reads back every doubleword right after programming it.
+    /// This also catches failures the status register doesn't report, e.g. a bit that didn't take.
+    ///
+    /// The read-back is a checked read, so an already corrupted location returns [Error::Corrupted] instead
+    /// of faulting. The returned [VerifyFailure] tells which doubleword of `array` failed.
+    pub fn program_with_verify(
+        &mut self,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), VerifyFailure> {
+        for (index, dword) in array.iter().enumerate() {
+            let dword_address = unsafe { address.add(2 * index) };
+            let fail = |error| VerifyFailure { index, error };
+
+            self.write_dwords(dword_address, core::slice::from_ref(dword))
+                .map_err(fail)?;
+
+            // write_dwords already flushed the data cache, so this reads the flash itself
+            let read = self
+                .read_dword_checked(dword_address as *const u64)
+                .map_err(fail)?;
+            if read != *dword {
+                return Err(fail(Error::VerifyMismatch));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that erasing, writing and reading work on this board, before relying on them for the corruption:
+    /// erases `scratch_page`, checks that it is blank, writes [SELF_TEST_PATTERN] to its start with
+    /// [FlashUnlocked::program_with_verify] and erases it again, so it is left blank.
+    ///
+    /// The page must not hold anything that is still needed. The firmware pages are refused by the erase.
+    pub fn self_test(&mut self, scratch_page: u32) -> Result<(), Error> {
+        let page_size = self.page_size();
+        let page_start = mem::FLASH_BASE + scratch_page * page_size;
+
+        self.erase_page(scratch_page)?;
+        // A dry run doesn't touch the flash, so there is nothing to read back
+        if cfg!(feature = "dry-run") {
+            return Ok(());
+        }
+        if self.verify_erased(page_start, page_size, true).is_some() {
+            return Err(Error::NotErased);
+        }
+
+        self.program_with_verify(page_start as *mut usize, &SELF_TEST_PATTERN)
+            .map_err(|failure| failure.error)?;
+
+        self.erase_page(scratch_page)
+    }
+
+    /// Returns [Error::Illegal] unless `dwords` doublewords starting at `address` are inside of the main flash,
+    /// and [Error::NotErased] unless they are inside of the pages erased last (see
+    /// [geometry::check_write_range_for]). This is always checked, not just in debug builds.
+    fn check_write_range(&self, address: *mut usize, dwords: usize) -> Result<(), Error> {
+        geometry::check_write_range_for(
+            address as u32,
+            (dwords * Flash::write_granularity()) as u32,
+            mem::flash_size(self),
+            self.page_size(),
+            &self.erased_pages,
+        )
+    }
+
     /// Writes the given array to a flash address.
-    /// This must only be called when the following is true:
-    /// - The flash is unlocked
-    /// - The target page(s) have been erased before
-    pub fn write_dwords(&mut self, mut address: *mut usize, array: &[u64]) -> Result<(), Error> {
+    /// The target page(s) must have been erased before with this [FlashUnlocked], in the last erase
+    /// ([FlashUnlocked::erase_page] or [FlashUnlocked::erase_page_range]). Otherwise, [Error::NotErased] is returned.
+    ///
+    /// If programming a doubleword fails, the remaining ones are not written. PG is cleared in any case.
+    pub fn write_dwords(&mut self, address: *mut usize, array: &[u64]) -> Result<(), Error> {
+        self.write_dwords_with(address, array, Spin)
+    }
+
+    /// Like [FlashUnlocked::write_dwords], but waits for the flash with the given [WaitStrategy] instead of
+    /// spinning on BSY, e.g. `|| watchdog.reload()` to feed a running watchdog.
+    pub fn write_dwords_with(
+        &mut self,
+        address: *mut usize,
+        array: &[u64],
+        mut wait: impl WaitStrategy,
+    ) -> Result<(), Error> {
         // See reference manual, "3.3.7 Flash main memory programming sequences"
         // We do "Standard programming"
 
-        debug_assert_ne!(address, 0 as *mut usize, "attempt to write to 0");
+        self.check_write_range(address, array.len())?;
 
         // 1. Check that no Flash main memory operation is ongoing
-        self.wait()?;
+        self.wait_for_idle(&mut wait)?;
 
         // 2. Check and clear all error programming flags due to a previous programming
         self.clear_programming_flags();
 
-        // 3. Set the PG bit in the FLASH_CR register
-        self.flash.flash.cr.modify(|_, w| w.pg().set_bit());
+        if cfg!(feature = "dry-run") {
+            self.simulate_operation(array.len() as u32 * DRY_RUN_DWORD_CYCLES, &mut wait);
+            return Ok(());
+        }
 
-        // 4. Perform the data write operation at the desired memory address, inside main memory block or OTP area
-        for dword in array {
-            unsafe {
-                core::ptr::write_volatile(address, *dword as usize);
-                dmb();
-                core::ptr::write_volatile(address.add(1), (*dword >> 32) as usize);
-                address = address.add(2);
-            }
+        // 3.-7. Set PG, program and wait for every doubleword, clear PG
+        let result = sequence::program_dwords(&self.regs(), address as u32, array, &mut wait);
 
-            // 5. Wait until the BSY bit is cleared in the FLASH_SR register
-            self.wait()?;
-
-            // Note: The manual mentions the following:
-            // > 6. Check that EOP flag is set in the FLASH_SR register
-            // > (meaning that the programming operation has succeed), and clear it by software.
-            // However, when looking at "3.6 Flash Interrupts", we have this:
-            // > EOP is set only if EOPIE is set.
-            // Since we disable interrupts, we don't need to care about this bit - just reset it
-            if self.flash.flash.sr.read().eop().bit_is_set() {
-                self.flash.flash.sr.modify(|_, w| w.eop().clear_bit());
-            }
+        // Some doublewords might have been written before an error
+        self.invalidate_data_cache();
+
+        result
+    }
+
+    /// Like [FlashUnlocked::write_dwords], but the programming runs from RAM (see [program_from_ram]), so the
+    /// core isn't stalled while a doubleword is programmed in the bank the code runs from
+    /// (see [Flash::shares_bank_with_code]). This keeps the timing of the write the same for all target pages.
+    ///
+    /// The cycles aren't recorded for the `measure-ops` feature, as the routine can't call anything while it runs.
+    pub fn write_dwords_from_ram(
+        &mut self,
+        address: *mut usize,
+        array: &[u64],
+    ) -> Result<(), Error> {
+        // Same checks and preparation as in write_dwords
+        self.check_write_range(address, array.len())?;
+        self.wait_for_idle(&mut Spin)?;
+        self.clear_programming_flags();
+
+        if cfg!(feature = "dry-run") {
+            self.simulate_operation(array.len() as u32 * DRY_RUN_DWORD_CYCLES, &mut Spin);
+            return Ok(());
         }
 
-        // 7. Clear the PG bit in the FLASH_SR register if there no more programming request anymore.
-        self.flash.flash.cr.modify(|_, w| w.pg().clear_bit());
+        let status = unsafe {
+            program_from_ram(
+                &self.flash.flash.cr as *const _ as u32,
+                &self.flash.flash.sr as *const _ as u32,
+                address as u32,
+                array.as_ptr() as u32,
+                array.len() as u32,
+                Spin.max_polls(),
+            )
+        };
+        trace_register!(b"CR  ", self.flash.flash.cr.read().bits());
+
+        // Some doublewords might have been written before an error
+        self.invalidate_data_cache();
+
+        Error::from_status_bits(status)
+    }
+
+    /// Like [FlashUnlocked::write_dwords], but copies `dwords` doublewords from `source` instead of a slice,
+    /// e.g. to back up a flash page into another one before corrupting it, without a buffer in RAM.
+    ///
+    /// Both pointers must be aligned to 8 bytes: the destination because only whole doublewords can be
+    /// programmed, the source because it is read as doublewords. The source must not overlap the destination,
+    /// as the destination pages have been erased. Every source doubleword is read with
+    /// [Flash::read_dword_checked], so a corrupted source returns [Error::Corrupted] instead of faulting,
+    /// with everything before it already copied.
+    #[allow(dead_code)]
+    pub fn write_dwords_from(
+        &mut self,
+        destination: *mut usize,
+        source: *const u64,
+        dwords: usize,
+    ) -> Result<(), Error> {
+        debug_assert_eq!(
+            destination as usize % Flash::write_granularity(),
+            0,
+            "destination is not aligned"
+        );
+        debug_assert_eq!(source as usize % 8, 0, "source is not aligned");
+        // Check the whole destination first, we don't want to stop in the middle of it
+        self.check_write_range(destination, dwords)?;
+
+        for index in 0..dwords {
+            let dword = self.read_dword_checked(unsafe { source.add(index) })?;
+            self.write_dwords(
+                unsafe { destination.add(2 * index) },
+                core::slice::from_ref(&dword),
+            )?;
+        }
 
         Ok(())
     }
 
+    /// Programs a whole row of [mem::FAST_PROGRAMMING_ROW_DWORDS] doublewords using "Fast programming".
+    /// `address` must be aligned to the row size and the row must have been erased before.
+    ///
+    /// Standard programming (see [FlashUnlocked::write_dwords]) can't be pipelined: the manual requires waiting
+    /// for BSY after every doubleword before the next one may be written. Fast programming instead takes the
+    /// whole row in one go and only verifies it once, which is much faster for bulk regions.
+    /// The row must be written without any pause, otherwise the flash interface reports MISERR, so interrupts
+    /// are disabled during the row. Note that code executing from the same bank can still stall the writes.
+    pub fn write_fast_row(
+        &mut self,
+        mut address: *mut usize,
+        row: &[u64; mem::FAST_PROGRAMMING_ROW_DWORDS],
+    ) -> Result<(), Error> {
+        // See reference manual, "3.3.7 Flash main memory programming sequences", "Fast programming"
+        debug_assert_eq!(
+            address as usize % mem::FAST_PROGRAMMING_ROW_SIZE,
+            0,
+            "row is not aligned"
+        );
+        self.check_write_range(address, row.len())?;
+
+        // 1./2. Check that no Flash main memory operation is ongoing
+        self.wait_for_idle(&mut Spin)?;
+
+        // 3. Check and clear all error programming flags due to a previous programming
+        self.clear_programming_flags();
+
+        if cfg!(feature = "dry-run") {
+            self.simulate_operation(DRY_RUN_ROW_CYCLES, &mut Spin);
+            return Ok(());
+        }
+
+        // 4. Set the FSTPG bit in FLASH_CR register
+        self.flash.flash.cr.modify(|_, w| w.fstpg().set_bit());
+        trace_register!(b"CR  ", self.flash.flash.cr.read().bits());
+
+        // 5. Write the 32 double words to program a row, one after another without interruption
+        cortex_m::interrupt::free(|_| {
+            for dword in row {
+                unsafe {
+                    core::ptr::write_volatile(address, *dword as usize);
+                    core::ptr::write_volatile(address.add(1), (*dword >> 32) as usize);
+                    address = address.add(2);
+                }
+            }
+            dsb();
+        });
+
+        // 6. Wait until the BSY bit is cleared in the FLASH_SR register
+        let result = self.wait();
+
+        // 7./8. Clear the FSTPG bit, even if programming failed
+        self.flash.flash.cr.modify(|_, w| w.fstpg().clear_bit());
+        trace_register!(b"CR  ", self.flash.flash.cr.read().bits());
+
+        self.invalidate_data_cache();
+
+        result
+    }
+
+    /// Toggles the BFB2 option bit, which swaps the banks: the other bank gets mapped to the boot address.
+    ///
+    /// This changes the option bytes! Loading them (OBL_LAUNCH, see "3.4.2 Option bytes programming")
+    /// resets the chip, so on success this never returns. After the reset, the chip boots from the other bank,
+    /// so make sure there is something to boot there.
+    #[cfg(feature = "destructive")]
+    #[allow(dead_code)]
+    pub fn toggle_boot_bank(&mut self) -> Result<(), Error> {
+        let mut option_bytes = self.unlock_option_bytes()?;
+        option_bytes
+            .optr()
+            .modify(|r, w| w.bfb2().bit(!r.bfb2().bit()));
+        option_bytes.program_and_load()
+    }
+
+    /// Unlocks the option bytes (FLASH_OPTR and the other option registers) with the OPTKEYR sequence,
+    /// see "3.4.2 Option bytes programming". Like [Flash::unlock], OPTLOCK is set again once the returned
+    /// object is dropped, which is also the case if programming fails.
+    #[cfg(feature = "destructive")]
+    #[allow(dead_code)]
+    pub fn unlock_option_bytes(&mut self) -> Result<OptionBytesUnlocked<'_, 'a>, Error> {
+        // The option bytes must not be changed while an operation is ongoing
+        self.wait()?;
+
+        // The flash itself is already unlocked, now unlock the option bytes
+        self.flash
+            .flash
+            .optkeyr
+            .write(|w| unsafe { w.optkeyr().bits(Flash::OPT_KEY1) });
+        trace_register!(b"OKEY", Flash::OPT_KEY1);
+        dmb();
+        self.flash
+            .flash
+            .optkeyr
+            .write(|w| unsafe { w.optkeyr().bits(Flash::OPT_KEY2) });
+        trace_register!(b"OKEY", Flash::OPT_KEY2);
+        dmb();
+
+        // Like LOCK, OPTLOCK stays set until the next reset if the sequence was wrong
+        if self.flash.flash.cr.read().optlock().bit_is_set() {
+            return Err(Error::UnlockFailed);
+        }
+
+        Ok(OptionBytesUnlocked { flash: self })
+    }
+
     /// Wait until the busy bit of the flash status register is cleared.
-    /// This must be done e.g. during writes.
+    /// This must be done e.g. during writes. If an error flag comes up before, this returns early with that error.
     pub fn wait(&mut self) -> Result<(), Error> {
+        self.wait_using(&mut Spin)
+    }
+
+    /// Like [FlashUnlocked::wait], but calls `idle` on every iteration while the flash is busy.
+    /// `idle` can also be any other [WaitStrategy], e.g. one with a different timeout.
+    ///
+    /// This is opt-in for long operations while the IWDG is running, e.g. with
+    /// `|| watchdog.reload()` the watchdog can't reset the chip during an erase.
+    /// The corruptor itself doesn't do this, a reset during the erase is exactly what it's looking for.
+    #[allow(dead_code)]
+    pub fn wait_with(&mut self, mut idle: impl WaitStrategy) -> Result<(), Error> {
+        self.wait_using(&mut idle)
+    }
+
+    /// [FlashUnlocked::wait_with] for a borrowed [WaitStrategy], so it can be used for more than one wait
+    fn wait_using(&mut self, wait: &mut impl WaitStrategy) -> Result<(), Error> {
         //! XXX: The datasheet for the STM32L4R5xx says, that max(t_prog) = 90.8µs and that
         //! max(t_prog_row normal programming) = 5.5ms, max(t_ERASE) = 24.5ms  and max(t_ME) = 25ms
         //!
@@ -290,14 +1476,51 @@ impl<'a> FlashUnlocked<'a> {
         //! longer, i.e. at 4 clock cycles per loop iteration we would actually wait a maximum of
         //! 100ms
         //!
-        //! A timeout is indicated by returning Error::Busy
+        //! A timeout is indicated by returning Error::Busy. The number of polls is WAIT_POLLS, unless the
+        //! WaitStrategy gives another one
+        //!
+        //! An error flag can be set while BSY is still high. The operation has failed then, so that error
+        //! is returned right away instead of waiting for BSY
 
-        for _ in 0..100_000 {
-            if self.flash.flash.sr.read().bsy().bit_is_clear() {
-                break;
-            }
+        sequence::wait(&self.regs(), wait)
+    }
+
+    /// Takes as long as a flash operation of `cycles`, instead of actually doing it (`dry-run` feature).
+    /// `wait` is idled in the meantime, like [FlashUnlocked::wait_with] would.
+    fn simulate_operation(&mut self, cycles: u32, wait: &mut impl WaitStrategy) {
+        let start = cortex_m::peripheral::DWT::cycle_count();
+        while cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start) < cycles {
+            wait.idle();
         }
+    }
 
-        self.status()
+    /// Like [FlashUnlocked::wait_with], but only waits for BSY to be cleared and ignores the error flags.
+    /// Returns [Error::Busy] on a timeout.
+    fn wait_for_idle(&mut self, wait: &mut impl WaitStrategy) -> Result<(), Error> {
+        sequence::wait_for_idle(&self.regs(), wait)
+    }
+}
+
+#[cfg(kani)]
+mod proofs {
+    use super::*;
+
+    /// The pages cover the whole flash, without a partial page at the end, for every size that is a whole
+    /// number of the larger (single-bank) pages
+    #[kani::proof]
+    fn page_count_covers_flash() {
+        let size_kb: u16 = kani::any();
+        let dualbank: bool = kani::any();
+        kani::assume(size_kb as u32 * 1024 % geometry::SINGLE_BANK_PAGE_SIZE == 0);
+
+        let page_size = if dualbank {
+            geometry::DUAL_BANK_PAGE_SIZE
+        } else {
+            geometry::SINGLE_BANK_PAGE_SIZE
+        };
+        assert_eq!(
+            page_count_for(size_kb, dualbank) * page_size,
+            size_kb as u32 * 1024
+        );
     }
 }
@@ -0,0 +1,385 @@
+//! The parts of the flash geometry of the STM32L4R5 that only depend on the size of the device and the bank mode,
+//! see "3.3.1 Flash memory organization" in the reference manual.
+//!
+//! The firmware reads both from the hardware and passes them in (see `mem` in the firmware), so the arithmetic
+//! can be tested on the host for the 1MB and the 2MB devices alike.
+
+use core::ops::Range;
+
+use crate::registers::Error;
+
+/// Start of the main flash memory. At boot, it is also aliased to address 0
+pub const FLASH_BASE: u32 = 0x0800_0000;
+
+/// Page size in dual-bank mode
+pub const DUAL_BANK_PAGE_SIZE: u32 = 0x1000;
+/// Page size in single-bank mode
+pub const SINGLE_BANK_PAGE_SIZE: u32 = 0x2000;
+
+/// A range of absolute flash addresses, e.g. the one targeted by the corruption.
+/// The success check and the reads of the target use the same type, so they can't disagree on the bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetRange {
+    /// First address of the range
+    pub start: u32,
+    /// Length of the range in bytes
+    pub len: u32,
+}
+
+impl TargetRange {
+    /// True if `address` is in `[start, start + len)`
+    pub const fn contains(&self, address: u32) -> bool {
+        address >= self.start && address - self.start < self.len
+    }
+}
+
+/// Page size in the given bank mode, either [DUAL_BANK_PAGE_SIZE] or [SINGLE_BANK_PAGE_SIZE]
+pub const fn page_size_for(dualbank: bool) -> u32 {
+    if dualbank {
+        DUAL_BANK_PAGE_SIZE
+    } else {
+        SINGLE_BANK_PAGE_SIZE
+    }
+}
+
+/// The pages of a flash of `size_kb` in the given bank mode, over all banks
+pub fn page_count_for(size_kb: u16, dualbank: bool) -> u32 {
+    size_kb as u32 * 1024 / page_size_for(dualbank)
+}
+
+/// Size of each bank of a flash of `size_kb` in bytes. In dual-bank mode, the flash is split in two halves, so the
+/// second bank starts this far after [FLASH_BASE]. In single-bank mode, there is only one bank.
+pub fn bank_size_for(size_kb: u16, dualbank: bool) -> u32 {
+    let size = size_kb as u32 * 1024;
+    if dualbank {
+        size / 2
+    } else {
+        size
+    }
+}
+
+/// The page number of an absolute flash address for the given page size. Addresses below [FLASH_BASE] (e.g. the
+/// alias at address 0) wrap around to a page number that doesn't exist, so they are refused like any other
+/// page past the end instead of being mapped to a wrong page.
+pub fn address_to_page_for(address: u32, page_size: u32) -> u32 {
+    address.wrapping_sub(FLASH_BASE) / page_size
+}
+
+/// Returns the first and the last page (inclusive, see [address_to_page_for]) touched by `[start, start + len)`.
+/// If they differ, the range crosses a page boundary. An empty range is treated like a single byte at `start`.
+pub fn pages_spanned_for(start: u32, len: u32, page_size: u32) -> (u32, u32) {
+    let last_byte = start + len.max(1) - 1;
+    (
+        address_to_page_for(start, page_size),
+        address_to_page_for(last_byte, page_size),
+    )
+}
+
+/// Returns the bank and the page number inside of that bank (the PNB field of FLASH_CR) for a page number below
+/// [page_count_for]. Page numbers count on over both banks in dual-bank mode, in single-bank mode there is only
+/// bank 0.
+pub fn page_location_for(page_number: u32, size_kb: u16, dualbank: bool) -> (u32, u32) {
+    if dualbank {
+        let pages_per_bank = page_count_for(size_kb, dualbank) / 2;
+        (page_number / pages_per_bank, page_number % pages_per_bank)
+    } else {
+        (0, page_number)
+    }
+}
+
+/// Checks a write of `len` bytes at the absolute address `start` against a main flash of `flash_size` bytes and the
+/// pages erased last (see `FlashUnlocked::write_dwords` in the firmware).
+///
+/// Returns [Error::Illegal] unless the whole write is inside of the main flash: a computed address that is too low
+/// (e.g. 0 or the alias of the flash at address 0) would otherwise fault in a confusing way. Returns
+/// [Error::NotErased] if it isn't inside of `erased_pages`, programming it would fail with PROGERR
+/// (or not do what we want) otherwise.
+pub fn check_write_range_for(
+    start: u32,
+    len: u32,
+    flash_size: u32,
+    page_size: u32,
+    erased_pages: &Range<u32>,
+) -> Result<(), Error> {
+    if start < FLASH_BASE || start.saturating_add(len) > FLASH_BASE + flash_size {
+        return Err(Error::Illegal);
+    }
+
+    let (first_page, last_page) = pages_spanned_for(start, len, page_size);
+    if !erased_pages.contains(&first_page) || !erased_pages.contains(&last_page) {
+        return Err(Error::NotErased);
+    }
+    Ok(())
+}
+
+/// The pages holding a firmware that starts with the vector table on page 0 and ends before the absolute address
+/// `firmware_end` (see `mem::firmware_end` in the firmware)
+pub fn firmware_pages_for(firmware_end: u32, page_size: u32) -> Range<u32> {
+    0..address_to_page_for(firmware_end - 1, page_size) + 1
+}
+
+/// Returns [Error::InvalidPage] unless all of `pages` are below `page_count` and none of them is one of
+/// `firmware_pages`, erasing those would brick the running tool
+pub fn check_erase_range_for(
+    pages: &Range<u32>,
+    page_count: u32,
+    firmware_pages: &Range<u32>,
+) -> Result<(), Error> {
+    if pages.end > page_count
+        || (pages.start < firmware_pages.end && firmware_pages.start < pages.end)
+    {
+        return Err(Error::InvalidPage);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_count_covers_both_device_sizes() {
+        assert_eq!(page_count_for(2048, true), 512);
+        assert_eq!(page_count_for(2048, false), 256);
+        assert_eq!(page_count_for(1024, true), 256);
+        assert_eq!(page_count_for(1024, false), 128);
+    }
+
+    #[test]
+    fn banks_split_the_flash_in_half() {
+        assert_eq!(bank_size_for(2048, true), 0x10_0000);
+        assert_eq!(bank_size_for(1024, true), 0x8_0000);
+        assert_eq!(bank_size_for(2048, false), 0x20_0000);
+        assert_eq!(bank_size_for(1024, false), 0x10_0000);
+    }
+
+    #[test]
+    fn addresses_map_to_their_page() {
+        assert_eq!(address_to_page_for(FLASH_BASE, DUAL_BANK_PAGE_SIZE), 0);
+        assert_eq!(
+            address_to_page_for(FLASH_BASE + 0x2300, DUAL_BANK_PAGE_SIZE),
+            2
+        );
+        assert_eq!(
+            address_to_page_for(FLASH_BASE + 0x2300, SINGLE_BANK_PAGE_SIZE),
+            1
+        );
+        // The alias at address 0 isn't the first page
+        assert!(address_to_page_for(0, DUAL_BANK_PAGE_SIZE) >= page_count_for(2048, true));
+    }
+
+    #[test]
+    fn range_at_a_page_start_stays_in_the_page() {
+        let start = FLASH_BASE + 2 * DUAL_BANK_PAGE_SIZE;
+        assert_eq!(pages_spanned_for(start, 8, DUAL_BANK_PAGE_SIZE), (2, 2));
+        assert_eq!(
+            pages_spanned_for(start, DUAL_BANK_PAGE_SIZE, DUAL_BANK_PAGE_SIZE),
+            (2, 2)
+        );
+        assert_eq!(
+            pages_spanned_for(start, DUAL_BANK_PAGE_SIZE + 1, DUAL_BANK_PAGE_SIZE),
+            (2, 3)
+        );
+    }
+
+    #[test]
+    fn range_ending_on_the_last_byte_of_a_page() {
+        let last_byte = FLASH_BASE + 3 * DUAL_BANK_PAGE_SIZE - 1;
+        assert_eq!(pages_spanned_for(last_byte, 1, DUAL_BANK_PAGE_SIZE), (2, 2));
+        assert_eq!(pages_spanned_for(last_byte, 2, DUAL_BANK_PAGE_SIZE), (2, 3));
+        // The same range is inside of page 1 with the larger single-bank pages
+        assert_eq!(
+            pages_spanned_for(last_byte, 2, SINGLE_BANK_PAGE_SIZE),
+            (1, 1)
+        );
+    }
+
+    #[test]
+    fn range_crossing_into_the_second_bank() {
+        for size_kb in [2048, 1024] {
+            let bank_size = bank_size_for(size_kb, true);
+            let pages_per_bank = page_count_for(size_kb, true) / 2;
+            let (first, last) =
+                pages_spanned_for(FLASH_BASE + bank_size - 8, 16, DUAL_BANK_PAGE_SIZE);
+
+            assert_eq!((first, last), (pages_per_bank - 1, pages_per_bank));
+            assert_eq!(
+                page_location_for(first, size_kb, true),
+                (0, pages_per_bank - 1)
+            );
+            assert_eq!(page_location_for(last, size_kb, true), (1, 0));
+        }
+    }
+
+    #[test]
+    fn empty_range_is_one_byte() {
+        let start = FLASH_BASE + 3 * DUAL_BANK_PAGE_SIZE - 1;
+        assert_eq!(
+            pages_spanned_for(start, 0, DUAL_BANK_PAGE_SIZE),
+            pages_spanned_for(start, 1, DUAL_BANK_PAGE_SIZE)
+        );
+        assert_eq!(
+            pages_spanned_for(FLASH_BASE, 0, DUAL_BANK_PAGE_SIZE),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn single_bank_pages_are_all_in_bank_0() {
+        assert_eq!(page_location_for(0, 2048, false), (0, 0));
+        assert_eq!(page_location_for(255, 2048, false), (0, 255));
+        assert_eq!(page_location_for(127, 1024, false), (0, 127));
+        assert_eq!(page_location_for(511, 2048, true), (1, 255));
+    }
+
+    /// Last erase of page 2 and 3 on a 2MB device in dual-bank mode
+    const ERASED: Range<u32> = 2..4;
+    const FLASH_SIZE: u32 = 2048 * 1024;
+
+    fn check_write(start: u32, len: u32) -> Result<(), Error> {
+        check_write_range_for(start, len, FLASH_SIZE, DUAL_BANK_PAGE_SIZE, &ERASED)
+    }
+
+    #[test]
+    fn write_below_the_flash_is_illegal() {
+        // The alias at address 0 maps to the same flash, but isn't where the checks expect it
+        assert_eq!(check_write(0x2000, 8), Err(Error::Illegal));
+        assert_eq!(check_write(FLASH_BASE - 8, 8), Err(Error::Illegal));
+        assert_eq!(check_write(FLASH_BASE - 8, 16), Err(Error::Illegal));
+    }
+
+    #[test]
+    fn write_past_the_end_of_the_flash_is_illegal() {
+        let end = FLASH_BASE + FLASH_SIZE;
+        let all = 0..page_count_for(2048, true);
+        assert_eq!(
+            check_write_range_for(end - 8, 8, FLASH_SIZE, DUAL_BANK_PAGE_SIZE, &all),
+            Ok(())
+        );
+        assert_eq!(
+            check_write_range_for(end - 8, 16, FLASH_SIZE, DUAL_BANK_PAGE_SIZE, &all),
+            Err(Error::Illegal)
+        );
+        assert_eq!(check_write(end, 8), Err(Error::Illegal));
+        // The end of the address space doesn't wrap around into the flash
+        assert_eq!(check_write(u32::MAX - 3, 8), Err(Error::Illegal));
+        // The end of a 2MB flash is past the end of a 1MB one
+        assert_eq!(
+            check_write_range_for(
+                FLASH_BASE + 1024 * 1024,
+                8,
+                1024 * 1024,
+                DUAL_BANK_PAGE_SIZE,
+                &all
+            ),
+            Err(Error::Illegal)
+        );
+    }
+
+    #[test]
+    fn write_must_be_inside_of_the_erased_pages() {
+        let page_2 = FLASH_BASE + 2 * DUAL_BANK_PAGE_SIZE;
+        assert_eq!(check_write(page_2, 8), Ok(()));
+        assert_eq!(check_write(page_2, 2 * DUAL_BANK_PAGE_SIZE), Ok(()));
+        // One byte into page 4, or one before page 2
+        assert_eq!(
+            check_write(page_2, 2 * DUAL_BANK_PAGE_SIZE + 1),
+            Err(Error::NotErased)
+        );
+        assert_eq!(check_write(page_2 - 8, 16), Err(Error::NotErased));
+        // Nothing erased yet
+        assert_eq!(
+            check_write_range_for(page_2, 8, FLASH_SIZE, DUAL_BANK_PAGE_SIZE, &(0..0)),
+            Err(Error::NotErased)
+        );
+    }
+
+    /// A firmware of about 12KB, as this one is in a debug build
+    const FIRMWARE_END: u32 = FLASH_BASE + 0x3100;
+
+    #[test]
+    fn firmware_pages_start_at_page_0() {
+        assert_eq!(firmware_pages_for(FIRMWARE_END, DUAL_BANK_PAGE_SIZE), 0..4);
+        assert_eq!(
+            firmware_pages_for(FIRMWARE_END, SINGLE_BANK_PAGE_SIZE),
+            0..2
+        );
+        // Ending right at a page boundary doesn't take the next page
+        assert_eq!(
+            firmware_pages_for(FLASH_BASE + 2 * DUAL_BANK_PAGE_SIZE, DUAL_BANK_PAGE_SIZE),
+            0..2
+        );
+        // Even the smallest firmware has its vector table on page 0
+        assert_eq!(
+            firmware_pages_for(FLASH_BASE + 1, DUAL_BANK_PAGE_SIZE),
+            0..1
+        );
+    }
+
+    #[test]
+    fn erase_refuses_page_0_and_the_firmware_pages() {
+        let firmware = firmware_pages_for(FIRMWARE_END, DUAL_BANK_PAGE_SIZE);
+        let check = |pages: Range<u32>| check_erase_range_for(&pages, 512, &firmware);
+
+        assert_eq!(check(0..1), Err(Error::InvalidPage));
+        assert_eq!(check(3..4), Err(Error::InvalidPage));
+        // A range that only starts or ends in the firmware
+        assert_eq!(check(3..6), Err(Error::InvalidPage));
+        assert_eq!(check(0..512), Err(Error::InvalidPage));
+
+        assert_eq!(check(4..5), Ok(()));
+        assert_eq!(check(4..512), Ok(()));
+        assert_eq!(check(4..4), Ok(()));
+    }
+
+    #[test]
+    fn erase_refuses_pages_past_the_end() {
+        let firmware = firmware_pages_for(FIRMWARE_END, DUAL_BANK_PAGE_SIZE);
+        assert_eq!(check_erase_range_for(&(511..512), 512, &firmware), Ok(()));
+        assert_eq!(
+            check_erase_range_for(&(511..513), 512, &firmware),
+            Err(Error::InvalidPage)
+        );
+        // The second half of a 2MB device doesn't exist on a 1MB one
+        assert_eq!(
+            check_erase_range_for(&(256..257), page_count_for(1024, true), &firmware),
+            Err(Error::InvalidPage)
+        );
+        // Addresses below the flash give a page number past the end
+        let alias_page = address_to_page_for(0x2300, DUAL_BANK_PAGE_SIZE);
+        assert_eq!(
+            check_erase_range_for(&(alias_page..alias_page + 1), 512, &firmware),
+            Err(Error::InvalidPage)
+        );
+    }
+
+    #[test]
+    fn target_range_contains_both_boundaries() {
+        let target = TargetRange {
+            start: FLASH_BASE + 0x4000,
+            len: 0x10,
+        };
+        assert!(target.contains(target.start));
+        assert!(target.contains(target.start + target.len - 1));
+        assert!(!target.contains(target.start - 1));
+        assert!(!target.contains(target.start + target.len));
+        // Far below the start, the subtraction doesn't wrap into the range
+        assert!(!target.contains(0));
+    }
+
+    #[test]
+    fn target_range_at_the_end_of_the_address_space() {
+        let target = TargetRange {
+            start: u32::MAX - 7,
+            len: 8,
+        };
+        assert!(target.contains(u32::MAX));
+        assert!(!target.contains(u32::MAX - 8));
+
+        let empty = TargetRange {
+            start: FLASH_BASE,
+            len: 0,
+        };
+        assert!(!empty.contains(FLASH_BASE));
+    }
+}
@@ -1,5 +1,120 @@
 use cortex_m::asm::delay;
-use stm32l4::stm32l4r5::{self, PWR, RCC, RTC};
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::{DCB, DWT, ITM, SCB, SYST, TPIU};
+use stm32l4::stm32l4r5::{self, DBGMCU, EXTI, PWR, RCC, RTC};
+
+use crate::mem;
+use crate::state::{BackupRegs, BootKind, BACKUP_REGISTER_COUNT};
+
+pub use stm32_flash_corruptor::report::{Led, LedSink};
+
+/// The pin of an LED: PC7 (green), PB14 (red) or PB7 (blue)
+pub const fn led_pin(led: Led) -> GpioPin {
+    match led {
+        Led::Green => GpioPin { port: 2, number: 7 },
+        Led::Red => GpioPin {
+            port: 1,
+            number: 14,
+        },
+        Led::Blue => GpioPin { port: 1, number: 7 },
+    }
+}
+
+// Lighting an LED must never cut the probe connection
+static_assertions::const_assert!(!led_pin(Led::Green).is_debug_pin());
+static_assertions::const_assert!(!led_pin(Led::Red).is_debug_pin());
+static_assertions::const_assert!(!led_pin(Led::Blue).is_debug_pin());
+
+/// The LEDs on the board, see [set_green_led], [set_red_led] and [set_blue_led].
+/// Each call configures the pin again, so this works without any initialization (e.g. in the panic handler).
+pub struct BoardLeds;
+
+impl LedSink for BoardLeds {
+    fn set(&mut self, led: Led, state: bool) {
+        match led {
+            Led::Green => set_green_led(state),
+            Led::Red => set_red_led(state),
+            Led::Blue => set_blue_led(state),
+        }
+    }
+
+    fn set_rgb(&mut self, red: bool, green: bool, blue: bool) {
+        set_rgb(red, green, blue);
+    }
+}
+
+/// The LEDs on the board after [init_leds] configured them. Setting an LED is then a single write to the
+/// BSRR register, which matters e.g. in the fault handlers or for blinking.
+pub struct ConfiguredLeds {
+    _private: (),
+}
+
+/// Enables the GPIO clocks and configures the pins of all LEDs as outputs, once.
+/// Use the [set_green_led] etc. functions instead if the LED is only set once.
+pub fn init_leds() -> ConfiguredLeds {
+    let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+    peripherals
+        .RCC
+        .ahb2enr
+        .modify(|_, w| w.gpioben().set_bit().gpiocen().set_bit());
+    peripherals.GPIOC.moder.modify(|_, w| w.moder7().output());
+    peripherals
+        .GPIOB
+        .moder
+        .modify(|_, w| w.moder14().output().moder7().output());
+
+    ConfiguredLeds { _private: () }
+}
+
+impl LedSink for ConfiguredLeds {
+    fn set(&mut self, led: Led, state: bool) {
+        let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+        match led {
+            // PC7
+            Led::Green => peripherals.GPIOC.bsrr.write(|w| {
+                if state {
+                    w.bs7().set_bit()
+                } else {
+                    w.br7().set_bit()
+                }
+            }),
+            // PB14
+            Led::Red => peripherals.GPIOB.bsrr.write(|w| {
+                if state {
+                    w.bs14().set_bit()
+                } else {
+                    w.br14().set_bit()
+                }
+            }),
+            // PB7
+            Led::Blue => peripherals.GPIOB.bsrr.write(|w| {
+                if state {
+                    w.bs7().set_bit()
+                } else {
+                    w.br7().set_bit()
+                }
+            }),
+        }
+    }
+
+    fn set_rgb(&mut self, red: bool, green: bool, blue: bool) {
+        let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+        // Red and blue share GPIOB, so a single BSRR write sets both
+        peripherals.GPIOB.bsrr.write(|w| {
+            let w = if red {
+                w.bs14().set_bit()
+            } else {
+                w.br14().set_bit()
+            };
+            if blue {
+                w.bs7().set_bit()
+            } else {
+                w.br7().set_bit()
+            }
+        });
+        self.set(Led::Green, green);
+    }
+}
 
 pub fn set_green_led(state: bool) {
     // PC7
@@ -25,12 +140,199 @@ pub fn set_blue_led(state: bool) {
     peripherals.GPIOB.odr.modify(|_, w| w.odr7().bit(state));
 }
 
+/// Sets all three LEDs with as few register writes as possible: the clocks of both ports are enabled with one
+/// write, red (PB14) and blue (PB7) share a single GPIOB `odr` modify, and green (PC7) gets the GPIOC one.
+/// Like [set_green_led] etc., this configures the pins every time, so it works without any initialization.
+pub fn set_rgb(red: bool, green: bool, blue: bool) {
+    let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+    peripherals
+        .RCC
+        .ahb2enr
+        .modify(|_, w| w.gpioben().set_bit().gpiocen().set_bit());
+    peripherals
+        .GPIOB
+        .moder
+        .modify(|_, w| w.moder14().output().moder7().output());
+    peripherals
+        .GPIOB
+        .odr
+        .modify(|_, w| w.odr14().bit(red).odr7().bit(blue));
+    peripherals.GPIOC.moder.modify(|_, w| w.moder7().output());
+    peripherals.GPIOC.odr.modify(|_, w| w.odr7().bit(green));
+}
+
+/// Configures the user button of the Nucleo board (PC13) as an input. The board has an external pull-down,
+/// so the pin is high while the button is pressed.
+#[cfg(feature = "button")]
+pub fn init_button(rcc: &RCC, gpioc: &stm32l4r5::GPIOC) {
+    rcc.ahb2enr.modify(|_, w| w.gpiocen().set_bit());
+    gpioc.moder.modify(|_, w| w.moder13().input());
+}
+
+/// True while the user button is pressed, see [init_button]
+#[cfg(feature = "button")]
+pub fn button_pressed() -> bool {
+    let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+    peripherals.GPIOC.idr.read().idr13().bit_is_set()
+}
+
+/// Resets the chip right away (SYSRESETREQ), just like the watchdog would - the backup registers are kept
+pub fn software_reset() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// The values that can be written to the IWDG key register (IWDG_KR), see "Independent watchdog (IWDG)"
+/// in the reference manual. They have very different effects, so callers have to pick one explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum WatchdogKey {
+    /// Reloads the down-counter with the value of IWDG_RLR, i.e. a real "feed"
+    Reload = 0xAAAA,
+    /// Enables write access to IWDG_PR, IWDG_RLR and IWDG_WINR
+    EnableAccess = 0x5555,
+    /// Starts the watchdog. Once started, it can only be stopped by a reset
+    Start = 0xCCCC,
+    /// Any other value write-protects IWDG_PR, IWDG_RLR and IWDG_WINR again.
+    /// Note that this does *not* reload the counter.
+    Protect = 0x0001,
+}
+
+/// How long the LED is on in each [heartbeat] period, in CPU cycles (100ms)
+pub const HEARTBEAT_ON_CYCLES: u32 = SYSTEM_CLOCK_HZ / 10;
+/// Length of a full [heartbeat] period, in CPU cycles (2s)
+pub const HEARTBEAT_PERIOD_CYCLES: u32 = 2 * SYSTEM_CLOCK_HZ;
+
+/// How long the LED is on in each period of the low power pulse, in CPU cycles (20ms)
+pub const LOW_POWER_ON_CYCLES: u32 = SYSTEM_CLOCK_HZ / 50;
+/// How long the LED is off in each period of the low power pulse, in CPU cycles (about 3s).
+/// That's less than 1% of the current of a solid LED.
+pub const LOW_POWER_OFF_CYCLES: u32 = 3 * SYSTEM_CLOCK_HZ;
+
+/// Slowly pulses `led` forever, so it's visible from a distance that the board is still alive - unlike a
+/// solid LED, which looks the same whether the board is running or hung. The watchdog is fed on every
+/// iteration, so a running IWDG can't reset the chip.
+///
+/// The period is measured with the DWT cycle counter, which must be enabled (see [enable_cycle_counter]).
+pub fn heartbeat(led: Led, iwdg: &stm32l4r5::IWDG) -> ! {
+    pulse_led(
+        led,
+        HEARTBEAT_ON_CYCLES,
+        HEARTBEAT_PERIOD_CYCLES - HEARTBEAT_ON_CYCLES,
+        iwdg,
+    )
+}
+
+/// Like [heartbeat], but with any timing: `led` is on for `on_cycles` and then off for `off_cycles`, forever.
+/// A short on time keeps the signal visible at a fraction of the current, e.g. with [LOW_POWER_ON_CYCLES].
+pub fn pulse_led(led: Led, on_cycles: u32, off_cycles: u32, iwdg: &stm32l4r5::IWDG) -> ! {
+    let period = on_cycles + off_cycles;
+    let mut leds = init_leds();
+    let mut period_start = DWT::cycle_count();
+    loop {
+        Watchdog::new(iwdg).reload();
+
+        let elapsed = DWT::cycle_count().wrapping_sub(period_start);
+        if elapsed >= period {
+            period_start = period_start.wrapping_add(period);
+        }
+        leds.set(led, elapsed < on_cycles);
+    }
+}
+
+/// How long the LED is on for a 0 bit in [blink_u32], in CPU cycles (200ms)
+pub const BLINK_SHORT_CYCLES: u32 = SYSTEM_CLOCK_HZ / 5;
+/// How long the LED is on for a 1 bit in [blink_u32], in CPU cycles (800ms)
+pub const BLINK_LONG_CYCLES: u32 = 4 * SYSTEM_CLOCK_HZ / 5;
+/// Pause after each bit in [blink_u32], in CPU cycles (400ms). After every byte, the pause is twice as long.
+pub const BLINK_GAP_CYCLES: u32 = 2 * SYSTEM_CLOCK_HZ / 5;
+/// Pause before [blink_u32] starts over, in CPU cycles (4s)
+pub const BLINK_REPEAT_CYCLES: u32 = 4 * SYSTEM_CLOCK_HZ;
+
+/// Blinks `value` on `led` forever, so it can be read off the board (or a video of it) without any tools.
+/// All 32 bits are sent, most significant first: a short blink is a 0, a long one is a 1. The pause after every
+/// 8 bits is longer, and after the last bit there is a long pause before it starts over.
+/// The watchdog is fed all the time, like in [heartbeat].
+///
+/// The timing is measured with the DWT cycle counter, which must be enabled (see [enable_cycle_counter]).
+pub fn blink_u32(led: Led, value: u32, iwdg: &stm32l4r5::IWDG) -> ! {
+    let mut leds = init_leds();
+    loop {
+        for bit in (0..32).rev() {
+            leds.set(led, true);
+            if value & (1 << bit) != 0 {
+                wait_feeding_watchdog(iwdg, BLINK_LONG_CYCLES);
+            } else {
+                wait_feeding_watchdog(iwdg, BLINK_SHORT_CYCLES);
+            }
+
+            leds.set(led, false);
+            if bit % 8 == 0 {
+                wait_feeding_watchdog(iwdg, 2 * BLINK_GAP_CYCLES);
+            } else {
+                wait_feeding_watchdog(iwdg, BLINK_GAP_CYCLES);
+            }
+        }
+        wait_feeding_watchdog(iwdg, BLINK_REPEAT_CYCLES);
+    }
+}
+
+/// Busy-waits for `cycles` CPU cycles, reloading the watchdog all the time
+fn wait_feeding_watchdog(iwdg: &stm32l4r5::IWDG, cycles: u32) {
+    let start = DWT::cycle_count();
+    while DWT::cycle_count().wrapping_sub(start) < cycles {
+        Watchdog::new(iwdg).reload();
+    }
+}
+
+/// Frequency of the system clock (HCLK). This is MSI at 4MHz after reset, and we never change it.
+pub const SYSTEM_CLOCK_HZ: u32 = 4_000_000;
+
+/// The reload value of the IWDG set by [activate_watchdog]
+pub const WATCHDOG_RELOAD: u16 = 0xFFF;
+/// Duration of a single IWDG tick: the 32kHz LSI divided by 4
+pub const WATCHDOG_TICK_US: u32 = 125;
+/// Time from a [WatchdogKey::Reload] until the IWDG resets the chip
+pub const WATCHDOG_TIMEOUT_US: u32 = (WATCHDOG_RELOAD as u32 + 1) * WATCHDOG_TICK_US;
+
+/// Writes the given key to the IWDG key register
+pub fn watchdog_write(iwdg: &stm32l4r5::IWDG, key: WatchdogKey) {
+    iwdg.kr.write(|w| unsafe { w.key().bits(key as u16) });
+}
+
+/// The IWDG after [activate_watchdog], with one method per intent. Only [Watchdog::reload] postpones the reset,
+/// so the corruption timing can't be thrown off by a write that just looks like a feed.
+#[derive(Clone, Copy)]
+pub struct Watchdog<'a> {
+    iwdg: &'a stm32l4r5::IWDG,
+}
+
+impl<'a> Watchdog<'a> {
+    pub fn new(iwdg: &'a stm32l4r5::IWDG) -> Self {
+        Watchdog { iwdg }
+    }
+
+    /// A full feed ([WatchdogKey::Reload]): the counter starts over, the reset is [WATCHDOG_TIMEOUT_US] away again
+    pub fn reload(&self) {
+        watchdog_write(self.iwdg, WatchdogKey::Reload);
+    }
+
+    /// Writes key 1 ([WatchdogKey::Protect]). Despite how it's used, this doesn't reload the counter, not even
+    /// partially: the manual only defines the keys for reload, start and register access, any other value
+    /// write-protects IWDG_PR, IWDG_RLR and IWDG_WINR again. This is well-defined behavior, not a trick.
+    ///
+    /// [activate_watchdog] leaves the registers writable, so this locks them. The counter keeps running from the
+    /// last reload, so the time until the reset doesn't change. In a loop, it waits for the reset without feeding.
+    pub fn partial_tickle(&self) {
+        watchdog_write(self.iwdg, WatchdogKey::Protect);
+    }
+}
+
 pub fn activate_watchdog(iwdg: &stm32l4r5::IWDG) -> Result<(), ()> {
-    iwdg.kr.write(|w| w.key().start());
-    iwdg.kr.write(|w| w.key().enable());
+    watchdog_write(iwdg, WatchdogKey::Start);
+    watchdog_write(iwdg, WatchdogKey::EnableAccess);
     // Smallest prescaler - unit of 1 is 0.125ms
     iwdg.pr.modify(|_, w| w.pr().divide_by4());
-    iwdg.rlr.modify(|_, w| w.rl().bits(0xFFF));
+    iwdg.rlr.modify(|_, w| w.rl().bits(WATCHDOG_RELOAD));
 
     let mut loop_iters: u32 = 0;
     const MAX_LOOP_ITER: u32 = 16000 * 10;
@@ -48,16 +350,219 @@ pub fn activate_watchdog(iwdg: &stm32l4r5::IWDG) -> Result<(), ()> {
             break Err(());
         }
     };
-    iwdg.kr.write(|w| w.key().reset());
+    watchdog_write(iwdg, WatchdogKey::Reload);
     activation_result
 }
 
+/// Frequency SysTick counts at with its external clock source: HCLK divided by 8
+pub const SYSTICK_HZ: u32 = SYSTEM_CLOCK_HZ / 8;
+/// The longest deadline [arm_systick_deadline] accepts, as SysTick is a 24-bit counter (about 33s)
+pub const SYSTICK_MAX_TICKS: u32 = 0x00FF_FFFF;
+
+/// Makes SysTick fire its exception after `ticks` of [SYSTICK_HZ] (at most [SYSTICK_MAX_TICKS]), independently of
+/// the IWDG. The SysTick handler then resets the chip, so a hung operation (e.g. a flash wait that never ends while
+/// the IWDG is fed) can't wedge the search. Call [disarm_systick_deadline] before waiting on purpose.
+///
+/// The exception covers every loop without having to check anything in it. It can be masked like any other
+/// interrupt though, so with PRIMASK set, the reset only happens once interrupts are unmasked again.
+pub fn arm_systick_deadline(syst: &mut SYST, ticks: u32) {
+    syst.disable_counter();
+    syst.set_clock_source(SystClkSource::External);
+    // The exception fires when the counter reaches zero, which is `reload` ticks after it was cleared
+    syst.set_reload(ticks.clamp(1, SYSTICK_MAX_TICKS));
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+/// Stops a deadline started with [arm_systick_deadline]
+pub fn disarm_systick_deadline(syst: &mut SYST) {
+    syst.disable_interrupt();
+    syst.disable_counter();
+}
+
+/// Enables the DWT cycle counter, which counts at [SYSTEM_CLOCK_HZ]
+pub fn enable_cycle_counter(dcb: &mut DCB, dwt: &mut DWT) {
+    // The DWT can only be used with TRCENA set in DEMCR
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+}
+
+/// DWT cycle count at the start of main, see [mark_boot]
+static BOOT_CYCLES: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// Remembers the current cycle count as the start of this boot for [measure_reset_to_write_latency].
+/// Call this at the very start of main, right after [enable_cycle_counter]. The startup code before main
+/// (copying `.data` and zeroing `.bss`) always takes the same time, so it doesn't add any jitter.
+pub fn mark_boot() {
+    BOOT_CYCLES.store(DWT::cycle_count(), core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Cycles since [mark_boot]
+pub fn cycles_since_boot() -> u32 {
+    DWT::cycle_count().wrapping_sub(BOOT_CYCLES.load(core::sync::atomic::Ordering::SeqCst))
+}
+
+/// Steps of an attempt, recorded with [log_transition] to reconstruct its timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Status {
+    /// [mark_boot] was called
+    Booted = 0,
+    /// The watchdog was started, from here on the time until the reset is fixed
+    WatchdogStarted = 1,
+    /// An erase of the target pages started
+    Erasing = 2,
+    /// The write of the target started
+    Writing = 3,
+    /// The operation the reset should have interrupted completed
+    Completed = 4,
+    /// An ECC error was reported by the NMI
+    EccErrorDetected = 5,
+}
+
+/// Number of entries in the [log_transition] ring buffer. An attempt has less transitions than that,
+/// so the whole boot fits.
+pub const TIMELINE_LEN: usize = 8;
+
+/// The [log_transition] ring buffer: the status in the lower 8 bits, the cycles since boot (see [mark_boot])
+/// in the upper 24 bits, i.e. in units of 256 cycles. That's 64 microseconds per unit and wraps after about 18 minutes.
+static TIMELINE: [core::sync::atomic::AtomicU32; TIMELINE_LEN] =
+    [const { core::sync::atomic::AtomicU32::new(0) }; TIMELINE_LEN];
+/// The [rtc_timestamp] of each [TIMELINE] entry, at the same index
+static TIMELINE_RTC: [core::sync::atomic::AtomicU32; TIMELINE_LEN] =
+    [const { core::sync::atomic::AtomicU32::new(0) }; TIMELINE_LEN];
+/// Number of entries ever written to [TIMELINE] in this boot
+static TIMELINE_COUNT: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Appends `status` with the current time to the timeline of this boot. Once [TIMELINE_LEN] entries are used,
+/// the oldest ones are overwritten. This is safe to call from the NMI, and quick enough for the corruption window.
+///
+/// The buffer is in RAM, so it can be read with a debugger ([TIMELINE] and [TIMELINE_RTC]), or sent with
+/// [dump_timeline]. The RTC calendar must have been initialized, see [init_rtc_calendar].
+pub fn log_transition(status: Status) {
+    let index = TIMELINE_COUNT.fetch_add(1, core::sync::atomic::Ordering::SeqCst) % TIMELINE_LEN;
+    let entry = (cycles_since_boot() & !0xFF) | status as u32;
+    TIMELINE[index].store(entry, core::sync::atomic::Ordering::SeqCst);
+    TIMELINE_RTC[index].store(rtc_timestamp(), core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Sends the [log_transition] entries of this boot over the ITM [TIMELINE_PORT], oldest first.
+/// Each entry is sent as two words: the [TIMELINE] entry, then its [rtc_timestamp].
+pub fn dump_timeline(itm: &mut ITM) {
+    let count = TIMELINE_COUNT.load(core::sync::atomic::Ordering::SeqCst);
+    for entry in count.saturating_sub(TIMELINE_LEN)..count {
+        let index = entry % TIMELINE_LEN;
+        let value = TIMELINE[index].load(core::sync::atomic::Ordering::SeqCst);
+        itm_write_u32(itm, TIMELINE_PORT, value);
+        let timestamp = TIMELINE_RTC[index].load(core::sync::atomic::Ordering::SeqCst);
+        itm_write_u32(itm, TIMELINE_PORT, timestamp);
+    }
+}
+
+/// Cycles since [mark_boot] without `excluded_cycles`, e.g. the waiting loop of the search.
+/// Right before the corruption write, this is the fixed overhead of the path from reset to the write:
+/// the search only controls the waiting loop, so any jitter here directly shifts the effective timing.
+pub fn measure_reset_to_write_latency(excluded_cycles: u32) -> u32 {
+    cycles_since_boot().wrapping_sub(excluded_cycles)
+}
+
+/// Estimates how many microseconds are left until the IWDG resets the chip, given the DWT cycle count
+/// at the time of the last [WatchdogKey::Reload] (the cycle counter must be enabled, see [enable_cycle_counter]).
+///
+/// The IWDG counter can't be read, so this is only an estimation: the LSI clocking the IWDG is only accurate
+/// to a few percent (see "Low-speed internal (LSI) RC oscillator" in the datasheet), while the cycle counter
+/// runs from the much more precise system clock. On top of that, the IWDG only counts in ticks of [WATCHDOG_TICK_US].
+/// So expect an error of some percent of the elapsed time plus one tick.
+pub fn watchdog_remaining(reloaded_at_cycles: u32) -> u32 {
+    // The cycle counter wraps after 2^32 cycles, which is much longer than the watchdog timeout
+    let elapsed_cycles = DWT::cycle_count().wrapping_sub(reloaded_at_cycles);
+    let elapsed_us = elapsed_cycles / (SYSTEM_CLOCK_HZ / 1_000_000);
+
+    WATCHDOG_TIMEOUT_US.saturating_sub(elapsed_us)
+}
+
+/// Writes [WatchdogKey::Protect], which does *not* reload the counter (see [Watchdog::partial_tickle])
+#[deprecated(note = "use `Watchdog::partial_tickle`, which documents what key 1 actually does")]
+#[allow(dead_code)]
 pub fn watchdog_feed_min(iwdg: &stm32l4r5::IWDG) {
-    iwdg.kr.write(|w| unsafe { w.key().bits(1) });
+    watchdog_write(iwdg, WatchdogKey::Protect);
 }
 
+/// Writes [WatchdogKey::Reload]
+#[deprecated(note = "use `Watchdog::reload`")]
+#[allow(dead_code)]
 pub fn watchdog_feed(iwdg: &stm32l4r5::IWDG) {
-    iwdg.kr.write(|w| w.key().reset());
+    watchdog_write(iwdg, WatchdogKey::Reload);
+}
+
+/// True if [enable_rtc] has already enabled access to the backup registers.
+/// Before that, reads return zero and writes are ignored.
+pub fn backup_registers_writable(rcc: &RCC, pwr: &PWR) -> bool {
+    let clocks = rcc.apb1enr1.read();
+    clocks.pwren().bit_is_set()
+        && clocks.rtcapben().bit_is_set()
+        && pwr.cr1.read().dbp().bit_is_set()
+}
+
+/// The backup registers of the RTC, see [crate::state::SearchState] for their layout.
+/// They must be accessible, see [enable_rtc].
+#[derive(Clone, Copy)]
+pub struct RtcBackupRegs<'a> {
+    rtc: &'a RTC,
+}
+
+impl<'a> RtcBackupRegs<'a> {
+    pub fn new(rtc: &'a RTC) -> Self {
+        RtcBackupRegs { rtc }
+    }
+}
+
+// The RTC has exactly one backup register per SearchState field
+static_assertions::const_assert_eq!(BACKUP_REGISTER_COUNT, 32);
+
+impl BackupRegs for RtcBackupRegs<'_> {
+    fn read(&self, index: usize) -> u32 {
+        self.rtc.bkpr[index].read().bits()
+    }
+
+    fn write(&mut self, index: usize, value: u32) {
+        self.rtc.bkpr[index].write(|w| unsafe { w.bits(value) });
+    }
+}
+
+/// True if the last reset was a power-on (or brown-out) reset, which also resets the backup domain
+/// unless VBAT stayed powered. There is no dedicated flag for a backup domain reset, so this checks
+/// BORRSTF in RCC_CSR. On the Nucleo board, VBAT is connected to VDD, so both always go together.
+///
+/// The reset flags stay set until they are cleared with [clear_reset_flags], so this only describes the
+/// last reset if the flags are cleared on every boot.
+pub fn backup_domain_was_reset(rcc: &RCC) -> bool {
+    rcc.csr.read().borrstf().bit_is_set()
+}
+
+/// Clears all reset flags in RCC_CSR, so the next boot only sees the flags of its own reset
+pub fn clear_reset_flags(rcc: &RCC) {
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+}
+
+/// Reads the cause of the last reset from the reset flags in RCC_CSR and clears them (see [clear_reset_flags]),
+/// so the next boot only sees its own. Every internal reset is also driven out on NRST, so PINRSTF only decides if nothing else is set.
+pub fn take_boot_kind(rcc: &RCC) -> BootKind {
+    let csr = rcc.csr.read();
+    let kind = if backup_domain_was_reset(rcc) {
+        BootKind::ColdBoot
+    } else if csr.iwdgrstf().bit_is_set() || csr.wwdgrstf().bit_is_set() {
+        BootKind::WatchdogReset
+    } else if csr.sftrstf().bit_is_set() {
+        BootKind::SoftwareReset
+    } else if csr.pinrstf().bit_is_set() {
+        BootKind::PinReset
+    } else {
+        BootKind::Other
+    };
+    clear_reset_flags(rcc);
+    kind
 }
 
 pub fn enable_rtc(rcc: &RCC, rtc: &RTC, pwr: &PWR) {
@@ -78,12 +583,546 @@ pub fn enable_rtc(rcc: &RCC, rtc: &RTC, pwr: &PWR) {
 
     // Per default tamper detection is off, but disable backup
     // register erase on tamper detection just to be safe.
+    set_tamper_erase(rtc, false);
+}
+
+/// Frequency of the RTC wakeup timer used by [sleep_ms]: the 32kHz LSI divided by 16 (WUCKSEL = 0b000)
+pub const RTC_WAKEUP_HZ: u32 = 2_000;
+
+/// Clock source of the RTC (RTCSEL in RCC_BDCR)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcClock {
+    /// The 32.768kHz crystal, accurate enough for timestamps. On the Nucleo board this is X2.
+    Lse,
+    /// The internal 32kHz RC oscillator, only accurate to a few percent
+    Lsi,
+}
+
+/// How long [select_rtc_clock] waits for the LSE to start before giving up on it. The datasheet allows up to
+/// 2s with the lowest drive level, which is 8 million cycles with the 4MHz MSI we run on after reset.
+pub const LSE_STARTUP_TIMEOUT_CYCLES: u32 = 8_000_000;
+
+/// How long [select_rtc_clock] waits for the LSI to start. The datasheet gives at most 260µs, so this is far
+/// more than it ever needs with the 4MHz MSI, but a broken oscillator must not hang the tool.
+pub const LSI_STARTUP_TIMEOUT_CYCLES: u32 = 100_000;
+
+/// Clocks the RTC from `source` and returns the source that is actually used.
+///
+/// If the LSE was requested but doesn't become ready within [LSE_STARTUP_TIMEOUT_CYCLES], e.g. because no
+/// crystal is fitted, it is turned off again and the RTC falls back to the LSI. If the LSI isn't ready within
+/// [LSI_STARTUP_TIMEOUT_CYCLES] either, this fails.
+///
+/// The clock source can only be selected once: changing it again requires a backup domain reset, which would
+/// also clear the backup registers. So if a clock was already selected, it is kept as long as it is one of
+/// the two (the LSE only as long as it's still running). If the HSE was selected, this fails.
+/// [enable_rtc] must have been called before, as RCC_BDCR is write-protected otherwise.
+pub fn select_rtc_clock(rcc: &RCC, source: RtcClock) -> Result<RtcClock, ()> {
+    let rtcsel = rcc.bdcr.read().rtcsel();
+    let source = if rtcsel.is_lse() {
+        RtcClock::Lse
+    } else if rtcsel.is_lsi() {
+        RtcClock::Lsi
+    } else if rtcsel.is_no_clock() {
+        source
+    } else {
+        return Err(());
+    };
+
+    if source == RtcClock::Lse && start_lse(rcc) {
+        if rtcsel.is_no_clock() {
+            rcc.bdcr.modify(|_, w| w.rtcsel().lse());
+        }
+    } else if rtcsel.is_lse() {
+        // The crystal is gone, but the selection can't be changed without losing the backup registers
+        return Err(());
+    } else {
+        // The LSI is also used by the IWDG, which turns it on by itself, but it's off after a reset
+        rcc.csr.modify(|_, w| w.lsion().set_bit());
+        if !wait_for_lsi(rcc) {
+            return Err(());
+        }
+
+        if rtcsel.is_no_clock() {
+            rcc.bdcr.modify(|_, w| w.rtcsel().lsi());
+        }
+    }
+    rcc.bdcr.modify(|_, w| w.rtcen().set_bit());
+
+    Ok(if rcc.bdcr.read().rtcsel().is_lse() {
+        RtcClock::Lse
+    } else {
+        RtcClock::Lsi
+    })
+}
+
+/// Turns on the LSE and waits until it is ready, or turns it off again after [LSE_STARTUP_TIMEOUT_CYCLES]
+fn start_lse(rcc: &RCC) -> bool {
+    // The LSE keeps running from the backup domain across resets, so it's usually ready right away
+    rcc.bdcr.modify(|_, w| w.lseon().set_bit());
+
+    const POLL_CYCLES: u32 = 1_000;
+    for _ in 0..LSE_STARTUP_TIMEOUT_CYCLES / POLL_CYCLES {
+        if rcc.bdcr.read().lserdy().bit_is_set() {
+            return true;
+        }
+        delay(POLL_CYCLES);
+    }
+
+    rcc.bdcr.modify(|_, w| w.lseon().clear_bit());
+    false
+}
+
+/// Waits until the LSI is ready, for at most [LSI_STARTUP_TIMEOUT_CYCLES]
+fn wait_for_lsi(rcc: &RCC) -> bool {
+    const POLL_CYCLES: u32 = 100;
+    for _ in 0..LSI_STARTUP_TIMEOUT_CYCLES / POLL_CYCLES {
+        if rcc.csr.read().lsirdy().bit_is_set() {
+            return true;
+        }
+        delay(POLL_CYCLES);
+    }
+
+    false
+}
+
+/// Clocks the RTC from the LSI, which the wakeup timer of [sleep_ms] needs.
+///
+/// Fails if another clock was already selected or the LSI doesn't start, see [select_rtc_clock].
+pub fn enable_rtc_clock(rcc: &RCC) -> Result<(), ()> {
+    match select_rtc_clock(rcc, RtcClock::Lsi)? {
+        RtcClock::Lsi => Ok(()),
+        RtcClock::Lse => Err(()),
+    }
+}
+
+/// Unlocks or locks the write protection of the RTC registers (RTC_WPR), the backup registers are not affected
+fn rtc_write_protection(rtc: &RTC, locked: bool) {
+    if locked {
+        // Any wrong key enables the protection again
+        rtc.wpr.write(|w| unsafe { w.key().bits(0xFF) });
+    } else {
+        rtc.wpr.write(|w| unsafe { w.key().bits(0xCA) });
+        rtc.wpr.write(|w| unsafe { w.key().bits(0x53) });
+    }
+}
+
+/// Sleeps for about `ms` milliseconds (longer times are cut to about 32s) in sleep mode, woken up by the RTC wakeup timer.
+/// The RTC must be clocked by the LSI (see [enable_rtc_clock]), so the time is only accurate to a few percent.
+///
+/// The wakeup timer is connected to EXTI line 20, which is configured as an event here: WFE then wakes up
+/// without any interrupt handler being involved. Note that a running IWDG keeps counting during sleep.
+pub fn sleep_ms(rtc: &RTC, exti: &EXTI, ms: u16) {
+    if ms == 0 {
+        return;
+    }
+    // The timer counts WUT + 1 ticks
+    let ticks = (ms as u32 * RTC_WAKEUP_HZ / 1000).clamp(1, 0x1_0000) - 1;
+
+    rtc_write_protection(rtc, false);
+    // WUTR can only be written while the timer is disabled and WUTWF confirms that
+    rtc.cr.modify(|_, w| w.wute().clear_bit());
+    while rtc.isr.read().wutwf().bit_is_clear() {}
+    rtc.wutr.write(|w| unsafe { w.wut().bits(ticks as u16) });
+    rtc.isr.modify(|_, w| w.wutf().clear_bit());
+    rtc.cr
+        .modify(|_, w| unsafe { w.wucksel().bits(0b000).wutie().set_bit().wute().set_bit() });
+    rtc_write_protection(rtc, true);
+
+    // Rising edge of the wakeup signal as an event, not as an interrupt
+    exti.rtsr1.modify(|_, w| w.tr20().set_bit());
+    exti.emr1.modify(|_, w| w.mr20().set_bit());
+
+    // WFE also returns for other events (or a pending event from before), so check the flag
+    while rtc.isr.read().wutf().bit_is_clear() {
+        cortex_m::asm::wfe();
+    }
+
+    rtc_write_protection(rtc, false);
+    rtc.cr
+        .modify(|_, w| w.wute().clear_bit().wutie().clear_bit());
+    rtc.isr.modify(|_, w| w.wutf().clear_bit());
+    rtc_write_protection(rtc, true);
+    exti.pr1.write(|w| w.pr20().set_bit());
+}
+
+/// Date written to the calendar by [init_rtc_calendar]: year 01, January 1st, a Monday.
+/// The year must not be 00, otherwise INITS wouldn't tell that the calendar was initialized.
+const RTC_CALENDAR_START_DATE: u32 = 0x0001_2101;
+
+/// Starts the RTC calendar at midnight, with the prescalers set for the highest resolution of [rtc_timestamp]:
+/// PREDIV_A = 0, so the sub-second counter (RTC_SSR) counts at the full RTC clock, and PREDIV_S makes that
+/// one second. One tick then is 30.5µs with the LSE and 31.25µs with the LSI. The LSI is only accurate to a few
+/// percent though, so with it the timestamps are only good for comparing events close to each other.
+///
+/// The calendar keeps running across resets, so it is only initialized if it wasn't yet (INITS), or with
+/// other prescalers. `clock` must be the one the RTC runs on, see [select_rtc_clock].
+pub fn init_rtc_calendar(rtc: &RTC, clock: RtcClock) {
+    let ticks_per_second: u32 = match clock {
+        RtcClock::Lse => 32_768,
+        RtcClock::Lsi => 32_000,
+    };
+
+    rtc_write_protection(rtc, false);
+    let prer = rtc.prer.read();
+    let initialized = rtc.isr.read().inits().bit_is_set()
+        && prer.prediv_a().bits() == 0
+        && prer.prediv_s().bits() as u32 == ticks_per_second - 1;
+    if !initialized {
+        // The calendar registers can only be written in initialization mode, which stops the calendar
+        rtc.isr.modify(|_, w| w.init().set_bit());
+        while rtc.isr.read().initf().bit_is_clear() {}
+
+        // The manual requires two separate writes, PREDIV_S first
+        rtc.prer
+            .modify(|_, w| unsafe { w.prediv_s().bits(ticks_per_second as u16 - 1) });
+        rtc.prer.modify(|_, w| unsafe { w.prediv_a().bits(0) });
+        rtc.tr.write(|w| unsafe { w.bits(0) });
+        rtc.dr.write(|w| unsafe { w.bits(RTC_CALENDAR_START_DATE) });
+        // 24-hour format
+        rtc.cr.modify(|_, w| w.fmt().clear_bit());
+
+        rtc.isr.modify(|_, w| w.init().clear_bit());
+    }
+
+    // After a reset or the initialization, the shadow registers are only valid once they were synchronized (RSF)
+    rtc.isr.modify(|_, w| w.rsf().clear_bit());
+    while rtc.isr.read().rsf().bit_is_clear() {}
+    rtc_write_protection(rtc, true);
+}
+
+/// Returns the time of the RTC calendar in ticks of its sub-second counter since midnight, see
+/// [init_rtc_calendar] for the resolution. It only wraps after a day, so unlike the cycle counter it
+/// keeps counting across resets and can order events of different boots too.
+///
+/// This reads the shadow registers: reading RTC_SSR locks RTC_TR and RTC_DR until RTC_DR is read,
+/// so all three are from the same moment. It's quick enough for the corruption window.
+pub fn rtc_timestamp() -> u32 {
+    let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+    let rtc = &peripherals.RTC;
+    let subseconds = rtc.ssr.read().ss().bits() as u32;
+    let time = rtc.tr.read().bits();
+    let _date = rtc.dr.read().bits();
+    let ticks_per_second = rtc.prer.read().prediv_s().bits() as u32 + 1;
+
+    // TR is BCD: units in the lower 4 bits, tens in the `tens_bits` above them
+    let bcd = |shift: u32, tens_bits: u32| {
+        ((time >> (shift + 4)) & ((1 << tens_bits) - 1)) * 10 + ((time >> shift) & 0xF)
+    };
+    let seconds = bcd(16, 2) * 3600 + bcd(8, 3) * 60 + bcd(0, 3);
+
+    // SS counts down from PREDIV_S to 0 within every second
+    seconds * ticks_per_second + (ticks_per_second - 1).saturating_sub(subseconds)
+}
+
+/// Stores where a fault came from in the backup registers ([crate::state::SearchState::fault_pc] and following),
+/// so the next boot can still tell an ECC fault at the target from an unrelated bus fault elsewhere:
+/// the stacked PC of the faulting code, CFSR, BFAR and MMFAR. BFAR and MMFAR only contain an address if
+/// BFARVALID (bit 15) or MMARVALID (bit 7) is set in CFSR.
+pub fn capture_fault_context(rtc: &RTC, frame: &cortex_m_rt::ExceptionFrame, scb: &SCB) {
+    let mut regs = RtcBackupRegs::new(rtc);
+    regs.set_fault_pc(frame.pc);
+    regs.set_fault_cfsr(scb.cfsr.read());
+    regs.set_fault_bfar(scb.bfar.read());
+    regs.set_fault_mmfar(scb.mmfar.read());
+}
+
+/// Starts the built-in bootloader in system memory, as if the chip had booted with BOOT0 high. A host can
+/// then recover the board over USB DFU (or one of the other bootloader interfaces), e.g. after an experiment
+/// corrupted the flash so badly that the probe can't halt the firmware anymore.
+///
+/// The sequence follows "How to jump to system bootloader from application code" (ST AN2606):
+/// 1. Mask interrupts, stop SysTick, then disable and clear all interrupts in the NVIC, so none of our
+///    handlers can be called from inside of the bootloader
+/// 2. Map the system memory to address 0 (MEM_MODE = 001 in SYSCFG_MEMRMP), where the bootloader expects
+///    to be, and point VTOR to it
+/// 3. Unmask interrupts again, the bootloader relies on them and doesn't enable them itself
+/// 4. Load the MSP from the first word of the bootloader's vector table and jump to its reset vector, the second word
+///
+/// Caveats:
+/// - A running IWDG can't be stopped, it would reset the chip back into this firmware in the middle of the
+///   download. So this must be called before [activate_watchdog].
+/// - The bootloader expects the clock configuration of a reset, which is fine as we never change it (see
+///   [SYSTEM_CLOCK_HZ]). Peripherals we configured (LEDs, RTC, ITM) stay configured.
+/// - The bootloader is not available with read protection level 2, and which interfaces it offers depends on
+///   the package (see AN2606).
+pub fn jump_to_bootloader(rcc: &RCC, syscfg: &stm32l4r5::SYSCFG) -> ! {
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+
+    cortex_m::interrupt::disable();
+    core_peripherals.SYST.disable_counter();
+    core_peripherals.SYST.disable_interrupt();
+    for (icer, icpr) in core_peripherals
+        .NVIC
+        .icer
+        .iter()
+        .zip(core_peripherals.NVIC.icpr.iter())
+    {
+        unsafe {
+            icer.write(0xFFFF_FFFF);
+            icpr.write(0xFFFF_FFFF);
+        }
+    }
+
+    rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+    // The register can only be written once the clock is running
+    delay(10);
+    syscfg
+        .memrmp
+        .modify(|_, w| unsafe { w.mem_mode().bits(0b001) });
+    unsafe {
+        core_peripherals.SCB.vtor.write(0);
+        cortex_m::interrupt::enable();
+        cortex_m::asm::bootload(mem::SYSTEM_MEMORY_BASE as *const u32)
+    }
+}
+
+/// Selects whether a tamper event on any of the three tamper inputs erases the backup registers
+/// (the TAMPxNOERASE bits in RTC_TAMPCR). This only has an effect once tamper detection itself is
+/// enabled with the TAMPxE bits, which are off after a backup domain reset.
+///
+/// On the STM32L4R5, there is no split between protected and unprotected registers: a tamper event erases
+/// all 32 backup registers, so the whole search state (see [crate::state::SearchState]) is lost.
+/// Without a tamper event, all of them survive system resets (including the watchdog resets of the search),
+/// and only a backup domain reset (see [backup_domain_was_reset]) clears them.
+pub fn set_tamper_erase(rtc: &RTC, enabled: bool) {
     rtc.tampcr.modify(|_, w| {
         w.tamp1noerase()
-            .set_bit()
+            .bit(!enabled)
             .tamp2noerase()
-            .set_bit()
+            .bit(!enabled)
             .tamp3noerase()
-            .set_bit()
+            .bit(!enabled)
+    });
+}
+
+/// A single GPIO pin, e.g. `GpioPin { port: 0, number: 3 }` for PA3.
+/// Unlike the LEDs, this can be any pin, so the registers are accessed by address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpioPin {
+    /// 0 for GPIOA, 1 for GPIOB and so on
+    pub port: u8,
+    /// Pin number within the port, 0 to 15
+    pub number: u8,
+}
+
+/// The pins a debug probe needs: SWDIO (PA13), SWCLK (PA14) and SWO (PB3, see [enable_itm]).
+/// Configuring any of them as an output cuts the connection to the probe, so [GpioPin::make_output] refuses to,
+/// and the LED pins are checked against them at compile time.
+pub const DEBUG_PINS: [GpioPin; 3] = [
+    GpioPin {
+        port: 0,
+        number: 13,
+    },
+    GpioPin {
+        port: 0,
+        number: 14,
+    },
+    GpioPin { port: 1, number: 3 },
+];
+
+impl GpioPin {
+    /// True if this is one of the [DEBUG_PINS]
+    pub const fn is_debug_pin(&self) -> bool {
+        let mut i = 0;
+        while i < DEBUG_PINS.len() {
+            if DEBUG_PINS[i].port == self.port && DEBUG_PINS[i].number == self.number {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    /// Address of the first GPIO port, each further port follows after 0x400 bytes
+    const GPIOA_ADDRESS: usize = 0x4800_0000;
+    const MODER_OFFSET: usize = 0x00;
+    const BSRR_OFFSET: usize = 0x18;
+
+    fn register(&self, offset: usize) -> *mut u32 {
+        (Self::GPIOA_ADDRESS + self.port as usize * 0x400 + offset) as *mut u32
+    }
+
+    /// Enables the clock of the port and configures the pin as output.
+    /// Returns an error without touching anything if it is one of the [DEBUG_PINS].
+    pub fn make_output(&self, rcc: &RCC) -> Result<(), ()> {
+        if self.is_debug_pin() {
+            return Err(());
+        }
+
+        // GPIOAEN to GPIOIEN are bits 0 to 8
+        rcc.ahb2enr
+            .modify(|r, w| unsafe { w.bits(r.bits() | 1 << self.port) });
+        // Two MODER bits per pin, 0b01 is general purpose output
+        let moder = self.register(Self::MODER_OFFSET);
+        unsafe {
+            let value = core::ptr::read_volatile(moder) & !(0b11 << (self.number * 2));
+            core::ptr::write_volatile(moder, value | 0b01 << (self.number * 2));
+        }
+        Ok(())
+    }
+
+    /// Sets the output level with a single write to BSRR
+    pub fn set(&self, high: bool) {
+        // The lower half of BSRR sets pins, the upper half resets them
+        let bit = if high { self.number } else { self.number + 16 };
+        unsafe { core::ptr::write_volatile(self.register(Self::BSRR_OFFSET), 1 << bit) };
+    }
+}
+
+/// The pin used by [bitbang_report], PA3 is A0 on the Arduino header of the Nucleo board
+pub const REPORT_PIN: GpioPin = GpioPin { port: 0, number: 3 };
+static_assertions::const_assert!(!REPORT_PIN.is_debug_pin());
+
+/// Baud rate of [bitbang_report] with [BITBANG_BAUD_CYCLES]
+pub const BITBANG_BAUD_RATE: u32 = 9600;
+
+/// Length of a single bit in CPU cycles. At 4MHz and 9600 baud, this is 416.67 cycles, rounded to 417.
+/// That's an error of 0.08%, far below the ~2% a UART receiver tolerates over a 10-bit frame.
+/// At higher baud rates, the rounding error grows, e.g. 115200 baud gives 35 cycles and an error of 0.8%.
+pub const BITBANG_BAUD_CYCLES: u32 = (SYSTEM_CLOCK_HZ + BITBANG_BAUD_RATE / 2) / BITBANG_BAUD_RATE;
+
+/// Shifts out `byte` on `pin` like a UART would (8N1: start bit low, 8 data bits LSB first, stop bit high),
+/// so a logic analyzer or USB serial adapter can capture it. The pin must already be an output
+/// (see [GpioPin::make_output]) and should idle high.
+///
+/// Every bit takes `baud_cycles` CPU cycles, as measured by the DWT cycle counter (see [enable_cycle_counter]).
+/// The edges are scheduled relative to the start bit, so the delays don't add up over the frame.
+/// Interrupts are disabled for the frame, as any delay would shift the following bits.
+pub fn bitbang_report(pin: GpioPin, byte: u8, baud_cycles: u32) {
+    cortex_m::interrupt::free(|_| {
+        let start = DWT::cycle_count();
+        // Start bit, data bits, stop bit
+        let frame = (byte as u16) << 1 | 1 << 9;
+        for bit in 0..10 {
+            pin.set(frame & (1 << bit) != 0);
+            let edge = start.wrapping_add((bit + 1) * baud_cycles);
+            while (edge.wrapping_sub(DWT::cycle_count()) as i32) > 0 {}
+        }
     });
 }
+
+/// Sends a 32-bit value with [bitbang_report], least significant byte first
+pub fn bitbang_report_u32(pin: GpioPin, value: u32, baud_cycles: u32) {
+    for byte in value.to_le_bytes() {
+        bitbang_report(pin, byte, baud_cycles);
+    }
+}
+
+/// Frequency of the trace clock. On the STM32L4, TRACECLKIN is connected to HCLK
+pub const TRACE_CLOCK_HZ: u32 = SYSTEM_CLOCK_HZ;
+
+/// Baud rate of the SWO pin. The trace clock must be a multiple of it, and the probe has to be configured
+/// with the same values, e.g. for OpenOCD: `tpiu config internal itm.txt uart off 4000000 2000000`
+pub const SWO_BAUD_RATE: u32 = 2_000_000;
+
+static_assertions::const_assert_eq!(TRACE_CLOCK_HZ % SWO_BAUD_RATE, 0);
+
+/// Enables ITM trace output over the SWO pin (PB3), with only stimulus port 0 enabled.
+/// This should be done once at boot; after that, [itm_write_u32] can be used from anywhere.
+pub fn enable_itm(dbgmcu: &DBGMCU, dcb: &mut DCB, tpiu: &TPIU, itm: &mut ITM) {
+    // Connect the trace pins, TRACE_MODE 00 means asynchronous mode (only the SWO pin is used)
+    dbgmcu
+        .cr
+        .modify(|_, w| unsafe { w.trace_ioen().set_bit().trace_mode().bits(0b00) });
+
+    // Sets TRCENA in DEMCR, otherwise the ITM and TPIU can't be used
+    dcb.enable_trace();
+
+    unsafe {
+        // Selected pin protocol: 0b10 is asynchronous SWO with NRZ (UART) encoding
+        tpiu.sppr.write(0b10);
+        // SWO output clock = trace clock / (ACPR + 1)
+        tpiu.acpr.write(TRACE_CLOCK_HZ / SWO_BAUD_RATE - 1);
+        // Disable the formatter, it isn't needed when only the ITM outputs data
+        tpiu.ffcr.write(0x100);
+
+        // Unlock the ITM registers
+        itm.lar.write(0xC5AC_CE55);
+        // ITMENA (Bit 0) and TraceBusID = 1 (Bits 16-22)
+        itm.tcr.write((1 << 16) | 1);
+        // Allow unprivileged access to all ports, and enable only port 0, the state dump port, the sweep port,
+        // the latency port, the timeline port, the device info port, the operation timing port,
+        // the read compare port (and the register trace port)
+        itm.tpr.write(0);
+        let trace_port = if cfg!(feature = "trace") {
+            1 << REGISTER_TRACE_PORT
+        } else {
+            0
+        };
+        itm.ter[0].write(
+            1 | 1 << STATE_DUMP_PORT
+                | 1 << SWEEP_MAP_PORT
+                | 1 << LATENCY_PORT
+                | 1 << TIMELINE_PORT
+                | 1 << DEVICE_INFO_PORT
+                | 1 << OPERATION_TIMING_PORT
+                | 1 << READ_COMPARE_PORT
+                | trace_port,
+        );
+    }
+}
+
+/// The 96-bit unique ID of this chip, which tells apart the results of several boards
+pub fn device_uid() -> [u32; 3] {
+    let uid = mem::UID_REGISTER as *const u32;
+    unsafe {
+        [
+            core::ptr::read_volatile(uid),
+            core::ptr::read_volatile(uid.add(1)),
+            core::ptr::read_volatile(uid.add(2)),
+        ]
+    }
+}
+
+/// Size of the flash memory in KB, from the factory programmed flash size register
+pub fn flash_size_kb() -> u16 {
+    unsafe { core::ptr::read_volatile(mem::FLASH_SIZE_REGISTER as *const u16) }
+}
+
+/// Sends [device_uid] and [flash_size_kb] over the ITM [DEVICE_INFO_PORT], so the results that follow can be
+/// attributed to a specific chip
+pub fn log_device_info(itm: &mut ITM) {
+    for word in device_uid() {
+        itm_write_u32(itm, DEVICE_INFO_PORT, word);
+    }
+    itm_write_u32(itm, DEVICE_INFO_PORT, flash_size_kb() as u32);
+}
+
+/// The ITM stimulus port used for tracing flash register writes with the `trace` feature
+pub const REGISTER_TRACE_PORT: usize = 1;
+
+/// The ITM stimulus port used for dumping the backup registers on request of the host
+pub const STATE_DUMP_PORT: usize = 2;
+
+/// The ITM stimulus port the page maps of a bank sweep (`bank-sweep` feature) are sent on
+pub const SWEEP_MAP_PORT: usize = 3;
+
+/// The ITM stimulus port [measure_reset_to_write_latency] is sent on after every completed write
+pub const LATENCY_PORT: usize = 4;
+
+/// The ITM stimulus port [dump_timeline] sends on
+pub const TIMELINE_PORT: usize = 5;
+
+/// The ITM stimulus port [log_device_info] sends on
+pub const DEVICE_INFO_PORT: usize = 6;
+
+/// The ITM stimulus port the measured erase and program cycles are sent on (`measure-ops` feature)
+pub const OPERATION_TIMING_PORT: usize = 7;
+
+/// The ITM stimulus port the results of the first read versus a later read are sent on (`read-compare` feature)
+pub const READ_COMPARE_PORT: usize = 8;
+
+/// Writes a value to the given ITM stimulus port. This doesn't need any buffers, so it can be used
+/// from the fault handlers. If the ITM or the port was not enabled (see [enable_itm]), nothing happens.
+pub fn itm_write_u32(itm: &mut ITM, port: usize, value: u32) {
+    // The FIFO never becomes ready if the ITM is disabled, so we would wait forever
+    let itm_enabled = itm.tcr.read() & 1 != 0;
+    let port_enabled = itm.ter[port / 32].read() & (1 << (port % 32)) != 0;
+    if !itm_enabled || !port_enabled {
+        return;
+    }
+
+    let stim = &mut itm.stim[port];
+    while !stim.is_fifo_ready() {}
+    stim.write_u32(value);
+}
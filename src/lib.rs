@@ -0,0 +1,13 @@
+//! The parts of the corruptor that don't access the hardware, so they can be tested on the host (`make test`).
+//! The firmware in `main.rs` uses them like any other module.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod ecc;
+pub mod geometry;
+pub mod registers;
+pub mod report;
+pub mod search;
+pub mod sequence;
+pub mod state;
+pub mod sweep;
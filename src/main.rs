@@ -5,90 +5,308 @@ use cortex_m_rt::{entry, exception};
 use stm32l4::stm32l4r5;
 
 // Which address should be corrupted, with an allowed range
-const APPROXIMATE_ADDRESS_TO_CORRUPT: usize = 0x2300;
+// This is the offset from the start of the flash, which is also what FLASH_ECCR reports
+const APPROXIMATE_ADDRESS_TO_CORRUPT: usize = 0x1_0300;
 const CORRUPT_RANGE: usize = 0x8;
 static_assertions::const_assert!(CORRUPT_RANGE > 0);
-
-// On the first page, this tool itself lies. Don't let it erase itself!
-// In dual bank mode, the first page is 4096 bytes, so we can't corrupt the first page.
-// If you are in single-bank mode, don't go below 8192
-static_assertions::const_assert!(APPROXIMATE_ADDRESS_TO_CORRUPT >= 8192);
-
+// The absolute address to corrupt, flash functions only accept these. A symbol or address set at build time
+// (see build.rs) takes precedence, otherwise this is APPROXIMATE_ADDRESS_TO_CORRUPT
+const TARGET_ADDRESS: usize = match parse_decimal(env!("CORRUPT_TARGET_ADDR")) {
+    Some(address) => address as usize,
+    None => mem::FLASH_BASE as usize + APPROXIMATE_ADDRESS_TO_CORRUPT,
+};
+static_assertions::const_assert!(TARGET_ADDRESS >= mem::FLASH_BASE as usize);
+// Where an ECC error counts as a hit. The reads after a reset or a write check the same range
+const TARGET: mem::TargetRange = mem::TargetRange {
+    start: TARGET_ADDRESS as u32,
+    len: CORRUPT_RANGE as u32,
+};
+
+// Which bits of each written doubleword should be flipped. The page is all 1s after the erase, so the bits set
+// here are programmed to 0, while all others keep their erased value. With all bits set, this writes zeros.
+// For single-bit ECC experiments, set a single bit (e.g. `1 << 3` for bit 3 of the first byte) and a
+// CORRUPT_RANGE of at most 8, so only one doubleword is written.
+const CORRUPT_BIT_MASK: u64 = u64::MAX;
+static_assertions::const_assert!(CORRUPT_BIT_MASK != 0);
+// What is actually written to each doubleword of the target range
+const CORRUPT_PATTERN: u64 = !CORRUPT_BIT_MASK;
+// How many doublewords are written, starting at TARGET_ADDRESS: the fewest that cover CORRUPT_RANGE.
+// Only whole doublewords can be written, so the write covers CORRUPT_RANGE rounded up to the next multiple of 8,
+// and never more than 7 bytes beyond it
+const CORRUPT_DWORDS: usize = CORRUPT_RANGE.div_ceil(Flash::write_granularity());
+static_assertions::const_assert!(CORRUPT_DWORDS * Flash::write_granularity() >= CORRUPT_RANGE);
+static_assertions::const_assert!(
+    CORRUPT_DWORDS * Flash::write_granularity() < CORRUPT_RANGE + Flash::write_granularity()
+);
+// Otherwise the doublewords wouldn't start at the target
+static_assertions::const_assert_eq!(TARGET_ADDRESS % Flash::write_granularity(), 0);
+
+// The bank mode this binary was built for (see the `dual-bank` feature). This must match the option bytes
+// of the chip, otherwise all page calculations would be wrong - this is checked at boot.
+const DUAL_BANK: bool = cfg!(feature = "dual-bank");
+
+// The experiments that replace the search are single-bank only, see their features in Cargo.toml
+#[cfg(all(feature = "dual-bank", feature = "corrupt-once"))]
+compile_error!("the corrupt-once feature is single-bank only, build it with --no-default-features");
+#[cfg(all(feature = "dual-bank", feature = "bank-sweep"))]
+compile_error!("the bank-sweep feature is single-bank only, build it with --no-default-features");
+// read-compare samples the reads after the resets of the search, so it needs a search that can corrupt something
+#[cfg(all(
+    feature = "read-compare",
+    any(
+        feature = "dry-run",
+        feature = "bench-write",
+        feature = "bank-sweep",
+        feature = "corrupt-once"
+    )
+))]
+compile_error!("the read-compare feature needs the search, it can't be combined with dry-run, bench-write, bank-sweep or corrupt-once");
+
+// On the first pages, this tool itself lies. Don't let it erase itself! The reset that interrupts the operation
+// boots from them, so they can't be targeted at all. Any other page can, also in the bank the code runs from:
+// the corrupting operation then runs from RAM (see Flash::shares_bank_with_code).
+// The target page must start at or after the end of the firmware image. memory.x checks that at link time with
+// this symbol, and main checks it again at runtime
+const TARGET_PAGE_START: usize =
+    TARGET_ADDRESS & !(geometry::page_size_for(DUAL_BANK) as usize - 1);
+core::arch::global_asm!(
+    ".globl __corrupt_target_page",
+    ".set __corrupt_target_page, {}",
+    const TARGET_PAGE_START
+);
+
+mod command;
+mod corruptor;
 mod flash;
 mod hw;
+mod mem;
 
+use command::Command;
+use corruptor::Corruptor;
 use flash::*;
 use hw::*;
+use search::{CorruptionMethod, Phase, SearchStrategy, TimingSearch};
+use state::{init_or_resume, BackupRegs, Outcome, SearchDefaults};
+use stm32_flash_corruptor::report::{evaluate_ecc_error, nmi_outcome, show_outcome};
+use stm32_flash_corruptor::{ecc, geometry, search, state, sweep};
 
 #[panic_handler]
 fn panic_handler(_info: &core::panic::PanicInfo) -> ! {
-    set_red_led(true);
+    show_outcome(&mut BoardLeds, Outcome::Panicked);
 
     let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
 
-    // Clear backup register zero - allows manual reset
-    peripherals.RTC.bkpr[0].write(|w| unsafe { w.bits(0) });
+    // We might panic before the backup registers are accessible, in that case there is nothing to save
+    if hw::backup_registers_writable(&peripherals.RCC, &peripherals.PWR) {
+        let mut regs = RtcBackupRegs::new(&peripherals.RTC);
+        // Clear the magic value - allows manual reset
+        regs.set_magic(0);
+        regs.set_outcome(Outcome::Panicked);
+
+        // Keep what we saw at the time of the panic, as a manual reset starts a new search.
+        // Reading ECCR can't fault, and the search state is just copied over
+        regs.set_panic_eccr(peripherals.FLASH.eccr.read().bits());
+        regs.set_panic_bottom(regs.bottom());
+        regs.set_panic_top(regs.top());
+        // The flash error that caused the panic (if any) goes into the upper bits, see SearchState::panic_state
+        let flash_error = FLASH_ERROR.load(core::sync::atomic::Ordering::SeqCst);
+        regs.set_panic_state(regs.state() | flash_error << 8);
+        regs.set_panic_middle(regs.middle());
+    }
 
+    // This doesn't feed the watchdog, a running one resets us
+    let watchdog = Watchdog::new(&peripherals.IWDG);
     loop {
-        watchdog_feed_min(&peripherals.IWDG);
+        watchdog.partial_tickle();
+    }
+}
+
+// The flash error that made an unwrap_flash panic, zero if there was none
+static FLASH_ERROR: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+/// `unwrap` for results of the flash driver, which remembers the [Error] for the panic handler
+trait UnwrapFlash<T> {
+    fn unwrap_flash(self) -> T;
+}
+
+impl<T> UnwrapFlash<T> for Result<T, Error> {
+    fn unwrap_flash(self) -> T {
+        self.unwrap_or_else(|error| {
+            FLASH_ERROR.store(error.as_u32(), core::sync::atomic::Ordering::SeqCst);
+            panic!("flash error: {:?}", error)
+        })
+    }
+}
+
+/// Unlocks the flash for an attempt. If the unlock sequence fails, the flash stays locked until the next reset
+/// ([Error::UnlockFailed]), so instead of panicking, this counts the failure and resets. The attempt is then
+/// repeated with the same range, as it didn't tell us anything about the timing.
+fn unlock_or_reset<'a>(flash: &'a Flash, regs: &mut impl BackupRegs) -> FlashUnlocked<'a> {
+    match flash.unlock() {
+        Err(Error::UnlockFailed) => {
+            regs.set_unlock_failures(regs.unlock_failures() + 1);
+            regs.set_phase(Phase::Fresh);
+            software_reset()
+        }
+        result => result.unwrap_flash(),
     }
 }
 
 macro_rules! bad_thing_happened {
     () => {{
-        // Turns on the green LED
         let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
-        peripherals.RTC.bkpr[0].write(|w| unsafe { w.bits(0) });
-
-        let reg_content = peripherals.FLASH.eccr.read();
-        let is_flash_nmi: bool = {
-            let flash = Flash::new(peripherals.FLASH);
-            if flash.is_dualbank() {
-                // In dual-bank mode, Bit 29 (ECCD2) is reserved, so only look at bit 31 (ECCD)
-                reg_content.eccd().bit_is_set()
-            } else {
-                /// Bit 31 and Bit 29 - either lower or upper 64 bits of 128 bit value
-                const ECCD_ECCD2_MASK: u32 = 0xa0000000;
-                reg_content.bits() & ECCD_ECCD2_MASK != 0
-            }
-        };
+        report_ecc_error(peripherals.FLASH.eccr.read().bits())
+    }};
+}
 
-        let dead_addr = reg_content.addr_ecc().bits() | ((reg_content.bk_ecc().bit() as u32) << 20);
+/// Size of each bank for [EccError::offset]. The fault handlers have no [Flash], so this reads the flash size
+/// register itself and takes the bank mode the binary was built for, which is checked at boot
+fn bank_size() -> u32 {
+    geometry::bank_size_for(flash_size_kb(), DUAL_BANK)
+}
 
-        // If this is an ECC error in the area we wanted, turn on the green LED
-        if is_flash_nmi {
-            if dead_addr >= APPROXIMATE_ADDRESS_TO_CORRUPT as u32
-                && dead_addr < (APPROXIMATE_ADDRESS_TO_CORRUPT + CORRUPT_RANGE) as u32
-            {
-                // We're done!
-                set_green_led(true);
+/// Offset of the ECC error in the given FLASH_ECCR content from the start of the flash (see [EccError::offset]),
+/// zero if no error is flagged
+fn ecc_error_offset(eccr: u32) -> u32 {
+    EccError::from_eccr(eccr, DUAL_BANK).map_or(0, |error| error.offset(bank_size()))
+}
 
-                loop {
-                    watchdog_feed(&peripherals.IWDG);
-                }
-            } else {
-                set_red_led(true);
-            }
-        } else {
-            set_red_led(true);
-            set_blue_led(true);
+/// The ECC error a dry run (`dry-run` feature) reports once the search converged: ECCD at the target address.
+/// ADDR_ECC is relative to the bank, so a target in the second bank also gets BK_ECC in dual-bank mode
+fn dry_run_eccr() -> u32 {
+    EccError::double_at(TARGET_ADDRESS as u32 - mem::FLASH_BASE, bank_size()).to_eccr()
+}
+
+// First byte of the result sent by report_ecc_error on REPORT_PIN, marks the start of a report
+const REPORT_SYNC_BYTE: u8 = 0xA5;
+
+/// Shows what the given FLASH_ECCR content means on the LEDs (see [show_outcome])
+fn report_ecc_error(eccr: u32) -> ! {
+    // This runs in the fault handlers, where a failing Flash::new would panic. The bank mode is checked
+    // against DUAL_BANK at boot, so the build constant is as good as the option bytes here
+    report_outcome(
+        eccr,
+        evaluate_ecc_error(eccr, DUAL_BANK, bank_size(), &TARGET),
+    )
+}
+
+/// Ends the search with `outcome` and reports it together with the FLASH_ECCR content it was derived from
+fn report_outcome(eccr: u32, outcome: Outcome) -> ! {
+    // This is also called from the fault handlers, which can't get the Corruptor from main
+    let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+
+    // Trace the faulting address and the ECC status for anyone listening on SWO
+    let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+    // Showing the result takes forever on purpose
+    disarm_systick_deadline(&mut core_peripherals.SYST);
+    itm_write_u32(&mut core_peripherals.ITM, 0, ecc_error_offset(eccr));
+    itm_write_u32(&mut core_peripherals.ITM, 0, eccr);
+    log_transition(Status::EccErrorDetected);
+    dump_timeline(&mut core_peripherals.ITM);
+
+    let mut regs = RtcBackupRegs::new(&peripherals.RTC);
+
+    // If the error was detected after the write of this boot, record how long that took
+    let write_started_at = WRITE_STARTED_AT.load(core::sync::atomic::Ordering::SeqCst);
+    if write_started_at != 0 {
+        let latency = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(write_started_at);
+        regs.set_detection_latency_cycles(latency);
+    }
+
+    regs.set_outcome(outcome);
+    show_outcome(&mut init_leds(), outcome);
+
+    // The search ends here, so report the result on a single wire too:
+    // a sync byte, the outcome, the last waiting time and the faulting address
+    // Sending takes about 10ms, the watchdog must not reset us before that
+    Watchdog::new(&peripherals.IWDG).reload();
+    // A pin the probe needs is refused, the report is then skipped instead of cutting the connection
+    if REPORT_PIN.make_output(&peripherals.RCC).is_ok() {
+        REPORT_PIN.set(true);
+        bitbang_report(REPORT_PIN, REPORT_SYNC_BYTE, BITBANG_BAUD_CYCLES);
+        bitbang_report(REPORT_PIN, outcome as u8, BITBANG_BAUD_CYCLES);
+        bitbang_report_u32(REPORT_PIN, regs.middle(), BITBANG_BAUD_CYCLES);
+        bitbang_report_u32(REPORT_PIN, ecc_error_offset(eccr), BITBANG_BAUD_CYCLES);
+    }
+
+    if outcome == Outcome::Hit {
+        // Keep statistics on which operation the reset interrupted to produce the corruption.
+        // A locked search just found the corruption of an earlier boot again, so it doesn't count
+        match regs.phase() {
+            Phase::Locked => {}
+            Phase::Erasing => regs.set_erase_race_hits(regs.erase_race_hits() + 1),
+            _ => regs.set_write_race_hits(regs.write_race_hits() + 1),
         }
 
-        loop {}
-    }};
+        // Lock the search, so the next boot only checks whether the corruption is still there
+        regs.set_phase(Phase::Locked);
+    } else {
+        // Clear the magic value - the next boot starts a new search
+        regs.set_magic(0);
+    }
+
+    if outcome == Outcome::Hit {
+        if cfg!(feature = "blink-result") {
+            // Blink the waiting time that produced the corruption, so it can be shared without a probe
+            blink_u32(Led::Green, regs.middle(), &peripherals.IWDG);
+        }
+        if cfg!(feature = "dry-run") {
+            // Pulse blue next to the solid green one, so a simulated hit can't be mistaken for a real one
+            heartbeat(Led::Blue, &peripherals.IWDG);
+        }
+        if cfg!(feature = "low-power-result") {
+            // Only flash the LED briefly, e.g. for a board on a battery that shows the result for days
+            pulse_led(
+                Led::Green,
+                LOW_POWER_ON_CYCLES,
+                LOW_POWER_OFF_CYCLES,
+                &peripherals.IWDG,
+            );
+        }
+        // We're done! Pulse the green LED, so it's visible that the board is still running
+        heartbeat(Led::Green, &peripherals.IWDG);
+    }
+
+    loop {
+        // Wait for the watchdog to reset us
+        cortex_m::asm::nop();
+    }
 }
 
 // Could reduce binary size by kind of just pointing these to the same function...
 // on the other hand, I don't care
 #[exception]
-unsafe fn HardFault(_: &cortex_m_rt::ExceptionFrame) -> ! {
+unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    // Only this handler gets the stacked registers, so remember where the fault came from
+    let peripherals = stm32l4r5::Peripherals::steal();
+    let core_peripherals = cortex_m::Peripherals::steal();
+    capture_fault_context(&peripherals.RTC, frame, &core_peripherals.SCB);
+
     bad_thing_happened!()
 }
 
 #[exception]
-unsafe fn NonMaskableInt() -> ! {
+unsafe fn NonMaskableInt() {
     // This should be the only thing getting called, as it's a non-maskable interrupt
-    bad_thing_happened!()
+    let peripherals = stm32l4r5::Peripherals::steal();
+    // ECC errors during checked reads are expected, so we just return to the reader
+    if flash::recover_checked_read_fault(&peripherals.FLASH) {
+        return;
+    }
+
+    // Other sources can raise an NMI too (e.g. the clock security system), see nmi_outcome
+    let eccr = peripherals.FLASH.eccr.read().bits();
+    // Like report_ecc_error, this uses the build constant instead of a Flash that could fail to construct
+    report_outcome(eccr, nmi_outcome(eccr, DUAL_BANK, bank_size(), &TARGET))
+}
+
+#[exception]
+unsafe fn SysTick() {
+    // An attempt took longer than ATTEMPT_DEADLINE_TICKS, something hung. The attempt didn't tell us anything
+    // about the timing, so retry it with the same range instead of moving it
+    let peripherals = stm32l4r5::Peripherals::steal();
+    RtcBackupRegs::new(&peripherals.RTC).set_phase(Phase::Fresh);
+    software_reset();
 }
 
 #[exception]
@@ -96,115 +314,650 @@ unsafe fn DefaultHandler(_irqn: i16) -> ! {
     bad_thing_happened!()
 }
 
-const STATE_BEFORE_WRITE: u32 = 1;
-const STATE_AFTER_WRITE: u32 = 2;
+/// Parses the decimal number build.rs passes in, `None` if it's empty
+const fn parse_decimal(value: &str) -> Option<u32> {
+    let digits = value.as_bytes();
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut parsed = 0u32;
+    let mut i = 0;
+    while i < digits.len() {
+        parsed = parsed * 10 + (digits[i] - b'0') as u32;
+        i += 1;
+    }
+    Some(parsed)
+}
+
+// How often each doubleword of the target range is read before we decide that it is not corrupted.
+// Cells near the threshold don't reliably produce an ECC error on every read.
+// This is only the default on first boot, backup register 5 can be changed to adjust it.
+const VERIFICATION_READS: u32 = 3;
+
+// Whether interrupts are masked (PRIMASK) from the waiting loop until the end of the write. Only the default on
+// first boot, backup register 19 can be changed to compare both modes. The NMI of an ECC error can't be masked.
+const MASK_INTERRUPTS: bool = false;
+
+// How many cycles after the watchdog reload the waiting loop starts at the earliest. The erase before it doesn't
+// always take the same time, so with a settle point after its longest duration, the waiting time is counted from
+// the same point in every attempt. Only the default on first boot, bits 8-31 of backup register 19 can be changed.
+// Zero starts the waiting loop right after the erase.
+const SETTLE_CYCLES: u32 = 0;
+
+// How long to sleep at the start of every attempt, in milliseconds. Only the default on first boot, backup
+// register 25 can be changed. Sleeping lets the chip cool down between the resets, which keeps the timing
+// more consistent over a long search. Zero disables it.
+const SLEEP_BETWEEN_ATTEMPTS_MS: u16 = 0;
+
+// Seed for the jitter that gets a stalled search going again. Only written to backup register 21 after a cold boot,
+// after any other reset a new search keeps the seed it has.
+// The same seed gives the same jumps, so a successful run can be reproduced.
+const JITTER_SEED: u32 = 0x1234_5678;
+// How often a stalled search may jump to a nearby time before we give up
+const MAX_JITTERS: u32 = 8;
+
+// Which operation the reset should interrupt. Only the default on first boot, backup register 30 can be changed
+// to compare the methods.
+const CORRUPTION_METHOD: CorruptionMethod = CorruptionMethod::InterruptWrite;
+
+// Where in the range the next waiting time is tried. Only the default on first boot, bits 8-15 of backup
+// register 30 can be changed to try another strategy without rebuilding.
+const SEARCH_STRATEGY: SearchStrategy = SearchStrategy::Bisect;
+
+// What a new search starts with, see init_or_resume
+const SEARCH_DEFAULTS: SearchDefaults = SearchDefaults {
+    verification_reads: VERIFICATION_READS,
+    mask_interrupts: MASK_INTERRUPTS,
+    settle_cycles: SETTLE_CYCLES,
+    sleep_ms: SLEEP_BETWEEN_ATTEMPTS_MS as u32,
+    method: CORRUPTION_METHOD,
+    strategy: SEARCH_STRATEGY,
+    jitter_seed: JITTER_SEED,
+};
+
+// How long a single attempt may take, from the end of the sleep until the watchdog reset (2s). The watchdog times
+// out after about 0.5s, so this only fires if something hung (see hw::arm_systick_deadline)
+const ATTEMPT_DEADLINE_TICKS: u32 = 2 * SYSTICK_HZ;
+
+// DWT cycle count at the start of the write (or erase, see CORRUPTION_METHOD) in this boot, zero before that.
+// Used for the detection latency
+static WRITE_STARTED_AT: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+
+// The backup register layout is documented in state::SearchState
+
+// Compares standard programming with fast programming by writing the whole target page with both,
+// the cycle counts end up in backup registers 16 and 17. Green and blue LEDs are on when done.
+fn benchmark_page_writes(corruptor: &Corruptor) -> ! {
+    let flash = corruptor.flash();
+    let page_size = flash.page_size() as usize;
+    let page_start = TARGET_ADDRESS & !(page_size - 1);
+    let page_number = flash.address_to_page_number(TARGET_ADDRESS as u32);
+
+    let mut flash_unlocked = flash.unlock().unwrap_flash();
+    let row = [0u64; mem::FAST_PROGRAMMING_ROW_DWORDS];
+    let rows = page_size / mem::FAST_PROGRAMMING_ROW_SIZE;
+
+    // Standard programming, waiting for BSY after every doubleword
+    flash_unlocked.erase_page(page_number).unwrap_flash();
+    let start = cortex_m::peripheral::DWT::cycle_count();
+    for i in 0..rows {
+        let address = (page_start + i * mem::FAST_PROGRAMMING_ROW_SIZE) as *mut usize;
+        flash_unlocked.write_dwords(address, &row).unwrap_flash();
+    }
+    let standard_cycles = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start);
+
+    // Fast programming, one BSY wait per row
+    flash_unlocked.erase_page(page_number).unwrap_flash();
+    let start = cortex_m::peripheral::DWT::cycle_count();
+    for i in 0..rows {
+        let address = (page_start + i * mem::FAST_PROGRAMMING_ROW_SIZE) as *mut usize;
+        flash_unlocked.write_fast_row(address, &row).unwrap_flash();
+    }
+    let fast_cycles = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start);
 
-const MAGIC_VALUE: u32 = 0x99999999;
+    let mut regs = corruptor.search();
+    regs.set_bench_standard_cycles(standard_cycles);
+    regs.set_bench_fast_cycles(fast_cycles);
 
-// Backup register use:
-// 0: Magic value to detect first boot
-// 1: Bottom of the waiting range (for binary search)
-// 2: Top of the waiting range
-// 3: State we are currently in (allows us to detect if last reset was before or after write)
-// 4: Reset counter
+    BoardLeds.set(Led::Green, true);
+    BoardLeds.set(Led::Blue, true);
+    loop {
+        // The watchdog isn't running yet, so we stay here
+        cortex_m::asm::nop();
+    }
+}
+
+// How long compare_first_reads waits between the first and the second read (100ms)
+const READ_COMPARE_DELAY_CYCLES: u32 = SYSTEM_CLOCK_HZ / 10;
+
+// Checks whether the first read of a corruption behaves differently than a later one regarding ECC (`read-compare`
+// feature). Only a reset that interrupts a write or erase corrupts the flash, so this runs at boot, before anything
+// else reads the target: the first read is then the first one since the corruption. The target is read right away
+// and again after READ_COMPARE_DELAY_CYCLES, and whether each read reported an ECC error is sent over ITM port 8
+// (bit 0 for the first read, bit 1 for the second). The verification reads after it decide the attempt as usual,
+// so a host collects one sample per boot.
+fn compare_first_reads(flash: &Flash, itm: &mut cortex_m::peripheral::ITM) {
+    // read_range_faults flushes the data cache, so both reads come from the flash itself
+    let first = flash.read_range_faults(TARGET.start, TARGET.len);
+    cortex_m::asm::delay(READ_COMPARE_DELAY_CYCLES);
+    let second = flash.read_range_faults(TARGET.start, TARGET.len);
+
+    itm_write_u32(itm, READ_COMPARE_PORT, first as u32 | (second as u32) << 1);
+}
+
+// Erases the pages of the target and writes it, the same as an attempt does, but without the watchdog that
+// interrupts it. Only an interrupted write or erase can corrupt anything, so this shows the baseline of the search:
+// anything but a fault without ECC error (red and blue LEDs) means the target is already broken. The result is
+// reported like at the end of a search. The watchdog is never started, so nothing resets the board afterwards,
+// report_ecc_error and the fault handlers only reload the watchdog, which doesn't start it.
+fn corrupt_once(flash: &Flash) -> ! {
+    let mut flash_unlocked = flash.unlock().unwrap_flash();
+    erase_and_write_target(&mut flash_unlocked, target_pages(flash));
+    drop(flash_unlocked);
+
+    let eccr = if flash.read_range_faults(TARGET.start, TARGET.len) {
+        flash::captured_ecc_error()
+    } else {
+        // Nothing latched, so this is reported as a fault without ECC error
+        0
+    };
+    report_ecc_error(eccr)
+}
+
+// The pages the write of the target touches. The write might cross a page boundary, then all of them must be erased
+fn target_pages(flash: &Flash) -> core::ops::Range<u32> {
+    let (first_page, last_page) = flash.pages_spanned(
+        TARGET_ADDRESS as u32,
+        (CORRUPT_DWORDS * Flash::write_granularity()) as u32,
+    );
+    first_page..last_page + 1
+}
+
+// Erases `pages` and writes CORRUPT_PATTERN to the target, without any timing. Attempts that interrupt the erase
+// use this to put data on the page before the timing starts
+fn erase_and_write_target(flash_unlocked: &mut FlashUnlocked, pages: core::ops::Range<u32>) {
+    log_transition(Status::Erasing);
+    flash_unlocked.erase_page_range(pages).unwrap_flash();
+    log_transition(Status::Writing);
+    flash_unlocked
+        .write_dwords(
+            TARGET_ADDRESS as *mut usize,
+            &[CORRUPT_PATTERN; CORRUPT_DWORDS],
+        )
+        .unwrap_flash();
+}
+
+// The progress of sweep_bank. It is in RAM that isn't initialized at boot, so it survives the watchdog resets
+// between the pages
+#[link_section = ".uninit.SWEEP_PROGRESS"]
+static mut SWEEP_PROGRESS: core::mem::MaybeUninit<sweep::SweepProgress> =
+    core::mem::MaybeUninit::uninit();
+
+// Writes the first doublewords of every page in the bank with the timing of the locked search, and reads them back
+// after the watchdog reset interrupted the write, one page per boot (see the sweep module). Pages holding the
+// firmware and write protected pages are skipped. Once all pages are done, the tested and the detected page maps
+// are sent over ITM port 3, 8 words each. Green LED if every tested page reported the ECC error, red if not.
+// The progress is only lost with the power, so a reset after the sweep just shows the result again.
+fn sweep_bank(
+    corruptor: &Corruptor,
+    regs: &impl BackupRegs,
+    itm: &mut cortex_m::peripheral::ITM,
+) -> ! {
+    // Without a timing that corrupts the target, there is nothing to sweep with
+    assert!(
+        regs.phase() == Phase::Locked && regs.method() == CorruptionMethod::InterruptWrite,
+        "the sweep needs a locked search that interrupts the write"
+    );
+
+    let stored = core::ptr::addr_of_mut!(SWEEP_PROGRESS).cast::<sweep::SweepProgress>();
+    // Every bit pattern is a SweepProgress, is_valid tells whether this one was stored by the sweep
+    let mut progress = unsafe { stored.read_volatile() };
+    if !progress.is_valid() {
+        progress = sweep::SweepProgress::new();
+    }
+
+    // In single-bank mode, the pages of the bank are all pages
+    let flash = corruptor.flash();
+    let page_start = |page_number: u32| mem::FLASH_BASE + page_number * flash.page_size();
+    let mut flash_unlocked = flash.unlock().unwrap_flash();
+    if let Some(page_number) = progress.armed_page() {
+        let written = (CORRUPT_DWORDS * Flash::write_granularity()) as u32;
+        progress.record(flash.read_range_faults(page_start(page_number), written));
+        unsafe { stored.write_volatile(progress) };
+
+        // Don't leave the bank full of corruptions
+        flash_unlocked.erase_page(page_number).unwrap_flash();
+    }
+
+    let firmware_end = mem::firmware_end();
+    let next = progress.next_page(mem::pages_per_bank(flash), |page_number| {
+        let write_protected = flash.is_write_protected(page_number);
+        sweep::classify_page(page_start(page_number), firmware_end, write_protected)
+    });
+    if let Some(page_number) = next {
+        progress.arm(page_number);
+        unsafe { stored.write_volatile(progress) };
+        interrupted_write(corruptor, &mut flash_unlocked, page_number, regs);
+    }
+    drop(flash_unlocked);
+
+    let result = progress.result();
+    for word in result.tested.0.iter().chain(result.detected.0.iter()) {
+        itm_write_u32(itm, SWEEP_MAP_PORT, *word);
+    }
+
+    BoardLeds.set_rgb(!result.passed(), result.passed(), false);
+    loop {
+        // The watchdog isn't running after the last reset, so we stay here
+        cortex_m::asm::nop();
+    }
+}
+
+// Writes CORRUPT_PATTERN to the first doublewords of `page_number` the same way an attempt that interrupts the
+// write does (see main): the watchdog is started, the page erased, and the write starts the waiting time of the
+// search (regs.middle) after its settle point. The watchdog reset is meant to cut the write short
+fn interrupted_write(
+    corruptor: &Corruptor,
+    flash_unlocked: &mut FlashUnlocked,
+    page_number: u32,
+    regs: &impl BackupRegs,
+) -> ! {
+    let middle = regs.middle();
+    let settle_cycles = regs.settle_cycles();
+    let address = (mem::FLASH_BASE + page_number * flash_unlocked.page_size()) as *mut usize;
+    let run_from_ram = flash_unlocked.shares_bank_with_code(page_number);
+
+    activate_watchdog(&corruptor.iwdg).unwrap();
+    let watchdog_reloaded_at = cortex_m::peripheral::DWT::cycle_count();
+    flash_unlocked
+        .erase_page_range(page_number..page_number + 1)
+        .unwrap_flash();
+    corruptor.watchdog().partial_tickle();
+    while cortex_m::peripheral::DWT::cycle_count().wrapping_sub(watchdog_reloaded_at)
+        < settle_cycles
+    {}
+
+    let mut corruption_window = || {
+        for _ in 0..middle {
+            core::hint::black_box(0);
+        }
+        let pattern = [CORRUPT_PATTERN; CORRUPT_DWORDS];
+        if run_from_ram {
+            flash_unlocked.write_dwords_from_ram(address, &pattern)
+        } else {
+            flash_unlocked.write_dwords(address, &pattern)
+        }
+        .unwrap_flash()
+    };
+    if regs.masks_interrupts() {
+        cortex_m::interrupt::free(|_| corruption_window());
+    } else {
+        corruption_window();
+    }
+
+    loop {
+        // The write completed before the reset, which comes anyway
+        cortex_m::asm::nop();
+    }
+}
+
+// Stops before the search touches anything and shows why, for outcomes that a search can't continue from.
+// The watchdog must not be running yet, nothing resets the board then
+fn halt_with(regs: &mut impl BackupRegs, leds: &mut impl LedSink, outcome: Outcome) -> ! {
+    regs.set_outcome(outcome);
+    show_outcome(leds, outcome);
+
+    loop {
+        // The watchdog isn't running yet, so we stay here
+        cortex_m::asm::nop();
+    }
+}
+
+// Executes a command from the host. This runs before the search state is read, so changing the
+// backup registers here affects this boot already
+fn run_command(corruptor: &Corruptor, itm: &mut cortex_m::peripheral::ITM, command: Command) {
+    let mut regs = corruptor.search();
+    match command {
+        // Without the magic value, the search starts from scratch
+        Command::Restart => regs.set_magic(0),
+        Command::Reseed(seed) => {
+            regs.set_jitter_seed(seed);
+            regs.set_magic(0);
+        }
+        Command::SetVerificationReads(reads) => regs.set_verification_reads(reads),
+        Command::Replay => {
+            // A range around the last waiting time has it as its middle, and a fresh phase keeps the range
+            let middle = regs.middle();
+            let half_range = TimingSearch::MIN_RANGE;
+            regs.set_search(TimingSearch {
+                bottom: middle.saturating_sub(half_range),
+                top: middle + half_range,
+            });
+            regs.set_phase(Phase::Fresh);
+        }
+        Command::DumpState => {
+            for value in regs.raw() {
+                itm_write_u32(itm, STATE_DUMP_PORT, value);
+            }
+        }
+        // Commands run before the watchdog is started, so it can't interrupt the bootloader
+        Command::EnterBootloader => jump_to_bootloader(&corruptor.rcc, &corruptor.syscfg),
+    }
+}
 
 #[entry]
 fn main() -> ! {
-    let peripherals = unsafe { stm32l4r5::Peripherals::steal() };
+    // The only place the peripherals are taken, everything below borrows them from here
+    let corruptor = Corruptor::take();
+    let mut core_peripherals = cortex_m::Peripherals::take().unwrap();
     // For backup register access
-    hw::enable_rtc(&peripherals.RCC, &peripherals.RTC, &peripherals.PWR);
+    hw::enable_rtc(&corruptor.rcc, &corruptor.rtc, &corruptor.pwr);
+    // For the timestamps of the timeline. The LSI is also what sleeping needs, an already selected LSE is kept
+    let rtc_clock = match hw::select_rtc_clock(&corruptor.rcc, RtcClock::Lsi) {
+        Ok(clock) => clock,
+        Err(()) => halt_with(
+            &mut corruptor.search(),
+            &mut corruptor.leds(),
+            Outcome::ClockFailure,
+        ),
+    };
+    hw::init_rtc_calendar(&corruptor.rtc, rtc_clock);
+    // For estimating the time until the watchdog fires
+    hw::enable_cycle_counter(&mut core_peripherals.DCB, &mut core_peripherals.DWT);
+    hw::mark_boot();
+    log_transition(Status::Booted);
+    // For tracing ECC faults over SWO
+    hw::enable_itm(
+        &corruptor.dbgmcu,
+        &mut core_peripherals.DCB,
+        &core_peripherals.TPIU,
+        &mut core_peripherals.ITM,
+    );
+    hw::log_device_info(&mut core_peripherals.ITM);
+
+    let mut regs = corruptor.search();
+    let flash = corruptor.flash();
+    let mut leds = corruptor.leds();
+
+    // The bank mode below comes from the option bytes, so they must have been loaded correctly
+    flash.option_bytes_status().unwrap_flash();
+
+    // With the wrong bank mode, we would erase and corrupt the wrong pages.
+    // Signal this with all LEDs on and stop before touching anything.
+    if flash.is_dualbank() != DUAL_BANK {
+        halt_with(&mut regs, &mut leds, Outcome::BankModeMismatch);
+    }
 
-    // Basically detect the first boot and set the top/bottom of the range
-    let magic_val = peripherals.RTC.bkpr[0].read().bits();
-    if magic_val != MAGIC_VALUE {
-        // Note that we're no longer in the first boot
-        peripherals.RTC.bkpr[0].write(|w| unsafe { w.bits(MAGIC_VALUE) });
+    // Erasing the target page must not erase this tool. memory.x checks this as well, but only for this build's
+    // bank mode: the page size comes from the option bytes here
+    let target_page_start = TARGET_ADDRESS as u32 & !(flash.page_size() - 1);
+    assert!(
+        target_page_start >= mem::firmware_end(),
+        "the target page holds the firmware"
+    );
+
+    if cfg!(feature = "self-test") {
+        // The last page is as far away from the firmware and the target as it gets
+        let scratch_page = mem::page_count(flash) - 1;
+        let result = flash
+            .unlock()
+            .and_then(|mut flash_unlocked| flash_unlocked.self_test(scratch_page));
+        if let Err(error) = result {
+            // Blink the error code, so a board problem can't be mistaken for a failing search
+            blink_u32(Led::Red, error.as_u32(), &corruptor.iwdg);
+        }
+    }
 
-        // Register 1 and 2 store the bottom and top of the range
-        peripherals.RTC.bkpr[1].write(|w| unsafe { w.bits(100) });
-        // In my tests, usually a value of just below ~400k is fine, but it's a bit random
-        peripherals.RTC.bkpr[2].write(|w| unsafe { w.bits(1_000_000) });
-        peripherals.RTC.bkpr[3].write(|w| unsafe { w.bits(0) });
+    if cfg!(feature = "bench-write") {
+        benchmark_page_writes(&corruptor);
+    }
+    if cfg!(feature = "bank-sweep") {
+        sweep_bank(&corruptor, &regs, &mut core_peripherals.ITM);
+    }
+    if cfg!(feature = "corrupt-once") {
+        corrupt_once(flash);
     }
 
+    // The reset flags accumulate, so they are cleared to only see the reason of the next reset
+    let boot = hw::take_boot_kind(&corruptor.rcc);
+
+    // A host can leave a command for us, it is only executed once
+    let command = regs.command();
+    regs.set_command(0);
+    if let Some(command) = Command::decode(command) {
+        run_command(&corruptor, &mut core_peripherals.ITM, command);
+    }
+
+    // Basically detect the first boot and set the top/bottom of the range
+    let phase = init_or_resume(&mut regs, boot, &SEARCH_DEFAULTS);
+
     // This is a reset counter, which is interesting when debugging
-    peripherals.RTC.bkpr[4].modify(|r, w| unsafe { w.bits(r.bits() + 1) });
+    regs.set_reset_count(regs.reset_count() + 1);
 
-    let mut bottom = peripherals.RTC.bkpr[1].read().bits();
-    let mut top = peripherals.RTC.bkpr[2].read().bits();
-    let mut middle = (bottom + top) / 2;
+    let mut search = regs.search();
+    let strategy = regs.strategy();
+    // How far the last attempt got, which narrows the range below
+    let mut last = phase;
 
     // If we are very close, we have likely missed the exact time and need to try again
-    let very_similar = top - bottom < 5;
-    assert!(!very_similar);
+    // A locked search is allowed to be close, it already found the time
+    if phase != Phase::Locked && search.is_stalled() {
+        // A dry run can't corrupt anything, so the converged search is as close to the real thing as it gets
+        if cfg!(feature = "dry-run") {
+            report_ecc_error(dry_run_eccr());
+        }
+
+        // Jump to a nearby time instead of giving up right away, the board might just have been flaky
+        let jitters = regs.jitter_count();
+        assert!(jitters < MAX_JITTERS);
 
-    let state = peripherals.RTC.bkpr[3].read().bits();
+        let mut rng = regs.jitter_state();
+        search.jitter(&mut rng);
+        regs.set_jitter_state(rng);
+        regs.set_jitter_count(jitters + 1);
 
-    if state == STATE_BEFORE_WRITE {
-        // Apparently we run too long before the reset, so we need to go down
-        top = middle;
-        peripherals.RTC.bkpr[2].write(|w| unsafe { w.bits(top) });
-    } else if state == STATE_AFTER_WRITE {
-        // Apparently reset too late, so go up a bit
-        bottom = middle;
-        peripherals.RTC.bkpr[1].write(|w| unsafe { w.bits(bottom) });
+        // The last attempt tried a time of the stalled range, so it says nothing about the new one
+        last = Phase::Fresh;
     }
 
     // We basically do a binary search over multiple resets to find the right time to corrupt
-    middle = (bottom + top) / 2;
+    let (advanced, mut middle) = strategy.next(search, last);
+    search = advanced;
+    regs.set_search(search);
 
-    peripherals.RTC.bkpr[3].write(|w| unsafe { w.bits(STATE_BEFORE_WRITE) });
+    show_outcome(&mut leds, Outcome::Searching);
 
-    set_green_led(false);
-    set_red_led(false);
-    set_blue_led(false);
-
-    // First of all, read all of the data to see if we get an interrupt
+    // First of all, read all of the data to see if we get an ECC error
     // If yes, we are already in a corrupted state - nice!
-    for i in 0..CORRUPT_RANGE {
-        let addr = (APPROXIMATE_ADDRESS_TO_CORRUPT as usize) + i;
-
-        let data = unsafe { core::ptr::read_volatile(addr as *const u8) };
-
-        core::hint::black_box(data);
+    // The phase register still tells how far the last attempt got, report_ecc_error uses that
+    // Read at least once, even if the backup register was set to zero
+    let verification_reads = regs.verification_reads().max(1);
+    if cfg!(feature = "read-compare") {
+        compare_first_reads(flash, &mut core_peripherals.ITM);
+    }
+    for _ in 0..verification_reads {
+        // The checked reads clear the ECC error again, so the next read starts clean
+        if flash.read_range_faults(TARGET.start, TARGET.len) {
+            report_ecc_error(flash::captured_ecc_error());
+        }
     }
 
     // If we reach this, there was no corruption in the aimed area
-    let mut flash = Flash::new(peripherals.FLASH);
-    let page_number = flash.address_to_page_number(APPROXIMATE_ADDRESS_TO_CORRUPT as u32);
+    if phase == Phase::Locked {
+        // The corruption we found earlier is gone, so we have to search again
+        search = TimingSearch::INITIAL;
+        regs.set_search(search);
+        middle = strategy.probe(&search);
+    }
 
-    // We use the watchdog to time the corruption 
-    activate_watchdog(&peripherals.IWDG).unwrap();
+    // Publish the attempt, so a host can follow the search
+    regs.set_middle(middle);
+    regs.set_detection_latency_cycles(0);
+    regs.set_outcome(Outcome::Searching);
 
-    // First of all, we erase the page, as otherwise we can't write to it
-    let mut flash_unlocked = flash.unlock().unwrap();
-    flash_unlocked.erase_page(page_number).unwrap();
+    let pages = target_pages(flash);
 
-    // After this, we have 0.125ms until we have to be within a write
-    watchdog_feed_min(&peripherals.IWDG);
+    // Let the chip cool down from the last attempt. The watchdog isn't running yet, so we can sleep as long as we want
+    let sleep_duration = regs.sleep_ms().min(u16::MAX as u32) as u16;
+    if sleep_duration != 0 {
+        if enable_rtc_clock(&corruptor.rcc).is_err() {
+            halt_with(&mut regs, &mut leds, Outcome::ClockFailure);
+        }
+        sleep_ms(&corruptor.rtc, &corruptor.exti, sleep_duration);
+    }
+
+    // From here on, the attempt must end with a reset within the deadline, no matter what hangs
+    arm_systick_deadline(&mut core_peripherals.SYST, ATTEMPT_DEADLINE_TICKS);
 
-    // This gets us towards the time window...
-    // Also this definitely isn't exactly cycles, but it does not really matter which unit of time we use
-    for _ in 0..middle {
-        core::hint::black_box(0);
+    let method = regs.method();
+    let run_from_ram = flash.shares_bank_with_code(pages.start);
+    let mut flash_unlocked = unlock_or_reset(flash, &mut regs);
+    if method == CorruptionMethod::InterruptErase {
+        // An interrupted erase can only leave something behind if there is data on the page,
+        // so write the target before the timing starts
+        erase_and_write_target(&mut flash_unlocked, pages.clone());
     }
 
-    // Now we write to actually corrupt the flash.
-    // We basically hope that the watchdog setup was timed perfectly, so that we are in a phase of 
-    // flash writing where power must not be cut, and then we cut it
-    flash_unlocked
-        .write_dwords(
-            APPROXIMATE_ADDRESS_TO_CORRUPT as *mut usize,
-            // We write zero, because the flash page is all 0xff after erase 
-            &[0u64; CORRUPT_RANGE / core::mem::size_of::<u64>() + 1],
-        )
-        .unwrap();
+    // We use the watchdog to time the corruption
+    activate_watchdog(&corruptor.iwdg).unwrap();
+    // activate_watchdog reloads the counter at the very end
+    let watchdog_reloaded_at = cortex_m::peripheral::DWT::cycle_count();
+    log_transition(Status::WatchdogStarted);
+
+    if method == CorruptionMethod::InterruptWrite {
+        // First of all, we erase the page(s), as otherwise we can't write to it
+        // If the reset happens during the erase, any corruption comes from the erase and not the write
+        regs.set_phase(Phase::Erasing);
+        log_transition(Status::Erasing);
+        flash_unlocked
+            .erase_page_range(pages.clone())
+            .unwrap_flash();
+    }
+    regs.set_phase(Phase::BeforeWrite);
+
+    // Lock the watchdog registers again. This doesn't reload the counter, the reset is still timed from
+    // the reload at the end of activate_watchdog
+    corruptor.watchdog().partial_tickle();
+
+    // Absorb the variance of the erase, so that the waiting loop starts at the same time after the reload
+    let settle_cycles = regs.settle_cycles();
+    while cortex_m::peripheral::DWT::cycle_count().wrapping_sub(watchdog_reloaded_at)
+        < settle_cycles
+    {}
+
+    // Read before the corruption window borrows the registers
+    let mask_interrupts = regs.masks_interrupts();
+    let mut reset_to_write_cycles = 0;
+    let mut corruption_window = || {
+        // This gets us towards the time window...
+        // Also this definitely isn't exactly cycles, but it does not really matter which unit of time we use
+        let wait_started_at = cortex_m::peripheral::DWT::cycle_count();
+        for _ in 0..middle {
+            core::hint::black_box(0);
+        }
+        let wait_cycles = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(wait_started_at);
+
+        // Record how close we are to the reset, this helps judging the search later on
+        let remaining = watchdog_remaining(watchdog_reloaded_at);
+        regs.set_watchdog_remaining_us(remaining);
+
+        WRITE_STARTED_AT.store(
+            cortex_m::peripheral::DWT::cycle_count(),
+            core::sync::atomic::Ordering::SeqCst,
+        );
+        // Only stored here, sending it right away would delay the write
+        reset_to_write_cycles = measure_reset_to_write_latency(wait_cycles);
+        match method {
+            CorruptionMethod::InterruptWrite => log_transition(Status::Writing),
+            CorruptionMethod::InterruptErase => log_transition(Status::Erasing),
+        }
+        match method {
+            // Now we write to actually corrupt the flash.
+            // We basically hope that the watchdog setup was timed perfectly, so that we are in a phase of
+            // flash writing where power must not be cut, and then we cut it
+            // The flash page is all 0xff after erase, so only the bits of CORRUPT_BIT_MASK are flipped
+            CorruptionMethod::InterruptWrite => {
+                let pattern = [CORRUPT_PATTERN; CORRUPT_DWORDS];
+                // Running from the bank we write would stall the core, so the write runs from RAM then
+                if run_from_ram {
+                    flash_unlocked.write_dwords_from_ram(TARGET_ADDRESS as *mut usize, &pattern)
+                } else {
+                    flash_unlocked.write_dwords(TARGET_ADDRESS as *mut usize, &pattern)
+                }
+                .unwrap_flash()
+            }
+            // The same, but we hope to cut the power while the page is only partially erased
+            CorruptionMethod::InterruptErase => {
+                regs.set_phase(Phase::Erasing);
+                // Like the write, the erase runs from RAM if it would stall the core otherwise
+                if run_from_ram {
+                    flash_unlocked.erase_page_range_from_ram(pages.clone())
+                } else {
+                    flash_unlocked.erase_page_range(pages.clone())
+                }
+                .unwrap_flash();
+            }
+        }
+    };
+
+    // Comparing both modes separates the behavior of the ECC hardware from interrupt timing effects
+    if mask_interrupts {
+        cortex_m::interrupt::free(|_| corruption_window());
+    } else {
+        corruption_window();
+    }
+    log_transition(Status::Completed);
+
+    if cfg!(feature = "measure-ops") {
+        let (erase_cycles, program_cycles) = flash::last_operation_cycles();
+        itm_write_u32(
+            &mut core_peripherals.ITM,
+            OPERATION_TIMING_PORT,
+            erase_cycles,
+        );
+        itm_write_u32(
+            &mut core_peripherals.ITM,
+            OPERATION_TIMING_PORT,
+            program_cycles,
+        );
+    }
+
+    // Only attempts that weren't interrupted by the reset get here, but the path up to the write is the same for all
+    itm_write_u32(
+        &mut core_peripherals.ITM,
+        LATENCY_PORT,
+        reset_to_write_cycles,
+    );
+
+    // Leftover error flags point to an operation that was interrupted earlier in this boot
+    regs.set_stale_programming_flags(flash::stale_programming_flags().0);
+
+    // The write (or erase) completed, but it might still have produced a corruption - check right away,
+    // so we don't need another reset to find out
+    if flash.read_range_faults(TARGET.start, TARGET.len) {
+        report_ecc_error(flash::captured_ecc_error());
+    }
 
     // If we reached this, we clearly didn't snipe early enough - after the next reset, we go lower
-    peripherals.RTC.bkpr[3].write(|w| unsafe { w.bits(STATE_AFTER_WRITE) });
-    set_blue_led(true);
+    regs.set_phase(Phase::AfterWrite);
+    leds.set(Led::Blue, true);
+
+    // With the button, the next attempt only starts once it is pressed, so the search can be single-stepped
+    #[cfg(feature = "button")]
+    {
+        // Waiting for the button can take as long as it wants
+        disarm_systick_deadline(&mut core_peripherals.SYST);
+        init_button(&corruptor.rcc, &corruptor.gpioc);
+        while !button_pressed() {
+            corruptor.watchdog().reload();
+        }
+        software_reset();
+    }
 
+    #[allow(unreachable_code)]
     loop {
         // Wait for the watchdog to reset us
+        cortex_m::asm::nop();
     }
 }
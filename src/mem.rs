@@ -0,0 +1,98 @@
+//! Geometry of the flash memory of the STM32L4R5, see "3.3.1 Flash memory organization" in the reference manual.
+//!
+//! Some values depend on the bank mode and the size of the device (1MB or 2MB), so they take the [Flash]. They only
+//! read these from it, the arithmetic is in [geometry], where it is tested on the host.
+
+use core::ops::Range;
+
+use stm32_flash_corruptor::geometry;
+
+use crate::flash::Flash;
+
+pub use stm32_flash_corruptor::geometry::{TargetRange, FLASH_BASE};
+
+/// Address of the flash size register, a 16-bit value with the size of the flash memory in KB
+pub const FLASH_SIZE_REGISTER: u32 = 0x1FFF_75E0;
+
+/// Start of the system memory with the built-in bootloader (USB DFU, UART, ...), which starts with its vector table
+pub const SYSTEM_MEMORY_BASE: u32 = 0x1FFF_0000;
+
+/// Address of the 96-bit unique device ID, three 32-bit words
+pub const UID_REGISTER: u32 = 0x1FFF_7590;
+
+/// Number of doublewords in a row, the unit of fast programming
+pub const FAST_PROGRAMMING_ROW_DWORDS: usize = 32;
+/// Size of a row in bytes (256)
+pub const FAST_PROGRAMMING_ROW_SIZE: usize =
+    FAST_PROGRAMMING_ROW_DWORDS * core::mem::size_of::<u64>();
+
+/// Size of the main flash memory in bytes, as reported by the flash size register
+pub fn flash_size(flash: &Flash) -> u32 {
+    flash.size_kb() as u32 * 1024
+}
+
+/// Number of banks in the current mode
+pub fn bank_count(flash: &Flash) -> u32 {
+    if flash.is_dualbank() {
+        2
+    } else {
+        1
+    }
+}
+
+/// Page size in the current mode, see [geometry::page_size_for]
+pub fn page_size(flash: &Flash) -> u32 {
+    geometry::page_size_for(flash.is_dualbank())
+}
+
+/// Number of pages over all banks in the current mode, see [Flash::page_count]
+pub fn page_count(flash: &Flash) -> u32 {
+    flash.page_count()
+}
+
+/// Number of pages in each bank in the current mode
+pub fn pages_per_bank(flash: &Flash) -> u32 {
+    page_count(flash) / bank_count(flash)
+}
+
+/// Address range of the main flash memory
+#[allow(dead_code)]
+pub fn flash_range(flash: &Flash) -> Range<u32> {
+    FLASH_BASE..FLASH_BASE + flash_size(flash)
+}
+
+/// First absolute flash address after this firmware (code, read-only data and the initial values of `.data`).
+/// The pages below it must never be erased.
+pub fn firmware_end() -> u32 {
+    // Symbols of the cortex-m-rt linker script: the initial values of `.data` are the last thing in flash
+    extern "C" {
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+    let end = unsafe {
+        let data_len = &__edata as *const u32 as u32 - &__sdata as *const u32 as u32;
+        &__sidata as *const u32 as u32 + data_len
+    };
+
+    // memory.x might link the firmware at the alias at address 0
+    if end < FLASH_BASE {
+        FLASH_BASE + end
+    } else {
+        end
+    }
+}
+
+/// Address range of the one-time programmable (OTP) area, 1KB
+#[allow(dead_code)]
+pub const fn otp_range() -> Range<u32> {
+    0x1FFF_7000..0x1FFF_7400
+}
+
+/// Address range of the option bytes of the given bank (0 or 1).
+/// Only bank 0 exists in single-bank mode, but the option bytes of both banks are always there.
+#[allow(dead_code)]
+pub const fn option_bytes_range(bank: u32) -> Range<u32> {
+    let start = 0x1FF0_0000 + bank * 0x1000;
+    start..start + 0x28
+}
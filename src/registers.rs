@@ -0,0 +1,546 @@
+//! Decoding of the flash interface registers of the STM32L4R5 (FLASH_SR, FLASH_CR and FLASH_ECCR) and the errors
+//! they report.
+//!
+//! Nothing here accesses the hardware: the flash driver of the firmware reads a register and passes the value in,
+//! so the decoding can be tested on the host and reused for values that were captured earlier.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Unlocking the flash failed. This should never happen and requires a reset to escape from
+    UnlockFailed = 0b1,
+    /// The flash is currently busy. This should go away after some time or indicates a timeout.
+    Busy = 0b10,
+    /// Flash programming failed due to an software implementation error.
+    Illegal = 0b11,
+    /// The given page number does not exist in the current bank mode.
+    InvalidPage = 0b100,
+    /// A checked read hit a double-bit ECC error, so the returned data would have been garbage.
+    Corrupted = 0b101,
+    /// The flash size register reports a size this driver doesn't know the dual-bank bit for.
+    UnsupportedDevice = 0b110,
+    /// A write did not have the expected size (SIZERR), e.g. a byte write during doubleword programming.
+    SizeMismatch = 0b111,
+    /// The option bytes could not be loaded (OPTVERR), they are in an inconsistent state
+    OptionBytesInvalid = 0b1000,
+    /// A doubleword read back after programming doesn't contain what was written
+    VerifyMismatch = 0b1001,
+    /// A write targets a page that wasn't erased with the same `FlashUnlocked` (see `FlashUnlocked::write_dwords` in
+    /// the firmware), or the flash reported that the doubleword wasn't erased (PROGERR), e.g. because the erase
+    /// silently failed
+    NotErased = 0b1010,
+}
+
+impl Error {
+    /// FLASH_SR bits 4, 5, 7, 8 and 9: WRPERR, PGAERR, PGSERR, MISERR and FASTERR
+    pub const SR_ILLEGAL_MASK: u32 = 0b11_1011_0000;
+    /// FLASH_SR bit 3
+    pub const SR_PROGERR: u32 = 1 << 3;
+    /// FLASH_SR bit 6
+    pub const SR_SIZERR: u32 = 1 << 6;
+    /// FLASH_SR bit 0, only set with EOPIE
+    pub const SR_EOP: u32 = 1 << 0;
+    /// FLASH_SR bit 16
+    pub const SR_BSY: u32 = 1 << 16;
+    /// Every FLASH_SR flag that makes the ongoing operation fail
+    pub const SR_ERROR_MASK: u32 = Self::SR_ILLEGAL_MASK | Self::SR_PROGERR | Self::SR_SIZERR;
+
+    /// The discriminant of the error, which is never zero. It fits into 8 bits, so it can be stored
+    /// next to other values, e.g. in a backup register.
+    pub fn as_u32(self) -> u32 {
+        self as u32
+    }
+
+    /// Decodes a value from [Error::as_u32], `None` for values that aren't an error (e.g. zero)
+    pub fn from_u32(value: u32) -> Option<Error> {
+        Some(match value {
+            0b1 => Error::UnlockFailed,
+            0b10 => Error::Busy,
+            0b11 => Error::Illegal,
+            0b100 => Error::InvalidPage,
+            0b101 => Error::Corrupted,
+            0b110 => Error::UnsupportedDevice,
+            0b111 => Error::SizeMismatch,
+            0b1000 => Error::OptionBytesInvalid,
+            0b1001 => Error::VerifyMismatch,
+            0b1010 => Error::NotErased,
+            _ => return None,
+        })
+    }
+
+    /// Decodes a FLASH_SR value, e.g. one that was captured earlier.
+    /// A busy flash takes precedence, then a size mismatch, then a doubleword that wasn't erased,
+    /// then the other programming errors.
+    pub fn from_status_bits(sr: u32) -> Result<(), Error> {
+        if sr & Self::SR_BSY != 0 {
+            Err(Error::Busy)
+        } else if sr & Self::SR_SIZERR != 0 {
+            // write_dwords does two 32-bit writes per doubleword, anything else sets this
+            Err(Error::SizeMismatch)
+        } else if sr & Self::SR_PROGERR != 0 {
+            // The write had no effect: the target still contained data, so the erase before it didn't happen.
+            // Note that writing all zeros never sets PROGERR, so that case can't be told apart from a success
+            Err(Error::NotErased)
+        } else if sr & Self::SR_ILLEGAL_MASK != 0 {
+            Err(Error::Illegal)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A snapshot of the programming error flags in FLASH_SR, as returned by `FlashUnlocked::clear_programming_flags`
+/// in the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgrammingFlags(pub u32);
+
+impl ProgrammingFlags {
+    /// Programming error (bit 3)
+    pub const PROGERR: u32 = 1 << 3;
+    /// Write protection error (bit 4)
+    pub const WRPERR: u32 = 1 << 4;
+    /// Programming alignment error (bit 5)
+    pub const PGAERR: u32 = 1 << 5;
+    /// Size error (bit 6)
+    pub const SIZERR: u32 = 1 << 6;
+    /// Programming sequence error (bit 7)
+    pub const PGSERR: u32 = 1 << 7;
+    /// Fast programming data miss error (bit 8)
+    pub const MISERR: u32 = 1 << 8;
+    /// Fast programming error (bit 9)
+    pub const FASTERR: u32 = 1 << 9;
+    /// All of the flags above
+    pub const ALL: u32 = Self::PROGERR
+        | Self::WRPERR
+        | Self::PGAERR
+        | Self::SIZERR
+        | Self::PGSERR
+        | Self::MISERR
+        | Self::FASTERR;
+
+    /// True if none of the flags is set
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// True if the given flag (e.g. [ProgrammingFlags::PGSERR]) is set
+    pub fn contains(&self, flag: u32) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+/// FLASH_ECCR bits 31 and 29: ECCD and ECCD2, double-bit (uncorrectable) errors
+pub const ECCR_DOUBLE_ERROR_FLAGS: u32 = 0xA000_0000;
+/// FLASH_ECCR bits 30 and 28: ECCC and ECCC2, the single-bit errors the ECC corrected
+pub const ECCR_CORRECTION_FLAGS: u32 = 0x5000_0000;
+/// FLASH_ECCR bits 31 to 28: ECCD, ECCC, ECCD2 and ECCC2, all flags that latch an error
+pub const ECCR_ERROR_FLAGS: u32 = 0xF000_0000;
+
+/// An ECC error as latched in FLASH_ECCR.
+///
+/// This is everything the STM32L4R5 exposes about an error: there is no syndrome, so not even for a corrected
+/// single-bit error it tells which bit flipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EccError {
+    /// Address of the doubleword with the error, relative to the start of its bank (ADDR_ECC)
+    pub address: u32,
+    /// True for a double-bit error, which can't be corrected (ECCD/ECCD2).
+    /// Otherwise, it was a single-bit error that has been corrected (ECCC/ECCC2).
+    pub double: bool,
+    /// The bank the error is in (BK_ECC), 0 for the manual's Bank 1. [EccError::address] is relative to this bank
+    pub bank: u8,
+    /// True if the error is in the system flash (SYSF_ECC), i.e. the bootloader or the OTP area,
+    /// and not in the main memory
+    pub system_flash: bool,
+    /// True if the flag of the upper 64 bits of a 128-bit line is set (ECCD2/ECCC2), which only exist in
+    /// single-bank mode. Always false in dual-bank mode, where every doubleword has its own ECC
+    pub upper_half: bool,
+}
+
+impl EccError {
+    /// Bit 31 (ECCD)
+    pub const ECCD: u32 = 1 << 31;
+    /// Bit 30 (ECCC)
+    pub const ECCC: u32 = 1 << 30;
+    /// Bit 29 (ECCD2), reserved in dual-bank mode
+    pub const ECCD2: u32 = 1 << 29;
+    /// Bit 28 (ECCC2), reserved in dual-bank mode
+    pub const ECCC2: u32 = 1 << 28;
+    /// Bits 0-20 (ADDR_ECC)
+    pub const ADDR_ECC_MASK: u32 = 0x001f_ffff;
+    /// Bit 21 (BK_ECC)
+    pub const BK_ECC: u32 = 1 << 21;
+    /// Bit 22 (SYSF_ECC)
+    pub const SYSF_ECC: u32 = 1 << 22;
+
+    /// Decodes the given FLASH_ECCR content, returns [None] if no error is flagged.
+    /// The second flags for the upper 64 bits of a 128-bit line (ECCD2/ECCC2) only exist in single-bank mode.
+    pub fn from_eccr(eccr: u32, dualbank: bool) -> Option<EccError> {
+        let (double_mask, corrected_mask) = if dualbank {
+            (Self::ECCD, Self::ECCC)
+        } else {
+            (Self::ECCD | Self::ECCD2, Self::ECCC | Self::ECCC2)
+        };
+
+        let double = eccr & double_mask != 0;
+        if !double && eccr & corrected_mask == 0 {
+            return None;
+        }
+
+        Some(EccError {
+            address: eccr & Self::ADDR_ECC_MASK,
+            double,
+            bank: (eccr & Self::BK_ECC != 0) as u8,
+            system_flash: eccr & Self::SYSF_ECC != 0,
+            upper_half: !dualbank && eccr & (Self::ECCD2 | Self::ECCC2) != 0,
+        })
+    }
+
+    /// Offset of the doubleword from the start of the flash, i.e. [EccError::address] plus the start of its bank.
+    /// The bank size depends on the device, see [crate::geometry::bank_size_for].
+    pub fn offset(&self, bank_size: u32) -> u32 {
+        self.bank as u32 * bank_size + self.address
+    }
+
+    /// The double-bit error in main memory that [EccError::offset] gives `offset` for, e.g. to fake one
+    /// in a dry run
+    pub fn double_at(offset: u32, bank_size: u32) -> EccError {
+        EccError {
+            address: offset % bank_size,
+            double: true,
+            bank: (offset / bank_size) as u8,
+            system_flash: false,
+            upper_half: false,
+        }
+    }
+
+    /// Encodes the error as FLASH_ECCR content, the inverse of [EccError::from_eccr]
+    pub fn to_eccr(&self) -> u32 {
+        let flag = match (self.double, self.upper_half) {
+            (true, false) => Self::ECCD,
+            (true, true) => Self::ECCD2,
+            (false, false) => Self::ECCC,
+            (false, true) => Self::ECCC2,
+        };
+        let bank = if self.bank != 0 { Self::BK_ECC } else { 0 };
+        let system_flash = if self.system_flash { Self::SYSF_ECC } else { 0 };
+
+        flag | bank | system_flash | (self.address & Self::ADDR_ECC_MASK)
+    }
+}
+
+/// The decoded content of FLASH_CR, as returned by `Flash::cr_snapshot` in the firmware. Meant for debugging,
+/// e.g. to see whether PG was left set by an earlier write that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrState {
+    /// Programming (bit 0)
+    pub pg: bool,
+    /// Page erase (bit 1)
+    pub per: bool,
+    /// Mass erase of bank 1 (bit 2)
+    pub mer1: bool,
+    /// Page number for the erase (bits 3-10)
+    pub pnb: u8,
+    /// Bank of the page erase (bit 11), `false` for the manual's Bank 1
+    pub bker: bool,
+    /// Mass erase of bank 2 (bit 15)
+    pub mer2: bool,
+    /// Start of an erase (bit 16)
+    pub strt: bool,
+    /// Start of an option bytes modification (bit 17)
+    pub optstrt: bool,
+    /// Fast programming (bit 18)
+    pub fstpg: bool,
+    /// Option bytes locked (bit 30)
+    pub optlock: bool,
+    /// Flash locked (bit 31)
+    pub lock: bool,
+}
+
+impl CrState {
+    /// PG (bit 0)
+    pub const PG: u32 = 1 << 0;
+    /// PER (bit 1)
+    pub const PER: u32 = 1 << 1;
+    /// First bit of PNB (bits 3-10)
+    pub const PNB_SHIFT: u32 = 3;
+    /// PNB (bits 3-10)
+    pub const PNB_MASK: u32 = 0xFF << Self::PNB_SHIFT;
+    /// BKER (bit 11)
+    pub const BKER: u32 = 1 << 11;
+    /// STRT (bit 16)
+    pub const STRT: u32 = 1 << 16;
+    /// FSTPG (bit 18)
+    pub const FSTPG: u32 = 1 << 18;
+    /// LOCK (bit 31)
+    pub const LOCK: u32 = 1 << 31;
+
+    /// Decodes a FLASH_CR value, e.g. one that was captured earlier
+    pub fn from_bits(cr: u32) -> CrState {
+        let bit = |number: u32| cr & (1 << number) != 0;
+        CrState {
+            pg: bit(0),
+            per: bit(1),
+            mer1: bit(2),
+            pnb: (cr >> 3) as u8,
+            bker: bit(11),
+            mer2: bit(15),
+            strt: bit(16),
+            optstrt: bit(17),
+            fstpg: bit(18),
+            optlock: bit(30),
+            lock: bit(31),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::bank_size_for;
+
+    const ERRORS: [Error; 10] = [
+        Error::UnlockFailed,
+        Error::Busy,
+        Error::Illegal,
+        Error::InvalidPage,
+        Error::Corrupted,
+        Error::UnsupportedDevice,
+        Error::SizeMismatch,
+        Error::OptionBytesInvalid,
+        Error::VerifyMismatch,
+        Error::NotErased,
+    ];
+
+    #[test]
+    fn errors_round_trip_through_u32() {
+        for error in ERRORS {
+            let value = error.as_u32();
+            assert_ne!(value, 0, "{:?}", error);
+            assert!(value <= 0xFF, "{:?}", error);
+            assert_eq!(Error::from_u32(value), Some(error));
+        }
+        assert_eq!(Error::from_u32(0), None);
+        assert_eq!(Error::from_u32(0b1011), None);
+    }
+
+    #[test]
+    fn each_status_flag_decodes_to_its_error() {
+        for (bit, expected) in [
+            // EOP and OPERR don't make an operation fail
+            (0, Ok(())),
+            (1, Ok(())),
+            (3, Err(Error::NotErased)),
+            (4, Err(Error::Illegal)),
+            (5, Err(Error::Illegal)),
+            (6, Err(Error::SizeMismatch)),
+            (7, Err(Error::Illegal)),
+            (8, Err(Error::Illegal)),
+            (9, Err(Error::Illegal)),
+            // RDERR and OPTVERR aren't about the ongoing operation either
+            (14, Ok(())),
+            (15, Ok(())),
+            (16, Err(Error::Busy)),
+        ] {
+            assert_eq!(Error::from_status_bits(1 << bit), expected, "bit {}", bit);
+        }
+        assert_eq!(Error::from_status_bits(0), Ok(()));
+    }
+
+    #[test]
+    fn status_flags_decode_in_order_of_precedence() {
+        let all = Error::SR_BSY | Error::SR_ERROR_MASK;
+        assert_eq!(Error::from_status_bits(all), Err(Error::Busy));
+        assert_eq!(
+            Error::from_status_bits(all & !Error::SR_BSY),
+            Err(Error::SizeMismatch)
+        );
+        assert_eq!(
+            Error::from_status_bits(Error::SR_PROGERR | Error::SR_ILLEGAL_MASK),
+            Err(Error::NotErased)
+        );
+        assert_eq!(
+            Error::from_status_bits(ProgrammingFlags::WRPERR | ProgrammingFlags::PGSERR),
+            Err(Error::Illegal)
+        );
+    }
+
+    #[test]
+    fn programming_flags_cover_the_error_mask() {
+        assert_eq!(ProgrammingFlags::ALL, Error::SR_ERROR_MASK);
+
+        let flags = ProgrammingFlags(ProgrammingFlags::PGSERR | ProgrammingFlags::SIZERR);
+        assert!(!flags.is_empty());
+        assert!(flags.contains(ProgrammingFlags::PGSERR));
+        assert!(!flags.contains(ProgrammingFlags::PROGERR));
+        assert!(ProgrammingFlags(0).is_empty());
+    }
+
+    #[test]
+    fn empty_eccr_has_no_error() {
+        assert_eq!(EccError::from_eccr(0, true), None);
+        assert_eq!(EccError::from_eccr(0, false), None);
+        // An address, a bank and ECCIE (bit 24) without any flag are leftovers of an earlier error
+        let stale = 0x1234 | EccError::BK_ECC | EccError::SYSF_ECC | 1 << 24;
+        assert_eq!(EccError::from_eccr(stale, true), None);
+        assert_eq!(EccError::from_eccr(stale, false), None);
+    }
+
+    #[test]
+    fn eccr_flags_decode_to_the_error_kind() {
+        let double = EccError::from_eccr(EccError::ECCD | 0x1238, true).unwrap();
+        assert_eq!(
+            double,
+            EccError {
+                address: 0x1238,
+                double: true,
+                bank: 0,
+                system_flash: false,
+                upper_half: false,
+            }
+        );
+
+        let corrected = EccError::from_eccr(EccError::ECCC | 0x8, false).unwrap();
+        assert!(!corrected.double);
+        assert!(!corrected.upper_half);
+
+        // A corrected error next to a double-bit one is still reported as double
+        let both = EccError::from_eccr(EccError::ECCD | EccError::ECCC, true).unwrap();
+        assert!(both.double);
+    }
+
+    #[test]
+    fn eccr_bank_and_system_flash_bits() {
+        let eccr = EccError::ECCD | EccError::BK_ECC | EccError::ADDR_ECC_MASK;
+        let error = EccError::from_eccr(eccr, true).unwrap();
+        assert_eq!(error.bank, 1);
+        assert_eq!(error.address, 0x1f_ffff);
+        assert!(!error.system_flash);
+
+        let error = EccError::from_eccr(EccError::ECCC | EccError::SYSF_ECC | 0x10, true).unwrap();
+        assert_eq!(error.bank, 0);
+        assert!(error.system_flash);
+        assert_eq!(error.address, 0x10);
+    }
+
+    #[test]
+    fn upper_half_flags_only_exist_in_single_bank_mode() {
+        let error = EccError::from_eccr(EccError::ECCD2 | 0x100, false).unwrap();
+        assert!(error.double);
+        assert!(error.upper_half);
+        assert_eq!(error.address, 0x100);
+
+        let error = EccError::from_eccr(EccError::ECCC2, false).unwrap();
+        assert!(!error.double);
+        assert!(error.upper_half);
+
+        // Reserved in dual-bank mode, so they don't flag anything there
+        assert_eq!(EccError::from_eccr(EccError::ECCD2, true), None);
+        assert_eq!(EccError::from_eccr(EccError::ECCC2, true), None);
+        let error = EccError::from_eccr(EccError::ECCD | EccError::ECCD2, true).unwrap();
+        assert!(!error.upper_half);
+    }
+
+    #[test]
+    fn eccr_flag_masks_match_the_decoder() {
+        assert_eq!(ECCR_DOUBLE_ERROR_FLAGS, EccError::ECCD | EccError::ECCD2);
+        assert_eq!(ECCR_CORRECTION_FLAGS, EccError::ECCC | EccError::ECCC2);
+        assert_eq!(
+            ECCR_ERROR_FLAGS,
+            ECCR_DOUBLE_ERROR_FLAGS | ECCR_CORRECTION_FLAGS
+        );
+    }
+
+    #[test]
+    fn cr_bits_decode_to_their_fields() {
+        assert_eq!(
+            CrState::from_bits(0),
+            CrState {
+                pg: false,
+                per: false,
+                mer1: false,
+                pnb: 0,
+                bker: false,
+                mer2: false,
+                strt: false,
+                optstrt: false,
+                fstpg: false,
+                optlock: false,
+                lock: false,
+            }
+        );
+
+        // The reset value: both locks set
+        let reset = CrState::from_bits(0xC000_0000);
+        assert!(reset.lock && reset.optlock);
+        assert!(!reset.pg && !reset.per);
+
+        // A page erase of the last page of the second bank
+        let erase = CrState::from_bits(1 << 1 | 0xFF << 3 | 1 << 11 | 1 << 16);
+        assert!(erase.per && erase.bker && erase.strt);
+        assert_eq!(erase.pnb, 0xFF);
+        assert!(!erase.pg && !erase.mer1 && !erase.mer2);
+
+        for (bit, field) in [
+            (0, CrState::from_bits(1).pg),
+            (2, CrState::from_bits(1 << 2).mer1),
+            (15, CrState::from_bits(1 << 15).mer2),
+            (17, CrState::from_bits(1 << 17).optstrt),
+            (18, CrState::from_bits(1 << 18).fstpg),
+        ] {
+            assert!(field, "bit {}", bit);
+        }
+        // PNB doesn't spill into BKER
+        assert!(!CrState::from_bits(0xFF << 3).bker);
+    }
+
+    #[test]
+    fn offset_adds_the_bank_size_of_the_device() {
+        let eccr = EccError::ECCD | EccError::BK_ECC | 0x2300;
+        let error = EccError::from_eccr(eccr, true).unwrap();
+        // The second bank starts at 1MB on a 2MB device, and at 512KB on a 1MB device
+        assert_eq!(error.offset(bank_size_for(2048, true)), 0x10_2300);
+        assert_eq!(error.offset(bank_size_for(1024, true)), 0x8_2300);
+
+        let error = EccError::from_eccr(EccError::ECCD | 0x2300, true).unwrap();
+        assert_eq!(error.offset(bank_size_for(2048, true)), 0x2300);
+        assert_eq!(error.offset(bank_size_for(1024, true)), 0x2300);
+
+        // Single-bank mode has one bank over the whole flash, ADDR_ECC covers all of it
+        let error = EccError::from_eccr(EccError::ECCD | 0x1F_FFF8, false).unwrap();
+        assert_eq!(error.offset(bank_size_for(2048, false)), 0x1F_FFF8);
+    }
+
+    #[test]
+    fn double_at_round_trips_through_eccr() {
+        for (size_kb, dualbank, offset, bank) in [
+            (2048, true, 0x2300, 0),
+            (2048, true, 0x10_2300, 1),
+            (2048, true, 0x1F_FFF8, 1),
+            (1024, true, 0x7_FFF8, 0),
+            (1024, true, 0x8_0000, 1),
+            (2048, false, 0x10_2300, 0),
+            (1024, false, 0xF_FFF8, 0),
+        ] {
+            let bank_size = bank_size_for(size_kb, dualbank);
+            let error = EccError::double_at(offset, bank_size);
+            assert_eq!(error.bank, bank, "{:#x} on {}KB", offset, size_kb);
+
+            let decoded = EccError::from_eccr(error.to_eccr(), dualbank).unwrap();
+            assert_eq!(decoded, error, "{:#x} on {}KB", offset, size_kb);
+            assert_eq!(decoded.offset(bank_size), offset);
+        }
+    }
+
+    #[test]
+    fn to_eccr_inverts_from_eccr() {
+        for eccr in [
+            EccError::ECCC | 0x10,
+            EccError::ECCD2 | EccError::SYSF_ECC | 0x18,
+            EccError::ECCC2 | 0x1F_FFF8,
+        ] {
+            assert_eq!(EccError::from_eccr(eccr, false).unwrap().to_eccr(), eccr);
+        }
+        let eccr = EccError::ECCD | EccError::BK_ECC | 0x8;
+        assert_eq!(EccError::from_eccr(eccr, true).unwrap().to_eccr(), eccr);
+    }
+}
@@ -0,0 +1,261 @@
+//! How the result of a search is shown: what an ECC error means for the search ([Outcome]) and which LEDs
+//! show it.
+//!
+//! The LEDs are only driven through [LedSink], so the firmware passes in the board LEDs (see `hw` in the firmware)
+//! and the host tests a recording one.
+
+use crate::geometry::{TargetRange, FLASH_BASE};
+use crate::registers::EccError;
+use crate::state::Outcome;
+
+/// The LEDs of the Nucleo board
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Led {
+    Green,
+    Red,
+    Blue,
+}
+
+/// Something that can show LED states. The success/failure signalling only talks to this trait,
+/// so it doesn't need to know whether it drives the real LEDs or e.g. records them.
+pub trait LedSink {
+    fn set(&mut self, led: Led, state: bool);
+
+    /// Sets all three LEDs at once, e.g. to show a color. Implementations can batch the writes per GPIO port
+    fn set_rgb(&mut self, red: bool, green: bool, blue: bool) {
+        self.set(Led::Red, red);
+        self.set(Led::Green, green);
+        self.set(Led::Blue, blue);
+    }
+}
+
+/// Shows an [Outcome] on the LEDs:
+/// - green: ECC error in the area we wanted
+/// - red: ECC error somewhere else, or a panic
+/// - red+blue: a fault without any ECC error
+/// - red+green: an NMI without any ECC error
+/// - green+blue: the RTC clock didn't start (the same as a finished benchmark, which never runs a search)
+/// - all: the bank mode doesn't match the build
+pub fn show_outcome(leds: &mut impl LedSink, outcome: Outcome) {
+    let (green, red, blue) = match outcome {
+        Outcome::Searching => (false, false, false),
+        Outcome::Hit => (true, false, false),
+        Outcome::Missed | Outcome::Panicked => (false, true, false),
+        Outcome::NoEccError => (false, true, true),
+        Outcome::UnexpectedNmi => (true, true, false),
+        Outcome::ClockFailure => (true, false, true),
+        Outcome::BankModeMismatch => (true, true, true),
+    };
+
+    leds.set_rgb(red, green, blue);
+}
+
+/// True if the given FLASH_ECCR content explains an NMI. Only a double-bit error causes one,
+/// a corrected error doesn't count
+pub fn is_ecc_nmi(eccr: u32, dualbank: bool) -> bool {
+    matches!(EccError::from_eccr(eccr, dualbank), Some(error) if error.double)
+}
+
+/// Decides what the given FLASH_ECCR content means for a search aiming at `target`: a double-bit error inside of
+/// it is a hit, anywhere else a miss. `bank_size` places errors in the second bank, see [EccError::offset].
+pub fn evaluate_ecc_error(
+    eccr: u32,
+    dualbank: bool,
+    bank_size: u32,
+    target: &TargetRange,
+) -> Outcome {
+    match EccError::from_eccr(eccr, dualbank) {
+        Some(error) if error.double => {
+            if target.contains(FLASH_BASE + error.offset(bank_size)) {
+                Outcome::Hit
+            } else {
+                Outcome::Missed
+            }
+        }
+        _ => Outcome::NoEccError,
+    }
+}
+
+/// What an NMI outside of a checked read means for the search, given the FLASH_ECCR content at that time.
+/// Only a double-bit error raises one (see [is_ecc_nmi]), it is then evaluated like any other ECC error.
+/// Anything else raised it (e.g. the clock security system), which must not end up as a hit or miss.
+pub fn nmi_outcome(eccr: u32, dualbank: bool, bank_size: u32, target: &TargetRange) -> Outcome {
+    if is_ecc_nmi(eccr, dualbank) {
+        evaluate_ecc_error(eccr, dualbank, bank_size, target)
+    } else {
+        Outcome::UnexpectedNmi
+    }
+}
+
+/// LEDs that remember their state and every write, for the host tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MockLeds {
+    /// The current state of the green, red and blue LED
+    pub lit: [bool; 3],
+    /// Every [LedSink::set] call, in order
+    pub writes: Vec<(Led, bool)>,
+}
+
+#[cfg(test)]
+impl MockLeds {
+    /// The current state as `(green, red, blue)`
+    pub fn rgb(&self) -> (bool, bool, bool) {
+        (self.lit[0], self.lit[1], self.lit[2])
+    }
+}
+
+#[cfg(test)]
+impl LedSink for MockLeds {
+    fn set(&mut self, led: Led, state: bool) {
+        self.lit[led as usize] = state;
+        self.writes.push((led, state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::bank_size_for;
+
+    /// The default target of the firmware
+    const TARGET: TargetRange = TargetRange {
+        start: FLASH_BASE + 0x2300,
+        len: 8,
+    };
+
+    fn lights(outcome: Outcome) -> (bool, bool, bool) {
+        let mut leds = MockLeds::default();
+        show_outcome(&mut leds, outcome);
+        leds.rgb()
+    }
+
+    #[test]
+    fn outcomes_map_to_their_colors() {
+        assert_eq!(lights(Outcome::Searching), (false, false, false));
+        assert_eq!(lights(Outcome::Hit), (true, false, false));
+        assert_eq!(lights(Outcome::Missed), (false, true, false));
+        assert_eq!(lights(Outcome::Panicked), (false, true, false));
+        assert_eq!(lights(Outcome::NoEccError), (false, true, true));
+        assert_eq!(lights(Outcome::UnexpectedNmi), (true, true, false));
+        assert_eq!(lights(Outcome::ClockFailure), (true, false, true));
+        assert_eq!(lights(Outcome::BankModeMismatch), (true, true, true));
+    }
+
+    #[test]
+    fn showing_an_outcome_sets_every_led() {
+        // An LED left on from an earlier outcome must not mix into the new color
+        let mut leds = MockLeds::default();
+        show_outcome(&mut leds, Outcome::BankModeMismatch);
+        show_outcome(&mut leds, Outcome::Hit);
+        assert_eq!(leds.rgb(), (true, false, false));
+        assert_eq!(leds.writes.len(), 6);
+        assert_eq!(
+            leds.writes[3..],
+            [(Led::Red, false), (Led::Green, true), (Led::Blue, false)]
+        );
+    }
+
+    #[test]
+    fn double_bit_error_in_range_lights_green() {
+        let eccr = EccError::ECCD | 0x2300;
+        let outcome = evaluate_ecc_error(eccr, true, bank_size_for(2048, true), &TARGET);
+        assert_eq!(outcome, Outcome::Hit);
+        assert_eq!(lights(outcome), (true, false, false));
+    }
+
+    #[test]
+    fn double_bit_error_elsewhere_lights_red() {
+        let bank_size = bank_size_for(2048, true);
+        for eccr in [
+            EccError::ECCD | 0x2308,
+            EccError::ECCD | 0x22F8,
+            // The same address in the second bank
+            EccError::ECCD | EccError::BK_ECC | 0x2300,
+        ] {
+            let outcome = evaluate_ecc_error(eccr, true, bank_size, &TARGET);
+            assert_eq!(outcome, Outcome::Missed, "{:#x}", eccr);
+            assert_eq!(lights(outcome), (false, true, false));
+        }
+    }
+
+    #[test]
+    fn target_in_the_second_bank_depends_on_the_device_size() {
+        // 0x80000 is the start of the second bank on a 1MB device, but still in the first one on a 2MB device
+        let target = TargetRange {
+            start: FLASH_BASE + 0x8_2300,
+            len: 8,
+        };
+        let in_second_bank = EccError::ECCD | EccError::BK_ECC | 0x2300;
+        assert_eq!(
+            evaluate_ecc_error(in_second_bank, true, bank_size_for(1024, true), &target),
+            Outcome::Hit
+        );
+        assert_eq!(
+            evaluate_ecc_error(in_second_bank, true, bank_size_for(2048, true), &target),
+            Outcome::Missed
+        );
+    }
+
+    #[test]
+    fn corrected_errors_are_no_hit() {
+        let bank_size = bank_size_for(2048, true);
+        let corrected = EccError::ECCC | 0x2300;
+        assert_eq!(
+            evaluate_ecc_error(corrected, true, bank_size, &TARGET),
+            Outcome::NoEccError
+        );
+        assert_eq!(
+            evaluate_ecc_error(0, true, bank_size, &TARGET),
+            Outcome::NoEccError
+        );
+        assert!(!is_ecc_nmi(corrected, true));
+        assert!(!is_ecc_nmi(0, true));
+        assert!(is_ecc_nmi(EccError::ECCD | 0x2300, true));
+    }
+
+    #[test]
+    fn upper_half_error_counts_in_single_bank_mode_only() {
+        let eccr = EccError::ECCD2 | 0x2300;
+        let bank_size = bank_size_for(2048, false);
+        assert!(is_ecc_nmi(eccr, false));
+        assert_eq!(
+            evaluate_ecc_error(eccr, false, bank_size, &TARGET),
+            Outcome::Hit
+        );
+        assert!(!is_ecc_nmi(eccr, true));
+    }
+
+    #[test]
+    fn nmi_without_an_ecc_error_is_unexpected() {
+        let bank_size = bank_size_for(2048, true);
+        assert_eq!(
+            nmi_outcome(0, true, bank_size, &TARGET),
+            Outcome::UnexpectedNmi
+        );
+        // A corrected error in the target doesn't raise an NMI either
+        assert_eq!(
+            nmi_outcome(EccError::ECCC | 0x2300, true, bank_size, &TARGET),
+            Outcome::UnexpectedNmi
+        );
+        // ECCD2 is reserved in dual-bank mode
+        assert_eq!(
+            nmi_outcome(EccError::ECCD2 | 0x2300, true, bank_size, &TARGET),
+            Outcome::UnexpectedNmi
+        );
+        assert_eq!(lights(Outcome::UnexpectedNmi), (true, true, false));
+    }
+
+    #[test]
+    fn nmi_with_a_double_bit_error_is_a_hit_or_miss() {
+        let bank_size = bank_size_for(2048, true);
+        assert_eq!(
+            nmi_outcome(EccError::ECCD | 0x2300, true, bank_size, &TARGET),
+            Outcome::Hit
+        );
+        assert_eq!(
+            nmi_outcome(EccError::ECCD | 0x4000, true, bank_size, &TARGET),
+            Outcome::Missed
+        );
+    }
+}
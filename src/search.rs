@@ -0,0 +1,368 @@
+//! The binary search for the right waiting time, without any hardware access.
+//! The state lives in the backup registers between resets, this only decides what to do with it.
+
+/// How far the last attempt got before it was reset, stored in backup register 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Phase {
+    /// No attempt has been made yet
+    Fresh = 0,
+    /// The reset happened before the write finished
+    BeforeWrite = 1,
+    /// The write finished before the reset
+    AfterWrite = 2,
+    /// An attempt corrupted the target range. The search is over, unless the corruption disappears again
+    Locked = 3,
+    /// The reset happened while the page was being erased: before the write even started, or as the
+    /// corrupting operation itself with [CorruptionMethod::InterruptErase]
+    Erasing = 4,
+}
+
+impl Phase {
+    /// Decodes a backup register value. Unknown values are treated as [Phase::Fresh]
+    pub fn from_u32(value: u32) -> Phase {
+        match value {
+            1 => Phase::BeforeWrite,
+            2 => Phase::AfterWrite,
+            3 => Phase::Locked,
+            4 => Phase::Erasing,
+            _ => Phase::Fresh,
+        }
+    }
+}
+
+/// Which operation the watchdog reset is timed to interrupt, stored in backup register 30
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CorruptionMethod {
+    /// Interrupt programming the target on an erased page
+    InterruptWrite = 0,
+    /// Program the target first, then interrupt the erase of its page(s), leaving them partially erased
+    InterruptErase = 1,
+}
+
+impl CorruptionMethod {
+    /// Decodes a backup register value. Unknown values are treated as [CorruptionMethod::InterruptWrite]
+    pub fn from_u32(value: u32) -> CorruptionMethod {
+        match value {
+            1 => CorruptionMethod::InterruptErase,
+            _ => CorruptionMethod::InterruptWrite,
+        }
+    }
+}
+
+/// Where in the range the next waiting time is tried, stored in bits 8-15 of backup register 30.
+/// Different boards converge better with different strategies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SearchStrategy {
+    /// Try the middle of the range, halving it with every attempt
+    Bisect = 0,
+    /// Try the point that splits the range at the golden ratio, closer to the bottom. The range shrinks faster
+    /// after a reset that came too early (before the write finished, 0.382 of it is left) than after one that came
+    /// too late (after the write, 0.618 of it is left)
+    GoldenSection = 1,
+    /// Step up from the bottom by [SearchStrategy::LINEAR_SCAN_STEP], e.g. to scan a narrow range in small steps.
+    /// Once the range is smaller than two steps, this bisects
+    LinearScan = 2,
+}
+
+impl SearchStrategy {
+    /// How far [SearchStrategy::LinearScan] steps up with every attempt
+    pub const LINEAR_SCAN_STEP: u32 = 500;
+
+    /// Decodes a backup register value. Unknown values are treated as [SearchStrategy::Bisect]
+    pub fn from_u32(value: u32) -> SearchStrategy {
+        match value {
+            1 => SearchStrategy::GoldenSection,
+            2 => SearchStrategy::LinearScan,
+            _ => SearchStrategy::Bisect,
+        }
+    }
+
+    /// The waiting time to try next in `search`. This only depends on the range, so the time of the last attempt
+    /// can be calculated again after the reset
+    pub fn probe(self, search: &TimingSearch) -> u32 {
+        let range = search.top - search.bottom;
+        match self {
+            SearchStrategy::Bisect => search.middle(),
+            // 1 - 1/phi of the range, about 0.382
+            SearchStrategy::GoldenSection => search.bottom + (range as u64 * 382 / 1000) as u32,
+            SearchStrategy::LinearScan => search.bottom + (range / 2).min(Self::LINEAR_SCAN_STEP),
+        }
+    }
+
+    /// Narrows `search` depending on how far the last attempt got (see [TimingSearch::advance]),
+    /// and returns the new range with the waiting time to try in it
+    pub fn next(self, mut search: TimingSearch, last: Phase) -> (TimingSearch, u32) {
+        search.advance(self, last);
+        (search, self.probe(&search))
+    }
+}
+
+/// The range of waiting times that is still being considered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingSearch {
+    /// Bottom of the waiting range
+    pub bottom: u32,
+    /// Top of the waiting range
+    pub top: u32,
+}
+
+impl TimingSearch {
+    /// The range at the start of a search.
+    /// In my tests, usually a value of just below ~400k is fine, but it's a bit random
+    pub const INITIAL: TimingSearch = TimingSearch {
+        bottom: 100,
+        top: 1_000_000,
+    };
+
+    /// If the range gets smaller than this, we have likely missed the exact time
+    pub const MIN_RANGE: u32 = 5;
+
+    /// How far [TimingSearch::jitter] moves the waiting time at most, in both directions.
+    /// This is also the half-width of the range it reopens around the new time.
+    pub const MAX_JITTER: u32 = 1000;
+
+    /// The waiting time to try next
+    pub fn middle(&self) -> u32 {
+        (self.bottom + self.top) / 2
+    }
+
+    /// True if the range is so small that the search can't continue
+    pub fn is_stalled(&self) -> bool {
+        self.top - self.bottom < Self::MIN_RANGE
+    }
+
+    /// Escapes a stalled search: moves the waiting time by a pseudo-random amount of up to [TimingSearch::MAX_JITTER]
+    /// and reopens the range around it, so the binary search can continue from there.
+    /// `rng` is the state of the generator (see [lcg_next]), so the same seed always gives the same jumps.
+    ///
+    /// The phase of the last attempt belongs to the stalled range, so the reopened one must not be narrowed
+    /// with it: continue with [Phase::Fresh].
+    pub fn jitter(&mut self, rng: &mut u32) {
+        *rng = lcg_next(*rng);
+        // The upper bits of an LCG are much more random than the lower ones
+        let offset = (*rng >> 16) % (2 * Self::MAX_JITTER + 1);
+        let middle = (self.middle() + offset).saturating_sub(Self::MAX_JITTER);
+
+        self.bottom = middle
+            .saturating_sub(Self::MAX_JITTER)
+            .max(Self::INITIAL.bottom);
+        self.top = middle + Self::MAX_JITTER;
+    }
+
+    /// Narrows the range depending on how far the last attempt got, which tried the waiting time `strategy` chose
+    pub fn advance(&mut self, strategy: SearchStrategy, last: Phase) {
+        let tried = strategy.probe(self);
+        match last {
+            // Apparently we run too long before the reset, so we need to go down
+            Phase::BeforeWrite | Phase::Erasing => self.top = tried,
+            // Apparently reset too late, so go up a bit
+            Phase::AfterWrite => self.bottom = tried,
+            // Nothing to learn from the first attempt, and a locked search keeps its timing
+            Phase::Fresh | Phase::Locked => {}
+        }
+    }
+}
+
+/// Advances a linear congruential generator (the constants from "Numerical Recipes"). This is far from
+/// a good random number generator, but it's deterministic and enough to jump around a bit.
+pub fn lcg_next(state: u32) -> u32 {
+    state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How close to the right waiting time an attempt has to be to corrupt the target
+    const HIT_WINDOW: u32 = 10;
+
+    /// How often the simulated search may jitter before it gives up, like the firmware
+    const MAX_JITTERS: u32 = 8;
+
+    /// Runs attempts like the firmware does, against a board that corrupts the target if the waiting time is
+    /// within [HIT_WINDOW] of `target`. Returns how often the search jittered until the hit, `None` if it gave up.
+    fn search_until_hit(
+        mut search: TimingSearch,
+        strategy: SearchStrategy,
+        target: u32,
+        mut rng: u32,
+    ) -> Option<u32> {
+        let mut last = Phase::Fresh;
+        let mut jitters = 0;
+        loop {
+            if search.is_stalled() {
+                if jitters == MAX_JITTERS {
+                    return None;
+                }
+                search.jitter(&mut rng);
+                jitters += 1;
+                last = Phase::Fresh;
+            }
+
+            let (advanced, tried) = strategy.next(search, last);
+            search = advanced;
+            last = if tried.abs_diff(target) <= HIT_WINDOW {
+                return Some(jitters);
+            } else if tried > target {
+                // Waited too long, the reset came before the write
+                Phase::BeforeWrite
+            } else {
+                Phase::AfterWrite
+            };
+        }
+    }
+
+    /// Runs attempts against a board where any waiting time above `target` is too long, until the search stalls.
+    /// Checks that the range never loses `target` and returns how many attempts it took.
+    fn attempts_until_stalled(strategy: SearchStrategy, target: u32) -> u32 {
+        let mut search = TimingSearch::INITIAL;
+        let mut last = Phase::Fresh;
+        let mut attempts = 0;
+        while !search.is_stalled() {
+            let (advanced, tried) = strategy.next(search, last);
+            search = advanced;
+            assert!(search.bottom <= target && target <= search.top);
+
+            last = if tried > target {
+                Phase::BeforeWrite
+            } else {
+                Phase::AfterWrite
+            };
+            attempts += 1;
+            assert!(attempts < 10_000, "{:?} doesn't converge", strategy);
+        }
+        attempts
+    }
+
+    #[test]
+    fn bisect_converges() {
+        for target in [TimingSearch::INITIAL.bottom, 1234, 400_000, 999_990] {
+            // Every attempt halves the range
+            assert!(attempts_until_stalled(SearchStrategy::Bisect, target) <= 20);
+        }
+    }
+
+    #[test]
+    fn golden_section_converges() {
+        for target in [TimingSearch::INITIAL.bottom, 1234, 400_000, 999_990] {
+            // At worst, 0.618 of the range is left after each attempt
+            assert!(attempts_until_stalled(SearchStrategy::GoldenSection, target) <= 30);
+        }
+    }
+
+    #[test]
+    fn golden_section_shrinks_faster_after_an_early_reset() {
+        let search = TimingSearch {
+            bottom: 0,
+            top: 1000,
+        };
+        let (early, _) = SearchStrategy::GoldenSection.next(search, Phase::BeforeWrite);
+        let (late, _) = SearchStrategy::GoldenSection.next(search, Phase::AfterWrite);
+        assert_eq!(early.top - early.bottom, 382);
+        assert_eq!(late.top - late.bottom, 618);
+    }
+
+    #[test]
+    fn linear_scan_converges() {
+        for target in [TimingSearch::INITIAL.bottom, 1234, 400_000, 999_990] {
+            // It steps up to the target, then bisects the last step
+            let steps = (target - TimingSearch::INITIAL.bottom) / SearchStrategy::LINEAR_SCAN_STEP;
+            assert!(attempts_until_stalled(SearchStrategy::LinearScan, target) <= steps + 10);
+        }
+    }
+
+    #[test]
+    fn linear_scan_steps_up_from_the_bottom() {
+        let search = TimingSearch {
+            bottom: 1000,
+            top: 100_000,
+        };
+        let (next, tried) = SearchStrategy::LinearScan.next(search, Phase::AfterWrite);
+        assert_eq!(next.bottom, 1000 + SearchStrategy::LINEAR_SCAN_STEP);
+        assert_eq!(tried, next.bottom + SearchStrategy::LINEAR_SCAN_STEP);
+    }
+
+    #[test]
+    fn jitter_reopens_a_stalled_range() {
+        let mut search = TimingSearch {
+            bottom: 400_000,
+            top: 400_003,
+        };
+        assert!(search.is_stalled());
+
+        let mut rng = 1;
+        search.jitter(&mut rng);
+        assert!(!search.is_stalled());
+        assert_eq!(search.top - search.bottom, 2 * TimingSearch::MAX_JITTER);
+        assert!(search.middle().abs_diff(400_001) <= TimingSearch::MAX_JITTER);
+    }
+
+    #[test]
+    fn jitter_is_reproducible() {
+        let stalled = TimingSearch {
+            bottom: 400_000,
+            top: 400_003,
+        };
+        let (mut first, mut second) = (stalled, stalled);
+        let (mut first_rng, mut second_rng) = (0x1234_5678, 0x1234_5678);
+        for _ in 0..4 {
+            first.jitter(&mut first_rng);
+            second.jitter(&mut second_rng);
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn jitter_keeps_the_range_above_the_initial_bottom() {
+        let mut search = TimingSearch {
+            bottom: TimingSearch::INITIAL.bottom,
+            top: TimingSearch::INITIAL.bottom + 2,
+        };
+        for seed in 0..64 {
+            let mut rng = seed;
+            search.jitter(&mut rng);
+            assert!(search.bottom >= TimingSearch::INITIAL.bottom);
+        }
+    }
+
+    #[test]
+    fn jitter_escapes_a_stuck_search() {
+        // A flaky attempt moved the top below the right time, so the search converged just below it
+        let stuck = TimingSearch {
+            bottom: 400_000,
+            top: 400_003,
+        };
+        let target = 400_600;
+
+        // Narrowing it further can't get there
+        let (narrowed, tried) = SearchStrategy::Bisect.next(stuck, Phase::AfterWrite);
+        assert!(narrowed.is_stalled());
+        assert!(tried.abs_diff(target) > HIT_WINDOW);
+
+        for seed in [1, 0x1234_5678, 0xdead_beef, u32::MAX] {
+            assert!(
+                search_until_hit(stuck, SearchStrategy::Bisect, target, seed).is_some(),
+                "seed {:#x} didn't escape",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn jitter_ignores_the_phase_of_the_stalled_range() {
+        let mut search = TimingSearch {
+            bottom: 400_000,
+            top: 400_003,
+        };
+        let mut rng = 0x1234_5678;
+        search.jitter(&mut rng);
+
+        // The reopened range is tried in its middle, not narrowed by an attempt outside of it
+        let (next, tried) = SearchStrategy::Bisect.next(search, Phase::Fresh);
+        assert_eq!(next, search);
+        assert_eq!(tried, search.middle());
+    }
+}
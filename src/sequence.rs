@@ -0,0 +1,763 @@
+//! The erase and program sequences of the flash interface, see "3.3.6 Flash main memory erase sequences" and
+//! "3.3.7 Flash main memory programming sequences" in the reference manual, and clearing the ECC errors latched in
+//! FLASH_ECCR.
+//!
+//! The sequences only talk to the registers through [FlashRegs], like the search state only talks to the backup
+//! registers through [crate::state::BackupRegs]. The firmware implements it on the real flash interface (see
+//! `flash::Registers`), the host tests on [MockFlash], a model of the registers and the memory behind them.
+//! What belongs to the firmware itself stays there: the range checks, the dry run and the data cache.
+
+use crate::registers::{CrState, Error, ProgrammingFlags, ECCR_ERROR_FLAGS};
+
+/// A register of the flash interface used by the sequences
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    /// FLASH_SR, the status register. The error flags are cleared by writing 1
+    Sr,
+    /// FLASH_CR, the control register
+    Cr,
+    /// FLASH_ECCR, the ECC register. The error flags are cleared by writing 1, like in FLASH_SR
+    Eccr,
+}
+
+/// The kind of an operation, see [FlashRegs::operation_finished]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Erasing a page, from setting STRT until BSY is cleared
+    Erase,
+    /// Programming a doubleword, from writing its second word until BSY is cleared
+    Program,
+}
+
+/// Access to the flash interface registers the sequences need
+pub trait FlashRegs {
+    fn read(&self, register: Register) -> u32;
+    fn write(&self, register: Register, value: u32);
+
+    /// Programs `value` to the doubleword at the absolute address `address` while PG is set: the low word first,
+    /// then the high word, and returns once both writes have completed, so that BSY is only polled afterwards
+    fn program_dword(&self, address: u32, value: u64);
+
+    /// Called right after an operation was started, returns a timestamp for [FlashRegs::operation_finished]
+    fn operation_started(&self) -> u32 {
+        0
+    }
+
+    /// Called once an operation that started at `started_at` ([FlashRegs::operation_started]) is done,
+    /// e.g. to measure how long it took
+    fn operation_finished(&self, _operation: Operation, _started_at: u32) {}
+
+    /// Writes `register` with `f` applied to its current value
+    fn modify(&self, register: Register, f: impl FnOnce(u32) -> u32) {
+        self.write(register, f(self.read(register)))
+    }
+}
+
+/// How often BSY is polled before a wait gives up with [Error::Busy], see [wait]
+pub const WAIT_POLLS: u32 = 100_000;
+
+/// What to do while waiting for a busy flash, e.g. feeding a watchdog, sleeping until an interrupt (WFI) or
+/// giving up earlier. See [wait] and `FlashUnlocked::write_dwords_with` in the firmware.
+///
+/// Any `FnMut()` closure is a strategy too: it is called on every poll and keeps the default timeout.
+pub trait WaitStrategy {
+    /// Called on every poll of BSY while the flash is still busy
+    fn idle(&mut self);
+
+    /// How often BSY is polled before giving up with [Error::Busy]
+    fn max_polls(&self) -> u32 {
+        WAIT_POLLS
+    }
+}
+
+/// The default [WaitStrategy]: spin on BSY without doing anything else
+pub struct Spin;
+
+impl WaitStrategy for Spin {
+    fn idle(&mut self) {}
+}
+
+impl<F: FnMut()> WaitStrategy for F {
+    fn idle(&mut self) {
+        self()
+    }
+}
+
+/// Waits until the busy bit of the flash status register is cleared, calling `wait` on every poll.
+/// If an error flag comes up before, this returns early with that error.
+///
+/// A timeout is indicated by returning [Error::Busy], after [WaitStrategy::max_polls] polls.
+pub fn wait(regs: &impl FlashRegs, wait: &mut impl WaitStrategy) -> Result<(), Error> {
+    // An error flag can be set while BSY is still high. The operation has failed then, so that error
+    // is returned right away instead of waiting for BSY
+    for _ in 0..wait.max_polls() {
+        let sr = regs.read(Register::Sr);
+        if sr & Error::SR_BSY == 0 {
+            break;
+        }
+        if sr & Error::SR_ERROR_MASK != 0 {
+            return Error::from_status_bits(sr & !Error::SR_BSY);
+        }
+        wait.idle();
+    }
+
+    Error::from_status_bits(regs.read(Register::Sr))
+}
+
+/// Like [wait], but only waits for BSY to be cleared and ignores the error flags.
+/// Returns [Error::Busy] on a timeout.
+pub fn wait_for_idle(regs: &impl FlashRegs, wait: &mut impl WaitStrategy) -> Result<(), Error> {
+    for _ in 0..wait.max_polls() {
+        if regs.read(Register::Sr) & Error::SR_BSY == 0 {
+            return Ok(());
+        }
+        wait.idle();
+    }
+
+    Err(Error::Busy)
+}
+
+/// Clears the programming error flags as described in the manual on Page 131, "Programming errors".
+/// Returns which of them were set before, e.g. because an earlier operation was interrupted.
+pub fn clear_programming_flags(regs: &impl FlashRegs) -> ProgrammingFlags {
+    let flags = ProgrammingFlags(regs.read(Register::Sr) & ProgrammingFlags::ALL);
+
+    // The flags are cleared by writing 1, writing 0 to the others leaves them alone
+    regs.write(Register::Sr, flags.0);
+
+    flags
+}
+
+/// Clears the given error `flags` in FLASH_ECCR. They are cleared by writing 1, so all other flags are
+/// written as 0 to keep them, while ECCIE is written back unchanged.
+pub fn clear_ecc_flags(regs: &impl FlashRegs, flags: u32) {
+    let eccr = regs.read(Register::Eccr);
+    regs.write(
+        Register::Eccr,
+        (eccr & !ECCR_ERROR_FLAGS) | (flags & ECCR_ERROR_FLAGS),
+    );
+}
+
+/// Clears all latched ECC errors (single- and double-bit, in both banks), so that the next error latches its own
+/// address again. Nothing is written if no error is latched.
+pub fn clear_ecc_error(regs: &impl FlashRegs) {
+    let flags = regs.read(Register::Eccr) & ECCR_ERROR_FLAGS;
+    if flags != 0 {
+        clear_ecc_flags(regs, flags);
+    }
+}
+
+/// Steps 3 to 7 of "Standard programming": programs `data` to the erased doublewords starting at the absolute
+/// address `address`. Steps 1 and 2 ([wait_for_idle] and [clear_programming_flags]) are up to the caller.
+///
+/// If programming a doubleword fails, the remaining ones are not written. PG is cleared in any case.
+pub fn program_dwords(
+    regs: &impl FlashRegs,
+    address: u32,
+    data: &[u64],
+    wait_strategy: &mut impl WaitStrategy,
+) -> Result<(), Error> {
+    // 3. Set the PG bit in the FLASH_CR register
+    regs.modify(Register::Cr, |cr| cr | CrState::PG);
+
+    let mut result = Ok(());
+    for (index, dword) in data.iter().enumerate() {
+        // 4. Perform the data write operation at the desired memory address, inside main memory block or OTP area
+        regs.program_dword(address + index as u32 * 8, *dword);
+        let started_at = regs.operation_started();
+
+        // 5. Wait until the BSY bit is cleared in the FLASH_SR register
+        // On errors, we stop programming, but still go on with the cleanup below
+        result = wait(regs, wait_strategy);
+        regs.operation_finished(Operation::Program, started_at);
+        if result.is_err() {
+            break;
+        }
+
+        // Note: The manual mentions the following:
+        // > 6. Check that EOP flag is set in the FLASH_SR register
+        // > (meaning that the programming operation has succeed), and clear it by software.
+        // However, when looking at "3.6 Flash Interrupts", we have this:
+        // > EOP is set only if EOPIE is set.
+        // Since we disable interrupts, we don't need to care about this bit - just reset it
+        if regs.read(Register::Sr) & Error::SR_EOP != 0 {
+            regs.write(Register::Sr, Error::SR_EOP);
+        }
+    }
+
+    // 7. Clear the PG bit in the FLASH_SR register if there no more programming request anymore.
+    // This also happens after an error: with PG left set, the next erase would fail with PGSERR
+    regs.modify(Register::Cr, |cr| cr & !CrState::PG);
+
+    result
+}
+
+/// Steps 3 to 5 of the page erase: erases every page of `pages`, given as the bank and the page inside of it (see
+/// [crate::geometry::page_location_for]). Steps 1 and 2 ([wait_for_idle] and [clear_programming_flags]) are up to
+/// the caller.
+///
+/// PER is only set once and stays set between the pages, only the page selection (BKER/PNB) and STRT are written
+/// for each one. If an erase fails, the remaining pages are not erased. PER is cleared in any case.
+pub fn erase_pages(
+    regs: &impl FlashRegs,
+    pages: impl IntoIterator<Item = (u32, u32)>,
+    wait_strategy: &mut impl WaitStrategy,
+) -> Result<(), Error> {
+    // 3. Set the PER bit and select the page to erase (PNB)
+    regs.modify(Register::Cr, |cr| cr | CrState::PER);
+
+    let mut result = Ok(());
+    for (bank, page_in_bank) in pages {
+        // Select the bank (false => Bank 1, true => Bank 2) and the page inside of it
+        // Note that the manual calls them Bank 1 and Bank 2, but we call them 0 and 1
+        regs.modify(Register::Cr, |cr| {
+            let selection = (page_in_bank << CrState::PNB_SHIFT) & CrState::PNB_MASK;
+            let bank = if bank == 1 { CrState::BKER } else { 0 };
+            (cr & !(CrState::BKER | CrState::PNB_MASK)) | bank | selection
+        });
+
+        // 4. Set the STRT bit in the FLASH_CR register
+        regs.modify(Register::Cr, |cr| cr | CrState::STRT);
+        let started_at = regs.operation_started();
+
+        // 5. Wait for the BSY bit to be cleared in the FLASH_SR register.
+        // If a programming error happened, wait will return an error
+        result = wait(regs, wait_strategy);
+        regs.operation_finished(Operation::Erase, started_at);
+        if result.is_err() {
+            break;
+        }
+    }
+
+    // Disable page erase again, also on errors: with PER left set, the next program would fail with PGSERR
+    regs.modify(Register::Cr, |cr| cr & !CrState::PER);
+
+    result
+}
+
+/// Repeats `erase` until the erased area reads as blank, at most `max_retries` times after the first attempt.
+/// `erase` erases and then checks the area, and returns the address of the first doubleword that isn't erased.
+///
+/// Errors of `erase` are returned right away. Returns [Error::Illegal] if the area still isn't erased after
+/// the last retry.
+pub fn erase_until_blank(
+    max_retries: u32,
+    mut erase: impl FnMut() -> Result<Option<u32>, Error>,
+) -> Result<(), Error> {
+    for _ in 0..=max_retries {
+        if erase()?.is_none() {
+            return Ok(());
+        }
+    }
+
+    Err(Error::Illegal)
+}
+
+/// A model of the flash interface registers and the main memory behind them, for the host tests.
+/// It shows the behavior the sequences rely on: BSY, the error flags (cleared by writing 1) and PGSERR for
+/// operations the manual doesn't allow. Operations finish right away, unless [MockFlash::stuck] is set.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct MockFlash {
+    pub cr: core::cell::Cell<u32>,
+    pub sr: core::cell::Cell<u32>,
+    pub eccr: core::cell::Cell<u32>,
+    /// How often FLASH_ECCR was written
+    pub eccr_writes: core::cell::Cell<u32>,
+    /// The programmed doublewords by address, all others read as erased
+    pub memory: core::cell::RefCell<std::collections::BTreeMap<u32, u64>>,
+    /// Every value written to FLASH_CR, in order
+    pub cr_writes: core::cell::RefCell<Vec<u32>>,
+    /// How many doublewords were programmed (or failed to be) so far
+    pub programs: core::cell::Cell<u32>,
+    /// `(index, flags)`: the program with this index (see [MockFlash::programs]) fails with these FLASH_SR flags
+    pub program_fault: core::cell::Cell<Option<(u32, u32)>>,
+    /// How many pages were erased (or failed to be) so far
+    pub erases: core::cell::Cell<u32>,
+    /// `(index, flags)`: the erase with this index (see [MockFlash::erases]) fails with these FLASH_SR flags
+    pub erase_fault: core::cell::Cell<Option<(u32, u32)>>,
+    /// The next erases that leave the last doubleword of their page programmed, like an erase that was cut short
+    pub incomplete_erases: core::cell::Cell<u32>,
+    /// BSY is set once an operation started, and stays set
+    pub stuck: core::cell::Cell<bool>,
+}
+
+#[cfg(test)]
+impl MockFlash {
+    /// FLASH_SR bit 7
+    pub const PGSERR: u32 = ProgrammingFlags::PGSERR;
+    /// The page size of the model, a 2MB device in dual-bank mode
+    pub const PAGE_SIZE: u32 = crate::geometry::DUAL_BANK_PAGE_SIZE;
+    /// The size of a bank of the model
+    pub const BANK_SIZE: u32 = 1024 * 1024;
+    /// The FLASH_SR flags that are cleared by writing 1: EOP, OPERR, the programming errors, RDERR and OPTVERR
+    const SR_WRITE_TO_CLEAR: u32 = 0b1100_0011_1111_1011;
+
+    /// An unlocked, idle flash
+    pub fn new() -> MockFlash {
+        MockFlash {
+            cr: Default::default(),
+            sr: Default::default(),
+            eccr: Default::default(),
+            eccr_writes: Default::default(),
+            memory: Default::default(),
+            cr_writes: Default::default(),
+            programs: Default::default(),
+            program_fault: Default::default(),
+            erases: Default::default(),
+            erase_fault: Default::default(),
+            incomplete_erases: Default::default(),
+            stuck: Default::default(),
+        }
+    }
+
+    /// The doubleword at `address`, as the flash holds it
+    pub fn dword(&self, address: u32) -> u64 {
+        *self.memory.borrow().get(&address).unwrap_or(&u64::MAX)
+    }
+
+    fn set_sr(&self, flags: u32) {
+        self.sr.set(self.sr.get() | flags);
+    }
+
+    /// ECCIE (bit 24), the only FLASH_ECCR bit besides the flags that can be written
+    pub const ECCIE: u32 = 1 << 24;
+
+    /// Latches an ECC error, given as the FLASH_ECCR flags and the address fields. Like the hardware, the address
+    /// fields of the first error are kept until all flags were cleared, only the flags of later ones are added.
+    pub fn ecc_error(&self, eccr: u32) {
+        let current = self.eccr.get();
+        if current & ECCR_ERROR_FLAGS == 0 {
+            self.eccr.set((current & Self::ECCIE) | eccr);
+        } else {
+            self.eccr.set(current | (eccr & ECCR_ERROR_FLAGS));
+        }
+    }
+
+    /// The absolute address of the first doubleword in `[start, start + len)` that isn't erased
+    pub fn first_programmed(&self, start: u32, len: u32) -> Option<u32> {
+        (start..start + len)
+            .step_by(8)
+            .find(|&address| self.dword(address) != u64::MAX)
+    }
+
+    /// Handles STRT: erases the page selected in `cr`, if the manual allows it
+    fn erase(&self, cr: u32) {
+        let index = self.erases.get();
+        self.erases.set(index + 1);
+
+        // Erasing needs PER but not PG, and the flags of an earlier error must have been cleared
+        if cr & CrState::PER == 0
+            || cr & CrState::PG != 0
+            || self.sr.get() & Error::SR_ERROR_MASK != 0
+        {
+            self.set_sr(Self::PGSERR);
+            return;
+        }
+        if let Some((fault_index, flags)) = self.erase_fault.get() {
+            if fault_index == index {
+                self.set_sr(flags);
+                return;
+            }
+        }
+
+        let bank = if cr & CrState::BKER != 0 { 1 } else { 0 };
+        let page_in_bank = (cr & CrState::PNB_MASK) >> CrState::PNB_SHIFT;
+        let start =
+            crate::geometry::FLASH_BASE + bank * Self::BANK_SIZE + page_in_bank * Self::PAGE_SIZE;
+        self.memory
+            .borrow_mut()
+            .retain(|&address, _| !(start..start + Self::PAGE_SIZE).contains(&address));
+
+        let incomplete = self.incomplete_erases.get();
+        if incomplete > 0 {
+            self.incomplete_erases.set(incomplete - 1);
+            self.memory
+                .borrow_mut()
+                .insert(start + Self::PAGE_SIZE - 8, 0xFFFF_0000_FFFF_0000);
+        }
+        self.start_operation();
+    }
+
+    /// BSY after starting an operation, if the flash is [MockFlash::stuck]
+    fn start_operation(&self) {
+        if self.stuck.get() {
+            self.set_sr(Error::SR_BSY);
+        }
+    }
+}
+
+#[cfg(test)]
+impl FlashRegs for MockFlash {
+    fn read(&self, register: Register) -> u32 {
+        match register {
+            Register::Sr => self.sr.get(),
+            Register::Cr => self.cr.get(),
+            Register::Eccr => self.eccr.get(),
+        }
+    }
+
+    fn write(&self, register: Register, value: u32) {
+        match register {
+            Register::Sr => self
+                .sr
+                .set(self.sr.get() & !(value & Self::SR_WRITE_TO_CLEAR)),
+            Register::Cr => {
+                self.cr_writes.borrow_mut().push(value);
+                // STRT is cleared by the hardware once the erase started
+                self.cr.set(value & !CrState::STRT);
+                if value & CrState::STRT != 0 {
+                    self.erase(value);
+                }
+            }
+            Register::Eccr => {
+                self.eccr_writes.set(self.eccr_writes.get() + 1);
+                let eccr = self.eccr.get() & !(value & ECCR_ERROR_FLAGS);
+                self.eccr.set((eccr & !Self::ECCIE) | (value & Self::ECCIE));
+            }
+        }
+    }
+
+    fn program_dword(&self, address: u32, value: u64) {
+        let index = self.programs.get();
+        self.programs.set(index + 1);
+
+        // Programming needs PG, and the flags of an earlier error must have been cleared
+        if self.cr.get() & CrState::PG == 0 || self.sr.get() & Error::SR_ERROR_MASK != 0 {
+            self.set_sr(Self::PGSERR);
+            return;
+        }
+        if let Some((fault_index, flags)) = self.program_fault.get() {
+            if fault_index == index {
+                self.set_sr(flags);
+                return;
+            }
+        }
+
+        // Only erased doublewords can be programmed, except with all zeros
+        let current = self.dword(address);
+        if current != u64::MAX && value != 0 {
+            self.set_sr(ProgrammingFlags::PROGERR);
+            return;
+        }
+        self.memory.borrow_mut().insert(address, current & value);
+        self.start_operation();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::FLASH_BASE;
+    use crate::registers::{EccError, ECCR_DOUBLE_ERROR_FLAGS};
+
+    /// A [WaitStrategy] that counts its polls and gives up after `max`
+    struct Polls {
+        count: u32,
+        max: u32,
+    }
+
+    impl WaitStrategy for Polls {
+        fn idle(&mut self) {
+            self.count += 1;
+        }
+
+        fn max_polls(&self) -> u32 {
+            self.max
+        }
+    }
+
+    const PAGE_4: u32 = FLASH_BASE + 4 * 0x1000;
+
+    #[test]
+    fn program_writes_every_doubleword() {
+        let flash = MockFlash::new();
+        let data = [0x0123_4567_89AB_CDEF, 0, 0x5555_5555_5555_5555];
+        assert_eq!(program_dwords(&flash, PAGE_4, &data, &mut Spin), Ok(()));
+
+        for (index, dword) in data.iter().enumerate() {
+            assert_eq!(flash.dword(PAGE_4 + index as u32 * 8), *dword);
+        }
+        assert_eq!(flash.cr.get() & CrState::PG, 0);
+        // PG is set once for all of them, and cleared afterwards
+        assert_eq!(*flash.cr_writes.borrow(), [CrState::PG, 0]);
+    }
+
+    #[test]
+    fn size_error_is_reported_as_size_mismatch() {
+        let flash = MockFlash::new();
+        flash.program_fault.set(Some((0, ProgrammingFlags::SIZERR)));
+
+        assert_eq!(
+            program_dwords(&flash, PAGE_4, &[1, 2], &mut Spin),
+            Err(Error::SizeMismatch)
+        );
+        // Nothing was written, not even the second doubleword
+        assert_eq!(flash.programs.get(), 1);
+        assert_eq!(flash.dword(PAGE_4), u64::MAX);
+        assert_eq!(flash.dword(PAGE_4 + 8), u64::MAX);
+
+        // The flag stays until the next sequence clears it
+        assert_eq!(
+            clear_programming_flags(&flash),
+            ProgrammingFlags(ProgrammingFlags::SIZERR)
+        );
+        assert_eq!(program_dwords(&flash, PAGE_4, &[1, 2], &mut Spin), Ok(()));
+    }
+
+    #[test]
+    fn programming_over_data_is_not_erased() {
+        let flash = MockFlash::new();
+        assert_eq!(program_dwords(&flash, PAGE_4, &[!1], &mut Spin), Ok(()));
+        assert_eq!(
+            program_dwords(&flash, PAGE_4, &[!2], &mut Spin),
+            Err(Error::NotErased)
+        );
+        assert_eq!(flash.dword(PAGE_4), !1);
+
+        // Zero is the exception, it doesn't raise PROGERR
+        clear_programming_flags(&flash);
+        assert_eq!(program_dwords(&flash, PAGE_4, &[0], &mut Spin), Ok(()));
+        assert_eq!(flash.dword(PAGE_4), 0);
+    }
+
+    #[test]
+    fn leftover_flags_make_programming_fail() {
+        let flash = MockFlash::new();
+        flash.set_sr(ProgrammingFlags::PGAERR);
+
+        assert_eq!(
+            program_dwords(&flash, PAGE_4, &[1], &mut Spin),
+            Err(Error::Illegal)
+        );
+        assert_ne!(flash.sr.get() & MockFlash::PGSERR, 0);
+        assert_eq!(flash.dword(PAGE_4), u64::MAX);
+
+        let flags = clear_programming_flags(&flash);
+        assert!(flags.contains(ProgrammingFlags::PGAERR));
+        assert!(flags.contains(ProgrammingFlags::PGSERR));
+        assert_eq!(flash.sr.get(), 0);
+        assert!(clear_programming_flags(&flash).is_empty());
+    }
+
+    /// The location of page 4 and 5, and of the first page of the second bank
+    const PAGES: [(u32, u32); 3] = [(0, 4), (0, 5), (1, 0)];
+
+    #[test]
+    fn erase_clears_every_page() {
+        let flash = MockFlash::new();
+        let second_bank = FLASH_BASE + MockFlash::BANK_SIZE;
+        for address in [PAGE_4, PAGE_4 + 0x1FF8, second_bank, second_bank + 0x1000] {
+            flash.memory.borrow_mut().insert(address, 0);
+        }
+
+        assert_eq!(erase_pages(&flash, PAGES, &mut Spin), Ok(()));
+        assert_eq!(flash.erases.get(), 3);
+        assert_eq!(
+            flash.first_programmed(PAGE_4, 2 * MockFlash::PAGE_SIZE),
+            None
+        );
+        assert_eq!(
+            flash.first_programmed(second_bank, MockFlash::PAGE_SIZE),
+            None
+        );
+        // The page after it is left alone
+        assert_eq!(flash.dword(second_bank + 0x1000), 0);
+
+        // PER stays set between the pages, and is cleared at the end
+        let writes = flash.cr_writes.borrow();
+        assert_eq!(writes[0], CrState::PER);
+        assert!(writes[1..writes.len() - 1]
+            .iter()
+            .all(|cr| cr & CrState::PER != 0));
+        assert_eq!(flash.cr.get(), CrState::BKER);
+    }
+
+    #[test]
+    fn failed_erase_stops_the_range() {
+        let flash = MockFlash::new();
+        flash.erase_fault.set(Some((1, ProgrammingFlags::WRPERR)));
+
+        assert_eq!(erase_pages(&flash, PAGES, &mut Spin), Err(Error::Illegal));
+        assert_eq!(flash.erases.get(), 2);
+    }
+
+    #[test]
+    fn incomplete_erase_is_retried() {
+        let flash = MockFlash::new();
+        flash.incomplete_erases.set(2);
+
+        let mut attempts = 0;
+        let result = erase_until_blank(2, || {
+            attempts += 1;
+            erase_pages(&flash, [(0, 4)], &mut Spin)?;
+            Ok(flash.first_programmed(PAGE_4, MockFlash::PAGE_SIZE))
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts, 3);
+        assert_eq!(flash.first_programmed(PAGE_4, MockFlash::PAGE_SIZE), None);
+    }
+
+    #[test]
+    fn incomplete_erase_is_detected() {
+        let flash = MockFlash::new();
+        flash.incomplete_erases.set(3);
+
+        let result = erase_until_blank(1, || {
+            erase_pages(&flash, [(0, 4)], &mut Spin)?;
+            Ok(flash.first_programmed(PAGE_4, MockFlash::PAGE_SIZE))
+        });
+        assert_eq!(result, Err(Error::Illegal));
+        assert_eq!(flash.erases.get(), 2);
+        assert_eq!(
+            flash.first_programmed(PAGE_4, MockFlash::PAGE_SIZE),
+            Some(PAGE_4 + MockFlash::PAGE_SIZE - 8)
+        );
+
+        // Errors of the erase itself are returned as they are, without any retry
+        flash.stuck.set(true);
+        let result = erase_until_blank(1, || {
+            erase_pages(&flash, [(0, 4)], &mut Polls { count: 0, max: 10 })?;
+            Ok(flash.first_programmed(PAGE_4, MockFlash::PAGE_SIZE))
+        });
+        assert_eq!(result, Err(Error::Busy));
+        assert_eq!(flash.erases.get(), 3);
+    }
+
+    #[test]
+    fn failed_program_leaves_cr_clean() {
+        let flash = MockFlash::new();
+        flash.program_fault.set(Some((1, ProgrammingFlags::PGAERR)));
+
+        assert_eq!(
+            program_dwords(&flash, PAGE_4, &[1, 2, 3], &mut Spin),
+            Err(Error::Illegal)
+        );
+        assert_eq!(flash.cr.get(), 0);
+        assert_eq!(flash.dword(PAGE_4), 1);
+
+        // With PG left set, this erase would fail with PGSERR
+        clear_programming_flags(&flash);
+        assert_eq!(erase_pages(&flash, [(0, 4)], &mut Spin), Ok(()));
+        assert_eq!(flash.dword(PAGE_4), u64::MAX);
+    }
+
+    #[test]
+    fn program_timeout_leaves_cr_clean() {
+        let flash = MockFlash::new();
+        flash.stuck.set(true);
+
+        let mut polls = Polls { count: 0, max: 10 };
+        assert_eq!(
+            program_dwords(&flash, PAGE_4, &[1, 2], &mut polls),
+            Err(Error::Busy)
+        );
+        assert_eq!(flash.programs.get(), 1);
+        assert_eq!(flash.cr.get(), 0);
+    }
+
+    #[test]
+    fn failed_erase_leaves_cr_clean() {
+        let flash = MockFlash::new();
+        flash.erase_fault.set(Some((0, ProgrammingFlags::PGAERR)));
+
+        assert_eq!(erase_pages(&flash, PAGES, &mut Spin), Err(Error::Illegal));
+        assert_eq!(flash.cr.get() & (CrState::PER | CrState::STRT), 0);
+
+        // With PER left set, this program would fail with PGSERR
+        clear_programming_flags(&flash);
+        assert_eq!(program_dwords(&flash, PAGE_4, &[1], &mut Spin), Ok(()));
+        assert_eq!(flash.dword(PAGE_4), 1);
+    }
+
+    #[test]
+    fn erase_timeout_leaves_cr_clean() {
+        let flash = MockFlash::new();
+        flash.stuck.set(true);
+
+        let mut polls = Polls { count: 0, max: 10 };
+        assert_eq!(erase_pages(&flash, PAGES, &mut polls), Err(Error::Busy));
+        assert_eq!(flash.erases.get(), 1);
+        assert_eq!(flash.cr.get() & (CrState::PER | CrState::STRT), 0);
+    }
+
+    #[test]
+    fn clearing_ecc_errors_latches_the_next_address() {
+        let flash = MockFlash::new();
+        flash.eccr.set(MockFlash::ECCIE);
+        flash.ecc_error(EccError::ECCD | 0x2300);
+        flash.ecc_error(EccError::ECCC | 0x4000);
+        assert_eq!(
+            flash.eccr.get(),
+            MockFlash::ECCIE | EccError::ECCD | EccError::ECCC | 0x2300
+        );
+
+        clear_ecc_error(&flash);
+        assert_eq!(flash.eccr.get() & ECCR_ERROR_FLAGS, 0);
+        // ECCIE is written back as it was
+        assert_ne!(flash.eccr.get() & MockFlash::ECCIE, 0);
+
+        flash.ecc_error(EccError::ECCC | 0x4000);
+        let error = EccError::from_eccr(flash.eccr.get(), true).unwrap();
+        assert!(!error.double);
+        assert_eq!(error.address, 0x4000);
+    }
+
+    #[test]
+    fn clearing_only_some_ecc_flags_keeps_the_others() {
+        let flash = MockFlash::new();
+        flash.ecc_error(EccError::ECCD | EccError::ECCC | 0x2300);
+
+        clear_ecc_flags(&flash, ECCR_DOUBLE_ERROR_FLAGS);
+        assert_eq!(flash.eccr.get(), EccError::ECCC | 0x2300);
+        assert_eq!(flash.eccr_writes.get(), 1);
+
+        // Without a latched error, there is nothing to write
+        clear_ecc_error(&flash);
+        clear_ecc_error(&flash);
+        assert_eq!(flash.eccr.get() & ECCR_ERROR_FLAGS, 0);
+        assert_eq!(flash.eccr_writes.get(), 2);
+    }
+
+    #[test]
+    fn wait_times_out_with_busy() {
+        let flash = MockFlash::new();
+        flash.sr.set(Error::SR_BSY);
+
+        let mut polls = Polls { count: 0, max: 10 };
+        assert_eq!(wait(&flash, &mut polls), Err(Error::Busy));
+        assert_eq!(polls.count, 10);
+
+        let mut polls = Polls { count: 0, max: 10 };
+        assert_eq!(wait_for_idle(&flash, &mut polls), Err(Error::Busy));
+        assert_eq!(polls.count, 10);
+    }
+
+    #[test]
+    fn wait_returns_an_error_before_busy_is_cleared() {
+        let flash = MockFlash::new();
+        flash.sr.set(Error::SR_BSY | Error::SR_SIZERR);
+
+        let mut polls = Polls { count: 0, max: 10 };
+        assert_eq!(wait(&flash, &mut polls), Err(Error::SizeMismatch));
+        assert_eq!(polls.count, 0);
+
+        // Waiting for idle doesn't look at the error flags
+        flash.sr.set(Error::SR_SIZERR);
+        assert_eq!(wait_for_idle(&flash, &mut Spin), Ok(()));
+    }
+
+    #[test]
+    fn closure_is_called_while_busy() {
+        let flash = MockFlash::new();
+        flash.stuck.set(true);
+
+        let mut idles = 0;
+        let mut idle = || idles += 1;
+        assert_eq!(
+            program_dwords(&flash, PAGE_4, &[1], &mut idle),
+            Err(Error::Busy)
+        );
+        assert_eq!(idles, WAIT_POLLS);
+    }
+}
@@ -0,0 +1,597 @@
+//! The search state is kept in the RTC backup registers, as they survive the watchdog resets.
+//!
+//! The layout of these registers is stable, so a host tool (e.g. a probe-rs script) can read
+//! [BACKUP_REGISTERS_ADDRESS] after each reset and interpret the words as a [SearchState],
+//! without having to parse any UART output.
+//!
+//! The registers are accessed through [BackupRegs], which the firmware implements for the RTC. The host tests use
+//! an array instead.
+
+use crate::search::{CorruptionMethod, Phase, SearchStrategy, TimingSearch};
+
+/// Address of the first RTC backup register (RTC_BKP0R) on the STM32L4R5
+pub const BACKUP_REGISTERS_ADDRESS: u32 = 0x4000_2850;
+
+/// Result of the search, as written to [SearchState::outcome]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Outcome {
+    /// The search is still running, the next reset will try another timing
+    Searching = 0,
+    /// An ECC error was detected in the target range - the green LED is on
+    Hit = 1,
+    /// An ECC error was detected, but outside of the target range - the red LED is on
+    Missed = 2,
+    /// We got a fault, but there was no ECC error - red and blue LEDs are on
+    NoEccError = 3,
+    /// The panic handler was called, e.g. because the search range got too small too often
+    Panicked = 4,
+    /// The bank mode of the chip doesn't match the one the binary was built for - all LEDs are on
+    BankModeMismatch = 5,
+    /// We got an NMI, but there was no uncorrectable ECC error, so something else raised it
+    /// (e.g. the clock security system) - red and green LEDs are on
+    UnexpectedNmi = 6,
+    /// The RTC clock didn't start, so neither sleeping nor the timestamps work - green and blue LEDs are on
+    ClockFailure = 7,
+}
+
+/// Layout of the RTC backup registers, one field per 32-bit register, starting at [BACKUP_REGISTERS_ADDRESS].
+/// The field order must never change, as external tooling relies on it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SearchState {
+    /// Register 0: Magic value to detect first boot
+    pub magic: u32,
+    /// Register 1: Bottom of the waiting range (for binary search)
+    pub bottom: u32,
+    /// Register 2: Top of the waiting range
+    pub top: u32,
+    /// Register 3: The [crate::search::Phase] we are currently in (allows us to detect if last reset was before or after write)
+    pub state: u32,
+    /// Register 4: Reset counter
+    pub reset_count: u32,
+    /// Register 5: Number of verification reads of the target range
+    pub verification_reads: u32,
+    /// Register 6: The waiting time used in the current attempt
+    pub middle: u32,
+    /// Register 7: The [Outcome] of the search
+    pub outcome: u32,
+    /// Register 8: FLASH_ECCR at the time of the last panic
+    pub panic_eccr: u32,
+    /// Register 9: [SearchState::bottom] at the time of the last panic
+    pub panic_bottom: u32,
+    /// Register 10: [SearchState::top] at the time of the last panic
+    pub panic_top: u32,
+    /// Register 11: [SearchState::state] at the time of the last panic in bits 0-7, and the
+    /// `flash::Error` of the firmware that caused it in bits 8-15 (see `Error::from_u32`), zero if there was none
+    pub panic_state: u32,
+    /// Register 12: [SearchState::middle] at the time of the last panic
+    pub panic_middle: u32,
+    /// Register 13: Estimated microseconds until the watchdog reset, right before the corruption write
+    pub watchdog_remaining_us: u32,
+    /// Register 14: Number of hits where the reset interrupted the erase
+    pub erase_race_hits: u32,
+    /// Register 15: Number of hits where the reset interrupted the write
+    pub write_race_hits: u32,
+    /// Register 16: Cycles for programming the target page doubleword by doubleword (`bench-write` feature)
+    pub bench_standard_cycles: u32,
+    /// Register 17: Cycles for programming the target page row by row (`bench-write` feature)
+    pub bench_fast_cycles: u32,
+    /// Register 18: 1 if the search was last started from scratch because the backup domain was reset,
+    /// 0 if the magic value was cleared on purpose
+    pub backup_domain_reset: u32,
+    /// Register 19: Bit 0 is 1 if interrupts are masked during the corruption window (waiting and writing), 0 if not.
+    /// Bits 8-31 are the settle point, in cycles after the watchdog reload: the waiting loop doesn't start before it,
+    /// so it starts at the same time no matter how long the erase before it took. Zero starts it right away
+    pub flags_and_settle: u32,
+    /// Register 20: Cycles from the start of the write until the ECC error was detected,
+    /// zero if it wasn't detected in the same boot
+    pub detection_latency_cycles: u32,
+    /// Register 21: Seed of the jitter that moves a stalled search, kept across searches until a cold boot
+    pub jitter_seed: u32,
+    /// Register 22: Current state of the jitter generator, reset to the seed when a search starts
+    pub jitter_state: u32,
+    /// Register 23: How often the current search stalled and jumped to a nearby waiting time
+    pub jitter_count: u32,
+    /// Register 24: A command from the host, cleared once it was executed (see `Command` in the firmware)
+    pub command: u32,
+    /// Register 25: Milliseconds to sleep at the start of every attempt, zero to not sleep at all
+    pub sleep_ms: u32,
+    /// Register 26: Stacked PC of the code that caused the last hard fault
+    pub fault_pc: u32,
+    /// Register 27: SCB CFSR at the time of the last hard fault, tells which of the fault addresses are valid
+    pub fault_cfsr: u32,
+    /// Register 28: SCB BFAR (bus fault address) at the time of the last hard fault
+    pub fault_bfar: u32,
+    /// Register 29: SCB MMFAR (memory management fault address) at the time of the last hard fault
+    pub fault_mmfar: u32,
+    /// Register 30: The [crate::search::CorruptionMethod] in bits 0-7, i.e. whether the reset interrupts the write
+    /// or the erase, the [crate::search::SearchStrategy] in bits 8-15, and in bits 16-23 how often unlocking
+    /// the flash failed and the board reset to recover (saturating, kept across searches)
+    pub method_strategy_unlocks: u32,
+    /// Register 31: FLASH_SR programming error flags that were still set from an earlier operation when an erase
+    /// or write started, in the last attempt that finished (see `flash::ProgrammingFlags` in the firmware)
+    pub stale_programming_flags: u32,
+}
+
+// Each field must map to exactly one of the 32 backup registers
+static_assertions::const_assert_eq!(
+    core::mem::size_of::<SearchState>(),
+    32 * core::mem::size_of::<u32>()
+);
+
+/// Value of [SearchState::magic] while a search is running, see [init_or_resume]
+pub const MAGIC_VALUE: u32 = 0x99999999;
+
+/// What caused the last reset, from the reset flags in RCC_CSR (see `hw::take_boot_kind` in the firmware)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootKind {
+    /// Power-on or brown-out reset (BORRSTF), the backup registers are lost with it unless VBAT stayed powered
+    ColdBoot,
+    /// The independent or the window watchdog reset the chip (IWDGRSTF, WWDGRSTF), e.g. the timed corruption
+    WatchdogReset,
+    /// A reset requested by the software (SFTRSTF), e.g. after a failed unlock
+    SoftwareReset,
+    /// Only the NRST pin was pulled low (PINRSTF), e.g. the reset button or the debug probe
+    PinReset,
+    /// Any other reset, e.g. loading the option bytes or a low-power reset
+    Other,
+}
+
+/// The settings a new search starts with, see [init_or_resume]
+#[derive(Debug, Clone, Copy)]
+pub struct SearchDefaults {
+    /// [SearchState::verification_reads]
+    pub verification_reads: u32,
+    /// Bit 0 of [SearchState::flags_and_settle]
+    pub mask_interrupts: bool,
+    /// The settle point in bits 8-31 of [SearchState::flags_and_settle]
+    pub settle_cycles: u32,
+    /// [SearchState::sleep_ms]
+    pub sleep_ms: u32,
+    /// The method in [SearchState::method_strategy_unlocks]
+    pub method: CorruptionMethod,
+    /// The strategy in [SearchState::method_strategy_unlocks]
+    pub strategy: SearchStrategy,
+    /// [SearchState::jitter_seed]
+    pub jitter_seed: u32,
+}
+
+/// Detects the first boot by the magic value in register 0 and starts a new search then.
+/// Returns the [Phase] that the search continues from: [Phase::Fresh] for a new search, else the stored one.
+///
+/// A new search starts with the `defaults` for its settings (verification reads, sleep, method etc.). Only the
+/// jitter seed is reset just after a cold boot, when the registers were lost. After any other reset it is kept,
+/// e.g. one set by the host, so a successful run can be reproduced.
+///
+/// Any other value in register 0 counts as absent, including the 0 after a backup domain reset and the one
+/// that the firmware leaves to start a new search. Only garbage that happens to equal [MAGIC_VALUE]
+/// would be taken for a search to resume, the range and phase are then decoded as usual.
+pub fn init_or_resume(
+    regs: &mut impl BackupRegs,
+    boot: BootKind,
+    defaults: &SearchDefaults,
+) -> Phase {
+    if regs.magic() != MAGIC_VALUE {
+        // Distinguishes a true first boot (or power loss) from a search that was cleared on purpose.
+        // Only the jitter seed depends on it below. The registers that aren't set here (e.g. the reset counter
+        // and the hit counts) are kept either way, they only start from zero because a cold boot lost them
+        let cold_boot = boot == BootKind::ColdBoot;
+        regs.set_backup_domain_reset(cold_boot as u32);
+
+        // Note that we're no longer in the first boot
+        regs.set_magic(MAGIC_VALUE);
+
+        regs.set_search(TimingSearch::INITIAL);
+        regs.set_phase(Phase::Fresh);
+        regs.set_verification_reads(defaults.verification_reads);
+        regs.set_flags_and_settle(defaults.mask_interrupts as u32);
+        regs.set_settle_cycles(defaults.settle_cycles);
+        regs.set_sleep_ms(defaults.sleep_ms);
+        regs.set_method(defaults.method);
+        regs.set_strategy(defaults.strategy);
+
+        // Keep the seed unless it was lost, so a successful run can be reproduced
+        if cold_boot {
+            regs.set_jitter_seed(defaults.jitter_seed);
+        }
+        regs.set_jitter_state(regs.jitter_seed());
+        regs.set_jitter_count(0);
+    }
+
+    regs.phase()
+}
+
+/// Defines a getter and a setter for the backup register of a [SearchState] field.
+/// The register index is taken from the position of the field, so the two can't disagree.
+macro_rules! backup_register {
+    ($field:ident, $setter:ident) => {
+        #[doc = concat!("Reads [SearchState::", stringify!($field), "]")]
+        fn $field(&self) -> u32 {
+            self.read(index(core::mem::offset_of!(SearchState, $field)))
+        }
+
+        #[doc = concat!("Writes [SearchState::", stringify!($field), "]")]
+        fn $setter(&mut self, value: u32) {
+            self.write(index(core::mem::offset_of!(SearchState, $field)), value);
+        }
+    };
+}
+
+/// Converts the byte offset of a [SearchState] field into the index of its register
+const fn index(offset: usize) -> usize {
+    offset / core::mem::size_of::<u32>()
+}
+
+/// Number of backup registers, one per [SearchState] field
+pub const BACKUP_REGISTER_COUNT: usize =
+    core::mem::size_of::<SearchState>() / core::mem::size_of::<u32>();
+
+/// Named access to the backup registers, in the layout of [SearchState].
+/// Only [BackupRegs::read] and [BackupRegs::write] need to be implemented, everything else is built on them.
+pub trait BackupRegs {
+    /// Reads the register with the given index (below [BACKUP_REGISTER_COUNT])
+    fn read(&self, index: usize) -> u32;
+
+    /// Writes the register with the given index (below [BACKUP_REGISTER_COUNT])
+    fn write(&mut self, index: usize, value: u32);
+
+    backup_register!(magic, set_magic);
+    backup_register!(bottom, set_bottom);
+    backup_register!(top, set_top);
+    backup_register!(state, set_state);
+    backup_register!(reset_count, set_reset_count);
+    backup_register!(verification_reads, set_verification_reads);
+    backup_register!(middle, set_middle);
+    backup_register!(outcome, set_outcome_bits);
+    backup_register!(panic_eccr, set_panic_eccr);
+    backup_register!(panic_bottom, set_panic_bottom);
+    backup_register!(panic_top, set_panic_top);
+    backup_register!(panic_state, set_panic_state);
+    backup_register!(panic_middle, set_panic_middle);
+    backup_register!(watchdog_remaining_us, set_watchdog_remaining_us);
+    backup_register!(erase_race_hits, set_erase_race_hits);
+    backup_register!(write_race_hits, set_write_race_hits);
+    backup_register!(bench_standard_cycles, set_bench_standard_cycles);
+    backup_register!(bench_fast_cycles, set_bench_fast_cycles);
+    backup_register!(backup_domain_reset, set_backup_domain_reset);
+    backup_register!(flags_and_settle, set_flags_and_settle);
+    backup_register!(detection_latency_cycles, set_detection_latency_cycles);
+    backup_register!(jitter_seed, set_jitter_seed);
+    backup_register!(jitter_state, set_jitter_state);
+    backup_register!(jitter_count, set_jitter_count);
+    backup_register!(command, set_command);
+    backup_register!(sleep_ms, set_sleep_ms);
+    backup_register!(fault_pc, set_fault_pc);
+    backup_register!(fault_cfsr, set_fault_cfsr);
+    backup_register!(fault_bfar, set_fault_bfar);
+    backup_register!(fault_mmfar, set_fault_mmfar);
+    backup_register!(method_strategy_unlocks, set_method_strategy_unlocks);
+    backup_register!(stale_programming_flags, set_stale_programming_flags);
+
+    /// The [Phase] stored in [SearchState::state]
+    fn phase(&self) -> Phase {
+        Phase::from_u32(self.state())
+    }
+
+    fn set_phase(&mut self, phase: Phase) {
+        self.set_state(phase as u32);
+    }
+
+    /// Writes the [Outcome] to its backup register ([SearchState::outcome]), so it can be read by a host
+    fn set_outcome(&mut self, outcome: Outcome) {
+        self.set_outcome_bits(outcome as u32);
+    }
+
+    /// The [CorruptionMethod] in the lower 8 bits of [SearchState::method_strategy_unlocks]
+    fn method(&self) -> CorruptionMethod {
+        CorruptionMethod::from_u32(self.method_strategy_unlocks() & 0xFF)
+    }
+
+    /// Changes the [CorruptionMethod], but keeps the [SearchStrategy]
+    fn set_method(&mut self, method: CorruptionMethod) {
+        self.set_method_strategy_unlocks(self.method_strategy_unlocks() & !0xFF | method as u32);
+    }
+
+    /// The [SearchStrategy] in bits 8-15 of [SearchState::method_strategy_unlocks]
+    fn strategy(&self) -> SearchStrategy {
+        SearchStrategy::from_u32((self.method_strategy_unlocks() >> 8) & 0xFF)
+    }
+
+    /// Changes the [SearchStrategy], but keeps the [CorruptionMethod]
+    fn set_strategy(&mut self, strategy: SearchStrategy) {
+        self.set_method_strategy_unlocks(
+            self.method_strategy_unlocks() & !0xFF00 | (strategy as u32) << 8,
+        );
+    }
+
+    /// How often unlocking the flash failed, from bits 16-23 of [SearchState::method_strategy_unlocks]
+    fn unlock_failures(&self) -> u32 {
+        (self.method_strategy_unlocks() >> 16) & 0xFF
+    }
+
+    /// Changes the unlock failure count (saturating at 255), but keeps the method and the strategy
+    fn set_unlock_failures(&mut self, count: u32) {
+        self.set_method_strategy_unlocks(
+            self.method_strategy_unlocks() & !0xFF_0000 | count.min(0xFF) << 16,
+        );
+    }
+
+    /// Bit 0 of [SearchState::flags_and_settle]
+    fn masks_interrupts(&self) -> bool {
+        self.flags_and_settle() & 1 != 0
+    }
+
+    /// The settle point in bits 8-31 of [SearchState::flags_and_settle]
+    fn settle_cycles(&self) -> u32 {
+        self.flags_and_settle() >> 8
+    }
+
+    /// Changes the settle point (saturating at 24 bits), but keeps whether interrupts are masked
+    fn set_settle_cycles(&mut self, cycles: u32) {
+        self.set_flags_and_settle(self.flags_and_settle() & 0xFF | cycles.min(0xFF_FFFF) << 8);
+    }
+
+    fn search(&self) -> TimingSearch {
+        TimingSearch {
+            bottom: self.bottom(),
+            top: self.top(),
+        }
+    }
+
+    fn set_search(&mut self, search: TimingSearch) {
+        self.set_bottom(search.bottom);
+        self.set_top(search.top);
+    }
+
+    /// All registers in order, e.g. for dumping them
+    fn raw(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..BACKUP_REGISTER_COUNT).map(move |index| self.read(index))
+    }
+}
+
+/// Backup registers in RAM, for the host tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MockBackupRegs(pub [u32; BACKUP_REGISTER_COUNT]);
+
+#[cfg(test)]
+impl BackupRegs for MockBackupRegs {
+    fn read(&self, index: usize) -> u32 {
+        self.0[index]
+    }
+
+    fn write(&mut self, index: usize, value: u32) {
+        self.0[index] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_map_to_their_registers() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_magic(1);
+        regs.set_reset_count(4);
+        regs.set_stale_programming_flags(31);
+        assert_eq!(regs.0[0], 1);
+        assert_eq!(regs.0[4], 4);
+        assert_eq!(regs.0[31], 31);
+        assert_eq!(regs.reset_count(), 4);
+    }
+
+    #[test]
+    fn search_is_kept_in_registers_1_and_2() {
+        let mut regs = MockBackupRegs::default();
+        let search = TimingSearch {
+            bottom: 100,
+            top: 2000,
+        };
+        regs.set_search(search);
+        assert_eq!(regs.0[1..3], [100, 2000]);
+        assert_eq!(regs.search(), search);
+    }
+
+    #[test]
+    fn method_strategy_and_unlock_failures_share_register_30() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_method(CorruptionMethod::InterruptErase);
+        regs.set_strategy(SearchStrategy::LinearScan);
+        regs.set_unlock_failures(3);
+        assert_eq!(regs.0[30], 0x03_02_01);
+
+        // Each setter only changes its own bits
+        regs.set_method(CorruptionMethod::InterruptWrite);
+        assert_eq!(regs.strategy(), SearchStrategy::LinearScan);
+        assert_eq!(regs.unlock_failures(), 3);
+        regs.set_strategy(SearchStrategy::Bisect);
+        assert_eq!(regs.method(), CorruptionMethod::InterruptWrite);
+        assert_eq!(regs.unlock_failures(), 3);
+
+        // The count saturates instead of overflowing into the next byte
+        regs.set_unlock_failures(1000);
+        assert_eq!(regs.0[30], 0xFF_00_00);
+    }
+
+    #[test]
+    fn settle_cycles_keep_the_interrupt_mask() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_flags_and_settle(1);
+        regs.set_settle_cycles(50_000);
+        assert!(regs.masks_interrupts());
+        assert_eq!(regs.settle_cycles(), 50_000);
+
+        regs.set_settle_cycles(u32::MAX);
+        assert!(regs.masks_interrupts());
+        assert_eq!(regs.settle_cycles(), 0xFF_FFFF);
+    }
+
+    #[test]
+    fn unknown_values_decode_to_defaults() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_state(99);
+        regs.set_method_strategy_unlocks(0xFF_FF);
+        assert_eq!(regs.phase(), Phase::Fresh);
+        assert_eq!(regs.method(), CorruptionMethod::InterruptWrite);
+        assert_eq!(regs.strategy(), SearchStrategy::Bisect);
+    }
+
+    const DEFAULTS: SearchDefaults = SearchDefaults {
+        verification_reads: 3,
+        mask_interrupts: true,
+        settle_cycles: 1000,
+        sleep_ms: 50,
+        method: CorruptionMethod::InterruptErase,
+        strategy: SearchStrategy::GoldenSection,
+        jitter_seed: 0x1234_5678,
+    };
+
+    #[test]
+    fn init_starts_a_search_without_the_magic_value() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_phase(Phase::AfterWrite);
+
+        assert_eq!(
+            init_or_resume(&mut regs, BootKind::ColdBoot, &DEFAULTS),
+            Phase::Fresh
+        );
+        assert_eq!(regs.magic(), MAGIC_VALUE);
+        assert_eq!(regs.phase(), Phase::Fresh);
+        assert_eq!(regs.search(), TimingSearch::INITIAL);
+        assert_eq!(regs.backup_domain_reset(), 1);
+
+        assert_eq!(regs.verification_reads(), 3);
+        assert!(regs.masks_interrupts());
+        assert_eq!(regs.settle_cycles(), 1000);
+        assert_eq!(regs.sleep_ms(), 50);
+        assert_eq!(regs.method(), CorruptionMethod::InterruptErase);
+        assert_eq!(regs.strategy(), SearchStrategy::GoldenSection);
+        assert_eq!(regs.jitter_seed(), 0x1234_5678);
+        assert_eq!(regs.jitter_state(), 0x1234_5678);
+        assert_eq!(regs.jitter_count(), 0);
+    }
+
+    #[test]
+    fn init_resumes_a_search_with_the_magic_value() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_magic(MAGIC_VALUE);
+        regs.set_search(TimingSearch {
+            bottom: 1000,
+            top: 2000,
+        });
+        regs.set_phase(Phase::BeforeWrite);
+        regs.set_jitter_count(2);
+        let before = regs.0;
+
+        assert_eq!(
+            init_or_resume(&mut regs, BootKind::WatchdogReset, &DEFAULTS),
+            Phase::BeforeWrite
+        );
+        // A resumed search doesn't change anything
+        assert_eq!(regs.0, before);
+    }
+
+    #[test]
+    fn init_treats_values_close_to_the_magic_value_as_absent() {
+        for magic in [
+            0,
+            MAGIC_VALUE ^ 1,
+            MAGIC_VALUE ^ 1 << 31,
+            !MAGIC_VALUE,
+            u32::MAX,
+        ] {
+            let mut regs = MockBackupRegs([0xAAAA_AAAA; BACKUP_REGISTER_COUNT]);
+            regs.set_magic(magic);
+
+            assert_eq!(
+                init_or_resume(&mut regs, BootKind::ColdBoot, &DEFAULTS),
+                Phase::Fresh,
+                "{:#x} was taken for the magic value",
+                magic
+            );
+            assert_eq!(regs.magic(), MAGIC_VALUE);
+            assert_eq!(regs.search(), TimingSearch::INITIAL);
+        }
+    }
+
+    #[test]
+    fn init_resumes_garbage_that_collides_with_the_magic_value() {
+        // Nothing can tell this apart from a running search, so the range and phase are decoded as usual
+        let mut regs = MockBackupRegs([0xAAAA_AAAA; BACKUP_REGISTER_COUNT]);
+        regs.set_magic(MAGIC_VALUE);
+        regs.set_state(7);
+
+        // An unknown phase decodes as a fresh attempt, which doesn't narrow the garbage range
+        assert_eq!(
+            init_or_resume(&mut regs, BootKind::ColdBoot, &DEFAULTS),
+            Phase::Fresh
+        );
+        assert_eq!(regs.top(), 0xAAAA_AAAA);
+        assert_eq!(regs.jitter_seed(), 0xAAAA_AAAA);
+    }
+
+    #[test]
+    fn init_keeps_the_seed_after_a_warm_boot() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_verification_reads(10);
+        regs.set_strategy(SearchStrategy::LinearScan);
+        regs.set_jitter_seed(42);
+
+        assert_eq!(
+            init_or_resume(&mut regs, BootKind::SoftwareReset, &DEFAULTS),
+            Phase::Fresh
+        );
+        assert_eq!(regs.backup_domain_reset(), 0);
+        // The settings start from the defaults again
+        assert_eq!(regs.verification_reads(), 3);
+        assert_eq!(regs.strategy(), SearchStrategy::GoldenSection);
+        assert_eq!(regs.jitter_seed(), 42);
+        assert_eq!(regs.jitter_state(), 42);
+    }
+
+    #[test]
+    fn init_keeps_the_statistics_after_a_warm_boot() {
+        let mut regs = MockBackupRegs::default();
+        for index in 0..BACKUP_REGISTER_COUNT {
+            regs.write(index, 0xA000 + index as u32);
+        }
+        regs.set_method_strategy_unlocks(0x05_00_00);
+        let before = regs.0;
+
+        init_or_resume(&mut regs, BootKind::WatchdogReset, &DEFAULTS);
+
+        // The registers of the new search and its settings start over, everything else survives
+        let reset = [0, 1, 2, 3, 5, 18, 19, 22, 23, 25, 30];
+        for index in 0..BACKUP_REGISTER_COUNT {
+            if !reset.contains(&index) {
+                assert_eq!(regs.0[index], before[index], "register {} changed", index);
+            }
+        }
+        assert_eq!(regs.reset_count(), before[4]);
+        assert_eq!(regs.jitter_seed(), before[21]);
+        // The unlock failures share register 30 with the method and the strategy
+        assert_eq!(regs.unlock_failures(), 5);
+        assert_eq!(regs.method(), CorruptionMethod::InterruptErase);
+    }
+
+    #[test]
+    fn init_resets_the_seed_after_a_cold_boot() {
+        let mut regs = MockBackupRegs::default();
+        regs.set_jitter_seed(42);
+
+        init_or_resume(&mut regs, BootKind::ColdBoot, &DEFAULTS);
+        assert_eq!(regs.jitter_seed(), 0x1234_5678);
+        assert_eq!(regs.jitter_state(), 0x1234_5678);
+    }
+
+    #[test]
+    fn raw_dumps_all_registers_in_order() {
+        let mut regs = MockBackupRegs::default();
+        for index in 0..BACKUP_REGISTER_COUNT {
+            regs.write(index, index as u32);
+        }
+        assert!(regs.raw().eq(0..BACKUP_REGISTER_COUNT as u32));
+    }
+}
@@ -0,0 +1,254 @@
+//! Checks that corruptions are detected uniformly over a whole bank, not just on the page the search targets
+//! (`bank-sweep` feature of the firmware).
+//!
+//! Only a reset that interrupts a write corrupts the flash, so the sweep takes one boot per page: the first
+//! doublewords of the page are written with the timing the search found, and the watchdog reset cuts the write
+//! short. After the reset, they are read back, the page is erased again and the next one is written.
+//! [SweepProgress] keeps track of that across the resets. Pages holding the firmware and write protected pages
+//! are skipped, see [classify_page].
+
+/// The most pages a bank can have: 256 pages of 4KB (dual-bank) or 8KB (single-bank) on a 2MB device
+pub const MAX_PAGES_PER_BANK: usize = 256;
+
+/// One bit per page of a bank: page `n` (counted from the start of the bank) is bit `n % 32` of word `n / 32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PageMap(pub [u32; MAX_PAGES_PER_BANK / 32]);
+
+impl PageMap {
+    pub fn set(&mut self, page_in_bank: u32) {
+        self.0[page_in_bank as usize / 32] |= 1 << (page_in_bank % 32);
+    }
+
+    pub fn contains(&self, page_in_bank: u32) -> bool {
+        self.0[page_in_bank as usize / 32] & 1 << (page_in_bank % 32) != 0
+    }
+}
+
+/// Result of a sweep, see [SweepProgress::result]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SweepResult {
+    /// The pages that were written and read back, i.e. all pages that were not skipped
+    pub tested: PageMap,
+    /// The tested pages where the readback reported the ECC error
+    pub detected: PageMap,
+}
+
+impl SweepResult {
+    /// True if every tested page reported its ECC error
+    pub fn passed(&self) -> bool {
+        self.tested == self.detected
+    }
+}
+
+/// What the sweep does with a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageClass {
+    /// The page holds (part of) the firmware, erasing it would erase the sweep itself
+    Firmware,
+    /// The page can't be written (see `Flash::is_write_protected` in the firmware)
+    WriteProtected,
+    /// The page is written and read back
+    Tested,
+}
+
+/// Decides what the sweep does with the page starting at the absolute address `page_start`, given the end of the
+/// firmware (see `mem::firmware_end` in the firmware) and whether the page is write protected
+pub fn classify_page(page_start: u32, firmware_end: u32, write_protected: bool) -> PageClass {
+    if page_start < firmware_end {
+        PageClass::Firmware
+    } else if write_protected {
+        PageClass::WriteProtected
+    } else {
+        PageClass::Tested
+    }
+}
+
+/// Magic value of a stored [SweepProgress]
+pub const SWEEP_MAGIC: u32 = 0x5EE9_0001;
+
+/// The progress of a sweep across the resets. The firmware keeps it in RAM that isn't initialized at boot, which
+/// is random after a power-on: [SweepProgress::is_valid] tells a stored progress apart from that.
+/// Every bit pattern is a valid value of this type, so it can be read from that RAM as it is.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SweepProgress {
+    magic: u32,
+    /// The first page (counted from the start of the bank) that wasn't looked at yet
+    next: u32,
+    /// One more than the page whose write was started before the last reset, zero if there is none
+    armed: u32,
+    result: SweepResult,
+    /// See [SweepProgress::checksum]
+    check: u32,
+}
+
+impl SweepProgress {
+    /// A sweep that starts at the first page of the bank
+    pub fn new() -> SweepProgress {
+        let mut progress = SweepProgress {
+            magic: SWEEP_MAGIC,
+            next: 0,
+            armed: 0,
+            result: SweepResult::default(),
+            check: 0,
+        };
+        progress.seal();
+        progress
+    }
+
+    /// True if this was stored by a sweep, and not random content
+    pub fn is_valid(&self) -> bool {
+        self.magic == SWEEP_MAGIC && self.check == self.checksum()
+    }
+
+    /// The result so far, the whole result once [SweepProgress::next_page] returns `None`
+    pub fn result(&self) -> SweepResult {
+        self.result
+    }
+
+    /// The page whose write was started before the last reset, it must be read back with [SweepProgress::record]
+    pub fn armed_page(&self) -> Option<u32> {
+        self.armed.checked_sub(1)
+    }
+
+    /// The next page to write, skipping those that `classify` doesn't mark as [PageClass::Tested].
+    /// Returns `None` once all `pages_per_bank` pages were looked at
+    pub fn next_page(
+        &self,
+        pages_per_bank: u32,
+        classify: impl Fn(u32) -> PageClass,
+    ) -> Option<u32> {
+        (self.next..pages_per_bank)
+            .find(|&page_in_bank| classify(page_in_bank) == PageClass::Tested)
+    }
+
+    /// Marks `page_in_bank` as tested, right before its write is started. The pages before it are done
+    pub fn arm(&mut self, page_in_bank: u32) {
+        self.result.tested.set(page_in_bank);
+        self.armed = page_in_bank + 1;
+        self.next = page_in_bank + 1;
+        self.seal();
+    }
+
+    /// Records whether the readback of the [SweepProgress::armed_page] reported the ECC error
+    pub fn record(&mut self, detected: bool) {
+        if let (Some(page_in_bank), true) = (self.armed_page(), detected) {
+            self.result.detected.set(page_in_bank);
+        }
+        self.armed = 0;
+        self.seal();
+    }
+
+    /// Mixes all other fields, so random content is very unlikely to match it
+    fn checksum(&self) -> u32 {
+        [self.next, self.armed]
+            .iter()
+            .copied()
+            .chain(self.result.tested.0)
+            .chain(self.result.detected.0)
+            .fold(self.magic, |sum, word| sum.rotate_left(5) ^ word)
+    }
+
+    fn seal(&mut self) {
+        self.check = self.checksum();
+    }
+}
+
+impl Default for SweepProgress {
+    fn default() -> Self {
+        SweepProgress::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{FLASH_BASE, SINGLE_BANK_PAGE_SIZE};
+
+    /// Pages 0 and 1 hold the firmware, page 3 is write protected
+    fn classify(page_in_bank: u32) -> PageClass {
+        let page_start = FLASH_BASE + page_in_bank * SINGLE_BANK_PAGE_SIZE;
+        classify_page(page_start, FLASH_BASE + 0x3100, page_in_bank == 3)
+    }
+
+    #[test]
+    fn pages_map_to_their_bits() {
+        let mut map = PageMap::default();
+        map.set(0);
+        map.set(33);
+        map.set(255);
+        assert_eq!(map.0[0], 1);
+        assert_eq!(map.0[1], 1 << 1);
+        assert_eq!(map.0[7], 1 << 31);
+        assert!(map.contains(33));
+        assert!(!map.contains(32));
+    }
+
+    #[test]
+    fn firmware_and_protected_pages_are_skipped() {
+        assert_eq!(classify(0), PageClass::Firmware);
+        // The firmware ends in page 1, so it is skipped as a whole
+        assert_eq!(classify(1), PageClass::Firmware);
+        assert_eq!(classify(2), PageClass::Tested);
+        assert_eq!(classify(3), PageClass::WriteProtected);
+        // A page starting right at the end of the firmware is free
+        assert_eq!(
+            classify_page(FLASH_BASE + 0x4000, FLASH_BASE + 0x4000, false),
+            PageClass::Tested
+        );
+    }
+
+    #[test]
+    fn sweep_visits_every_tested_page_once() {
+        let mut progress = SweepProgress::new();
+        let mut written = Vec::new();
+        while let Some(page) = progress.next_page(6, classify) {
+            assert_eq!(progress.armed_page(), None);
+            progress.arm(page);
+            written.push(page);
+
+            // The reset comes here, then the page is read back
+            assert_eq!(progress.armed_page(), Some(page));
+            progress.record(page != 4);
+        }
+
+        assert_eq!(written, [2, 4, 5]);
+        let result = progress.result();
+        assert_eq!(result.tested.0[0], 0b11_0100);
+        assert_eq!(result.detected.0[0], 0b10_0100);
+        assert!(!result.passed());
+    }
+
+    #[test]
+    fn sweep_passes_if_every_page_detected_it() {
+        let mut progress = SweepProgress::new();
+        while let Some(page) = progress.next_page(6, classify) {
+            progress.arm(page);
+            progress.record(true);
+        }
+        assert!(progress.result().passed());
+
+        // Nothing to test at all passes too, nothing was missed
+        assert!(SweepResult::default().passed());
+    }
+
+    #[test]
+    fn only_stored_progress_is_valid() {
+        let mut progress = SweepProgress::new();
+        assert!(progress.is_valid());
+        progress.arm(2);
+        assert!(progress.is_valid());
+
+        // Random RAM content after a power-on
+        let mut random = progress;
+        random.next = 0x1234;
+        assert!(!random.is_valid());
+        let mut random = progress;
+        random.result.detected.0[7] ^= 1;
+        assert!(!random.is_valid());
+        let mut random = progress;
+        random.magic = 0;
+        random.seal();
+        assert!(!random.is_valid());
+    }
+}